@@ -0,0 +1,58 @@
+use std::{
+	fmt,
+	net::{IpAddr, Ipv4Addr},
+	path::PathBuf,
+	str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Where a system listens: a TCP port, or a Unix socket for the systems of the
+/// same host, which skips the TCP stack
+///
+/// Parsed from `ip:port`, `port` for localhost, or `unix:path`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Address {
+	Tcp(IpAddr, u16),
+	Unix(PathBuf),
+}
+
+impl From<(IpAddr, u16)> for Address {
+	fn from((ip, port): (IpAddr, u16)) -> Self {
+		Address::Tcp(ip, port)
+	}
+}
+
+/// A port on localhost
+impl From<u16> for Address {
+	fn from(port: u16) -> Self {
+		Address::Tcp(Ipv4Addr::LOCALHOST.into(), port)
+	}
+}
+
+impl FromStr for Address {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if let Some(path) = s.strip_prefix("unix:") {
+			return Ok(Address::Unix(path.into()));
+		}
+		if let Ok(port) = s.parse::<u16>() {
+			return Ok(port.into());
+		}
+		let address: std::net::SocketAddr = s
+			.parse()
+			.with_context(|| format!("{s} should be ip:port, port or unix:path"))?;
+		Ok(Address::Tcp(address.ip(), address.port()))
+	}
+}
+
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Address::Tcp(ip, port) => write!(f, "{}", std::net::SocketAddr::new(*ip, *port)),
+			Address::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
@@ -0,0 +1,222 @@
+use std::{
+	path::{Component, Path, PathBuf},
+	sync::{atomic::Ordering, Weak},
+};
+
+use anyhow::Result;
+use futures::channel::oneshot;
+use serde::Serialize;
+
+use crate::{
+	http::{Request, Response},
+	network_message::NetworkMessage,
+	prelude::*,
+	shard::Command,
+};
+
+#[derive(Serialize)]
+struct Status {
+	system_id: u16,
+	uptime_seconds: f64,
+	health: String,
+	shards: Vec<ShardStatus>,
+	drivers: Vec<DriverStatus>,
+	peers: Vec<PeerStatus>,
+}
+
+#[derive(Serialize)]
+struct ShardStatus {
+	id: usize,
+	/// As of the last batch the shard processed
+	nodes: Option<u64>,
+	queue_len: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DriverStatus {
+	id: usize,
+	queue_len: usize,
+}
+
+#[derive(Serialize)]
+struct PeerStatus {
+	id: u16,
+	state: &'static str,
+	unacked: u64,
+	refused: u64,
+	sent_bytes: u64,
+	received_bytes: u64,
+}
+
+/// The routes of `NetworkConfig::with_admin_endpoint`
+pub(crate) async fn handle(system: Weak<System>, request: Request) -> Response {
+	let Some(system) = system.upgrade() else {
+		return Response::error(503, "The system stopped");
+	};
+	// The endpoint isn't served without a cluster token, but `accepts_token`
+	// would accept any request then
+	let config = system.config();
+	if config.cluster_token().is_none() || !config.accepts_token(request.bearer_token()) {
+		return Response::error(401, "The cluster token is missing or wrong");
+	}
+	match (request.method.as_str(), request.path.as_str()) {
+		("GET", "/status") => match serde_json::to_string_pretty(&status(&system)) {
+			Ok(status) => Response::json(status),
+			Err(error) => Response::error(500, error.to_string()),
+		},
+		("POST", "/flush") => {
+			run_on_shards(&system, "Flushed", |_, done| Command::Flush(done)).await
+		}
+		("POST", "/checkpoint") => {
+			let Some(root) = system.config().checkpoint_dir() else {
+				return Response::error(403, "No checkpoint dir is configured");
+			};
+			match request.query("dir").map(|dir| checkpoint_dir(root, dir)) {
+				// Laid out like the storages of the worker binary, so that it can
+				// restart from the checkpoint
+				Some(Some(dir)) => {
+					run_on_shards(&system, "Checkpointed", |shard_id, done| {
+						Command::Checkpoint(dir.join(format!("shard_{shard_id}")), done)
+					})
+					.await
+				}
+				Some(None) => {
+					Response::error(400, "The dir of a checkpoint is a relative path without ..")
+				}
+				None => Response::error(400, "The checkpoint needs a dir parameter"),
+			}
+		}
+		("POST", "/shutdown") => leave(&system),
+		(_, "/status") => Response::error(405, "Only GET is allowed"),
+		(_, "/flush" | "/checkpoint" | "/shutdown") => Response::error(405, "Only POST is allowed"),
+		_ => Response::error(404, "Not found"),
+	}
+}
+
+/// `dir` inside `root`, if it can't lead out of it
+fn checkpoint_dir(root: &Path, dir: &str) -> Option<PathBuf> {
+	let dir = Path::new(dir);
+	let mut components = dir.components().peekable();
+	components.peek()?;
+	components
+		.all(|component| matches!(component, Component::Normal(_)))
+		.then(|| root.join(dir))
+}
+
+fn local_shards(system: &System) -> Vec<usize> {
+	(0..system.n_shards())
+		.filter(|&shard_id| system.shard_owner(shard_id) == system.system_id())
+		.collect()
+}
+
+fn status(system: &System) -> Status {
+	let health = match system.health() {
+		ClusterHealth::Healthy => "healthy".to_owned(),
+		ClusterHealth::Degraded(error) => format!("degraded: {error}"),
+		ClusterHealth::Failed(error) => format!("failed: {error}"),
+	};
+	Status {
+		system_id: system.system_id(),
+		uptime_seconds: system.uptime().as_secs_f64(),
+		health,
+		shards: local_shards(system)
+			.into_iter()
+			.map(|id| ShardStatus {
+				id,
+				nodes: system.metrics().nodes(id),
+				queue_len: system.shard(id).queue_len(),
+			})
+			.collect(),
+		drivers: (0..system.n_drivers())
+			.filter_map(|id| {
+				let queue_len = system.driver(id).queue_len()?;
+				Some(DriverStatus { id, queue_len })
+			})
+			.collect(),
+		peers: system
+			.metrics()
+			.links()
+			.into_iter()
+			.map(|(id, link)| PeerStatus {
+				id,
+				state: link.state().name(),
+				unacked: link.unacked.load(Ordering::Relaxed),
+				refused: link.refused.load(Ordering::Relaxed),
+				sent_bytes: link.sent_bytes.load(Ordering::Relaxed),
+				received_bytes: link.received_bytes.load(Ordering::Relaxed),
+			})
+			.collect(),
+	}
+}
+
+/// Sends the command to every local shard and waits for all of them
+async fn run_on_shards(
+	system: &System,
+	done: &str,
+	command: impl Fn(usize, oneshot::Sender<Result<()>>) -> Command,
+) -> Response {
+	let mut answers = Vec::new();
+	for (shard_id, commands) in system.shard_commands() {
+		let (s, r) = oneshot::channel();
+		// A shard that stopped since drops the command, and its answer
+		let _ = commands.send(command(shard_id, s));
+		answers.push((shard_id, r));
+	}
+	let mut shard_ids = Vec::new();
+	let mut errors = Vec::new();
+	for (shard_id, answer) in answers {
+		match answer.await {
+			Ok(Ok(())) => shard_ids.push(shard_id.to_string()),
+			Ok(Err(error)) => errors.push(format!("Shard {shard_id}: {error:#}")),
+			Err(_) => errors.push(format!("Shard {shard_id} stopped")),
+		}
+	}
+	if errors.is_empty() {
+		Response::text(format!("{done} shards [{}]\n", shard_ids.join(", ")))
+	} else {
+		Response::error(500, errors.join("\n") + "\n")
+	}
+}
+
+/// A worker that doesn't serve shards anymore asks the master to remove it, as
+/// `System::remove_worker` does, after which its server returns
+fn leave(system: &System) -> Response {
+	if system.system_id() == 0 {
+		return Response::error(
+			409,
+			"The master stops with its drivers, see Driver::shutdown_all_and_wait_for_completion",
+		);
+	}
+	let shards = local_shards(system);
+	if !shards.is_empty() {
+		return Response::error(
+			409,
+			format!("This worker still serves shards {shards:?}, move them away first"),
+		);
+	}
+	system.send_to_peer(
+		0,
+		NetworkMessage::SystemLeft {
+			seq: 0,
+			system_id: system.system_id(),
+		},
+	);
+	Response::text("Leaving the system\n".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn checkpoints_stay_in_their_dir() {
+		let root = Path::new("/var/big_uf");
+		assert_eq!(
+			checkpoint_dir(root, "daily/1"),
+			Some(PathBuf::from("/var/big_uf/daily/1"))
+		);
+		for dir in ["", "/etc", "../etc", "daily/../../etc", "."] {
+			assert_eq!(checkpoint_dir(root, dir), None, "{dir}");
+		}
+	}
+}
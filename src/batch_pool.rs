@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_queue::ArrayQueue;
+
+/// Batches bigger than that are dropped rather than kept for reuse
+const MAX_RECYCLED_CAPACITY: usize = 1 << 16;
+
+/// Memory that the batches of a pool hold at most while nobody uses them
+pub(crate) const MAX_POOLED_BYTES: usize = 16 << 20;
+
+/// Emptied batches, handed back to the senders so that sending a batch doesn't
+/// allocate
+///
+/// Shards give back the batches they processed, links the batches their peer
+/// acknowledged.
+pub(crate) struct BatchPool<T> {
+	batches: ArrayQueue<Vec<T>>,
+	max_bytes: usize,
+	/// Held by the batches in `batches`
+	bytes: AtomicUsize,
+}
+
+impl<T> BatchPool<T> {
+	pub fn new(max_batches: usize, max_bytes: usize) -> Self {
+		Self {
+			batches: ArrayQueue::new(max_batches.max(1)),
+			max_bytes,
+			bytes: AtomicUsize::new(0),
+		}
+	}
+
+	/// An empty batch, which keeps the capacity it had when recycled
+	pub fn take(&self) -> Vec<T> {
+		match self.batches.pop() {
+			Some(batch) => {
+				self.bytes.fetch_sub(Self::bytes(&batch), Ordering::Relaxed);
+				batch
+			}
+			None => Vec::new(),
+		}
+	}
+
+	pub fn recycle(&self, mut batch: Vec<T>) {
+		if batch.capacity() == 0 || batch.capacity() > MAX_RECYCLED_CAPACITY {
+			return;
+		}
+		let bytes = Self::bytes(&batch);
+		// When the pool is full the batch is dropped
+		if self
+			.bytes
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pooled| {
+				Some(pooled + bytes).filter(|&pooled| pooled <= self.max_bytes)
+			})
+			.is_err()
+		{
+			return;
+		}
+		batch.clear();
+		if self.batches.push(batch).is_err() {
+			self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+		}
+	}
+
+	fn bytes(batch: &Vec<T>) -> usize {
+		batch.capacity() * std::mem::size_of::<T>()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn recycled_batches_keep_their_capacity() {
+		let pool = BatchPool::<u64>::new(2, MAX_POOLED_BYTES);
+		pool.recycle(Vec::with_capacity(100));
+		pool.recycle(Vec::with_capacity(MAX_RECYCLED_CAPACITY + 1));
+		assert_eq!(pool.take().capacity(), 100);
+		assert_eq!(pool.take().capacity(), 0);
+	}
+
+	#[test]
+	fn pools_hold_a_bounded_memory() {
+		let pool = BatchPool::<u64>::new(8, 1000);
+		pool.recycle(Vec::with_capacity(100));
+		// 1600 bytes in all
+		pool.recycle(Vec::with_capacity(100));
+		assert_eq!(pool.take().capacity(), 100);
+		assert_eq!(pool.take().capacity(), 0);
+		// Taking a batch makes room for another one
+		pool.recycle(Vec::with_capacity(100));
+		assert_eq!(pool.take().capacity(), 100);
+	}
+}
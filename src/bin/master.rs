@@ -4,12 +4,18 @@ use big_uf::*;
 
 #[tokio::main()]
 async fn main() {
+	#[cfg(feature = "tracing")]
+	tracing_subscriber::fmt()
+		.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+		.init();
+
 	let (mut driver, system, _threads, _futures) = System::connect(
 		5,
 		vec![
 			(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 10000),
 			(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 10001),
 		],
+		NetworkConfig::from_env().expect("Invalid network settings"),
 	)
 	.await
 	.unwrap();
@@ -17,7 +23,7 @@ async fn main() {
 	let driver_count = system.n_drivers();
 	let shard_count = system.n_shards();
 	let id_count = 1_000_000;
-	let barrier = std::sync::Barrier::new(2 * (driver_count as usize) + 1);
+	let barrier = std::sync::Barrier::new(2 * driver_count + 1);
 
 	let start_time = rayon::scope(|s| {
 		let ids_range = (id_count * driver.driver_id() as u64 / (driver_count as u64))
@@ -48,6 +54,15 @@ async fn main() {
 						} => {
 							panic!("There should be no find")
 						}
+						DriverMessage::FindDetailedDone { .. } => {
+							panic!("There should be no find")
+						}
+						DriverMessage::FindManyDone { .. } => {
+							panic!("There should be no find")
+						}
+						DriverMessage::UnionManyDone { .. } => {
+							panic!("There should be no unions")
+						}
 						DriverMessage::AddNodeDone {
 							req_id: _,
 							response: _,
@@ -57,6 +72,22 @@ async fn main() {
 						DriverMessage::ShutdownDone { req_id: _ } => {
 							panic!("There should be no Shutdown");
 						}
+						DriverMessage::BarrierAck { .. } => {
+							panic!("There should be no Barrier");
+						}
+						DriverMessage::MoveShardDone { .. }
+						| DriverMessage::MoveShardFailed { .. } => {
+							panic!("There should be no shard migration");
+						}
+						DriverMessage::VerifyNodes { .. } => {
+							panic!("There should be no verification");
+						}
+						DriverMessage::SystemFailure { error } => {
+							panic!("The system failed: {error}");
+						}
+						DriverMessage::RequestRefused { reason, .. } => {
+							panic!("A request was refused: {reason}");
+						}
 					}
 				}
 			}
@@ -0,0 +1,50 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use big_uf::{
+	storage::rocksdb::RocksDbStorage,
+	verify::{ForestCheck, Report},
+};
+
+/// Checks the union find stored in the RocksDB directories of workers, or in
+/// the checkpoints of the admin API, which hold a `shard_{id}` database for each
+/// of their shards
+fn main() {
+	let dirs: Vec<String> = std::env::args().skip(1).collect();
+	assert!(
+		!dirs.is_empty(),
+		"You should put the storage directories holding every shard as parameters"
+	);
+	let mut shards: BTreeMap<usize, PathBuf> = BTreeMap::new();
+	for dir in &dirs {
+		for entry in std::fs::read_dir(dir).expect("Couldn't read the storage directory") {
+			let path = entry.expect("Couldn't read the storage directory").path();
+			let Some(shard_id) = path
+				.file_name()
+				.and_then(|name| name.to_str()?.strip_prefix("shard_")?.parse().ok())
+			else {
+				continue;
+			};
+			if let Some(previous) = shards.insert(shard_id, path.clone()) {
+				panic!("Shard {shard_id} is both in {previous:?} and {path:?}");
+			}
+		}
+	}
+	// Keys can point to any shard, so the check needs all of them
+	let n_shards = shards.keys().next_back().map_or(0, |&last| last + 1);
+	let missing: Vec<usize> = (0..n_shards)
+		.filter(|id| !shards.contains_key(id))
+		.collect();
+	assert!(missing.is_empty(), "Shards {missing:?} are missing");
+
+	let mut forest = ForestCheck::new(n_shards);
+	for (shard_id, path) in shards {
+		let storage = RocksDbStorage::open_read_only(&path)
+			.unwrap_or_else(|error| panic!("Couldn't open {path:?}: {error}"));
+		forest.add_storage(shard_id, &storage);
+	}
+	let report: Report = forest.check();
+	print!("{report}");
+	if !report.is_valid() {
+		std::process::exit(1);
+	}
+}
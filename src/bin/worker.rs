@@ -2,11 +2,31 @@ use big_uf::*;
 
 #[tokio::main()]
 async fn main() {
-	let args = std::env::args()
+	#[cfg(feature = "tracing")]
+	tracing_subscriber::fmt()
+		.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+		.init();
+
+	let args: Address = std::env::args()
 		.nth(1)
-		.expect("You should put the port as first parameter")
+		.expect("You should put the port (or ip:port, or unix:path) as first parameter")
 		.parse()
-		.expect("Couldn't parse the port");
-	let res = System::server(args).await;
+		.expect("Couldn't parse the address");
+	let config = NetworkConfig::from_env().expect("Invalid network settings");
+	// With a storage directory, the worker can be restarted and rejoin the system
+	let res = match std::env::args().nth(2) {
+		Some(dir) => {
+			System::server_with_storage(
+				args,
+				move |shard_id| {
+					let path = std::path::Path::new(&dir).join(format!("shard_{shard_id}"));
+					move || storage::rocksdb::RocksDbStorage::from_path(path)
+				},
+				config,
+			)
+			.await
+		}
+		None => System::server(args, config).await,
+	};
 	println!("{res:?}");
 }
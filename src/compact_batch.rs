@@ -0,0 +1,358 @@
+//! Compact encoding of the `ShardMessages` frames
+//!
+//! Keys and request ids are written as the zigzag varint of their difference
+//! with the previous one in the batch. The keys of a batch mostly share their
+//! shard, so that usually takes 2 to 4 bytes instead of 8.
+//!
+//! `SetParent` messages only carry their request id when `TRACE_CONTEXT` was
+//! negotiated.
+
+use anyhow::{bail, ensure, Result};
+
+use crate::prelude::*;
+
+const UNION: u8 = 0;
+const SET_CHILD: u8 = 1;
+const SET_SIBLING: u8 = 2;
+const SET_PARENT: u8 = 3;
+const FIND: u8 = 4;
+const ADD_NODE: u8 = 5;
+const GRACEFUL_SHUTDOWN: u8 = 6;
+const BARRIER: u8 = 7;
+const MIGRATE: u8 = 8;
+const VERIFY: u8 = 9;
+const SWAP_UNION: u8 = 10;
+const FIND_DETAILED: u8 = 11;
+const ADD_SIZE: u8 = 12;
+
+struct Encoder<'a> {
+	out: &'a mut Vec<u8>,
+	previous_key: u64,
+	previous_req_id: u64,
+	trace_context: bool,
+}
+
+impl Encoder<'_> {
+	fn varint(&mut self, mut value: u64) {
+		while value >= 0x80 {
+			self.out.push(value as u8 | 0x80);
+			value >>= 7;
+		}
+		self.out.push(value as u8);
+	}
+
+	fn delta(&mut self, value: u64, previous: u64) {
+		let delta = value.wrapping_sub(previous) as i64;
+		self.varint(((delta << 1) ^ (delta >> 63)) as u64);
+	}
+
+	fn key(&mut self, key: Key) {
+		self.delta(key.inner, self.previous_key);
+		self.previous_key = key.inner;
+	}
+
+	fn req_id(&mut self, req_id: ReqId) {
+		self.delta(req_id.inner, self.previous_req_id);
+		self.previous_req_id = req_id.inner;
+	}
+
+	fn message(&mut self, message: &ShardMessage) {
+		match *message {
+			ShardMessage::Union {
+				node,
+				to,
+				child,
+				req_id,
+				hops,
+			} => {
+				self.out.push(UNION);
+				self.key(node);
+				self.key(to);
+				self.key(child);
+				self.req_id(req_id);
+				self.varint(hops as u64);
+			}
+			ShardMessage::SwapUnion {
+				node,
+				to,
+				req_id,
+				hops,
+			} => {
+				self.out.push(SWAP_UNION);
+				self.key(node);
+				self.key(to);
+				self.req_id(req_id);
+				self.varint(hops as u64);
+			}
+			ShardMessage::SetChild { node, to, req_id } => {
+				self.out.push(SET_CHILD);
+				self.key(node);
+				self.key(to);
+				self.req_id(req_id);
+			}
+			ShardMessage::SetSibling { node, to, req_id } => {
+				self.out.push(SET_SIBLING);
+				self.key(node);
+				self.key(to);
+				self.req_id(req_id);
+			}
+			ShardMessage::SetParent { node, to, req_id } => {
+				self.out.push(SET_PARENT);
+				self.key(node);
+				self.key(to);
+				if self.trace_context {
+					self.req_id(req_id);
+				}
+			}
+			ShardMessage::Find {
+				node,
+				child,
+				req_id,
+				hops,
+			} => {
+				self.out.push(FIND);
+				self.key(node);
+				self.key(child);
+				self.req_id(req_id);
+				self.varint(hops as u64);
+			}
+			ShardMessage::FindDetailed {
+				node,
+				child,
+				req_id,
+				hops,
+				ref shards,
+			} => {
+				self.out.push(FIND_DETAILED);
+				self.key(node);
+				self.key(child);
+				self.req_id(req_id);
+				self.varint(hops as u64);
+				self.varint(shards.len() as u64);
+				for &shard in shards {
+					self.varint(shard as u64);
+				}
+			}
+			ShardMessage::AddSize { node, size, req_id } => {
+				self.out.push(ADD_SIZE);
+				self.key(node);
+				self.varint(size);
+				self.req_id(req_id);
+			}
+			ShardMessage::AddNode { shard, req_id } => {
+				self.out.push(ADD_NODE);
+				self.varint(shard as u64);
+				self.req_id(req_id);
+			}
+			ShardMessage::GracefulShutdown { shard, req_id } => {
+				self.out.push(GRACEFUL_SHUTDOWN);
+				self.varint(shard as u64);
+				self.req_id(req_id);
+			}
+			ShardMessage::Barrier { shard, req_id } => {
+				self.out.push(BARRIER);
+				self.varint(shard as u64);
+				self.req_id(req_id);
+			}
+			ShardMessage::Migrate {
+				shard,
+				to_system,
+				req_id,
+			} => {
+				self.out.push(MIGRATE);
+				self.varint(shard as u64);
+				self.varint(to_system as u64);
+				self.req_id(req_id);
+			}
+			ShardMessage::Verify { shard, req_id } => {
+				self.out.push(VERIFY);
+				self.varint(shard as u64);
+				self.req_id(req_id);
+			}
+		}
+	}
+}
+
+pub(crate) fn encode(
+	seq: u64,
+	shard_id: u16,
+	batch: &[ShardMessage],
+	trace_context: bool,
+	out: &mut Vec<u8>,
+) {
+	let mut encoder = Encoder {
+		out,
+		previous_key: 0,
+		previous_req_id: 0,
+		trace_context,
+	};
+	encoder.varint(seq);
+	encoder.varint(shard_id as u64);
+	encoder.varint(batch.len() as u64);
+	for message in batch {
+		encoder.message(message);
+	}
+}
+
+struct Decoder<'a> {
+	input: &'a [u8],
+	previous_key: u64,
+	previous_req_id: u64,
+	trace_context: bool,
+}
+
+impl Decoder<'_> {
+	fn byte(&mut self) -> Result<u8> {
+		let (&byte, rest) = self
+			.input
+			.split_first()
+			.ok_or_else(|| anyhow::anyhow!("Truncated batch"))?;
+		self.input = rest;
+		Ok(byte)
+	}
+
+	fn varint(&mut self) -> Result<u64> {
+		let mut value = 0u64;
+		for shift in (0..64).step_by(7) {
+			let byte = self.byte()?;
+			value |= ((byte & 0x7f) as u64) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		bail!("Varint too long")
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		let value = self.varint()?;
+		u16::try_from(value).map_err(|_| anyhow::anyhow!("{value} doesn't fit in a u16"))
+	}
+
+	fn delta(&mut self, previous: u64) -> Result<u64> {
+		let zigzag = self.varint()?;
+		let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+		Ok(previous.wrapping_add(delta as u64))
+	}
+
+	fn key(&mut self) -> Result<Key> {
+		self.previous_key = self.delta(self.previous_key)?;
+		Ok(Key {
+			inner: self.previous_key,
+		})
+	}
+
+	fn req_id(&mut self) -> Result<ReqId> {
+		self.previous_req_id = self.delta(self.previous_req_id)?;
+		Ok(ReqId {
+			inner: self.previous_req_id,
+		})
+	}
+
+	fn shards(&mut self) -> Result<Vec<u16>> {
+		let len = self.varint()?;
+		// A shard takes at least a byte
+		let mut shards = Vec::with_capacity((len as usize).min(self.input.len()));
+		for _ in 0..len {
+			shards.push(self.u16()?);
+		}
+		Ok(shards)
+	}
+
+	fn message(&mut self) -> Result<ShardMessage> {
+		Ok(match self.byte()? {
+			UNION => ShardMessage::Union {
+				node: self.key()?,
+				to: self.key()?,
+				child: self.key()?,
+				req_id: self.req_id()?,
+				hops: u32::try_from(self.varint()?)?,
+			},
+			SWAP_UNION => ShardMessage::SwapUnion {
+				node: self.key()?,
+				to: self.key()?,
+				req_id: self.req_id()?,
+				hops: u32::try_from(self.varint()?)?,
+			},
+			SET_CHILD => ShardMessage::SetChild {
+				node: self.key()?,
+				to: self.key()?,
+				req_id: self.req_id()?,
+			},
+			SET_SIBLING => ShardMessage::SetSibling {
+				node: self.key()?,
+				to: self.key()?,
+				req_id: self.req_id()?,
+			},
+			SET_PARENT => ShardMessage::SetParent {
+				node: self.key()?,
+				to: self.key()?,
+				req_id: if self.trace_context {
+					self.req_id()?
+				} else {
+					ReqId::unknown()
+				},
+			},
+			FIND => ShardMessage::Find {
+				node: self.key()?,
+				child: self.key()?,
+				req_id: self.req_id()?,
+				hops: u32::try_from(self.varint()?)?,
+			},
+			FIND_DETAILED => ShardMessage::FindDetailed {
+				node: self.key()?,
+				child: self.key()?,
+				req_id: self.req_id()?,
+				hops: u32::try_from(self.varint()?)?,
+				shards: self.shards()?,
+			},
+			ADD_SIZE => ShardMessage::AddSize {
+				node: self.key()?,
+				size: self.varint()?,
+				req_id: self.req_id()?,
+			},
+			ADD_NODE => ShardMessage::AddNode {
+				shard: self.u16()?,
+				req_id: self.req_id()?,
+			},
+			GRACEFUL_SHUTDOWN => ShardMessage::GracefulShutdown {
+				shard: self.u16()?,
+				req_id: self.req_id()?,
+			},
+			BARRIER => ShardMessage::Barrier {
+				shard: self.u16()?,
+				req_id: self.req_id()?,
+			},
+			MIGRATE => ShardMessage::Migrate {
+				shard: self.u16()?,
+				to_system: self.u16()?,
+				req_id: self.req_id()?,
+			},
+			VERIFY => ShardMessage::Verify {
+				shard: self.u16()?,
+				req_id: self.req_id()?,
+			},
+			tag => bail!("Unknown shard message tag {tag}"),
+		})
+	}
+}
+
+/// Returns the `seq`, the `shard_id` and the batch
+pub(crate) fn decode(input: &[u8], trace_context: bool) -> Result<(u64, u16, Vec<ShardMessage>)> {
+	let mut decoder = Decoder {
+		input,
+		previous_key: 0,
+		previous_req_id: 0,
+		trace_context,
+	};
+	let seq = decoder.varint()?;
+	let shard_id = decoder.u16()?;
+	let len = decoder.varint()?;
+	// Every message takes at least 2 bytes, so a forged length can't make us
+	// allocate more than the frame
+	let mut batch = Vec::with_capacity((len as usize).min(decoder.input.len() / 2));
+	for _ in 0..len {
+		batch.push(decoder.message()?);
+	}
+	ensure!(decoder.input.is_empty(), "Trailing bytes after the batch");
+	Ok((seq, shard_id, batch))
+}
@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::prelude::*;
+
+/// The `find_many` and `union_many` of a driver waiting for their answers
+///
+/// The requests of a batch take consecutive `ReqId::batched` ids, so the batch
+/// an answer belongs to is the one starting at the largest id below it, and
+/// its position in the batch is the difference.
+#[derive(Default)]
+pub(crate) struct Batches {
+	state: Mutex<BatchesState>,
+}
+
+#[derive(Default)]
+struct BatchesState {
+	next_id: u64,
+	/// By the id of their first request
+	pending: BTreeMap<u64, Batch>,
+}
+
+struct Batch {
+	req_id: ReqId,
+	len: usize,
+	n_answered: usize,
+	/// Of each node for a `find_many`, none for a `union_many`
+	roots: Option<Vec<Key>>,
+}
+
+impl Batches {
+	/// Returns the id of the first request of the batch, the next ones following
+	pub fn start(&self, req_id: ReqId, len: usize, is_find: bool) -> u64 {
+		let mut state = self.state.lock().unwrap();
+		let first = state.next_id;
+		state.next_id += len as u64;
+		state.pending.insert(
+			first,
+			Batch {
+				req_id,
+				len,
+				n_answered: 0,
+				roots: is_find.then(|| vec![Key::new(0, 0); len]),
+			},
+		);
+		first
+	}
+
+	/// Replaces the answers to the requests of batches with the answer of each
+	/// batch they complete, or with the refusal of the first request refused
+	///
+	/// The answers of batches that are no longer pending, dropped by a
+	/// failure, are dropped too: their ids are ours, not the user's.
+	pub fn received(&self, mut batch: Vec<DriverMessage>) -> Vec<DriverMessage> {
+		let mut state = self.state.lock().unwrap();
+		if state.pending.is_empty() {
+			batch.retain(|message| {
+				!matches!(
+					message,
+					DriverMessage::FindDone { req_id, .. }
+						| DriverMessage::UnionDone { req_id }
+						| DriverMessage::RequestRefused { req_id, .. }
+						if req_id.batched_id().is_some()
+				)
+			});
+			return batch;
+		}
+		let mut messages = Vec::with_capacity(batch.len());
+		for message in batch {
+			let (req_id, root) = match message {
+				DriverMessage::FindDone { req_id, response } => (req_id, Some(response)),
+				DriverMessage::UnionDone { req_id }
+				| DriverMessage::RequestRefused { req_id, .. } => (req_id, None),
+				DriverMessage::SystemFailure { .. } => {
+					state.pending.clear();
+					messages.push(message);
+					continue;
+				}
+				message => {
+					messages.push(message);
+					continue;
+				}
+			};
+			let Some(id) = req_id.batched_id() else {
+				messages.push(message);
+				continue;
+			};
+			let Some((&first, batch)) = state.pending.range_mut(..=id).next_back() else {
+				continue;
+			};
+			let position = (id - first) as usize;
+			if position >= batch.len {
+				continue;
+			}
+			if let DriverMessage::RequestRefused { reason, .. } = message {
+				// The answers of the other requests are dropped with the batch
+				let batch = state.pending.remove(&first).unwrap();
+				messages.push(DriverMessage::RequestRefused {
+					req_id: batch.req_id,
+					reason,
+				});
+				continue;
+			}
+			if let (Some(roots), Some(root)) = (&mut batch.roots, root) {
+				roots[position] = root;
+			}
+			batch.n_answered += 1;
+			if batch.n_answered == batch.len {
+				let batch = state.pending.remove(&first).unwrap();
+				messages.push(match batch.roots {
+					Some(roots) => DriverMessage::FindManyDone {
+						req_id: batch.req_id,
+						roots,
+					},
+					None => DriverMessage::UnionManyDone {
+						req_id: batch.req_id,
+					},
+				});
+			}
+		}
+		messages
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn answers_of_failed_batches_are_dropped() {
+		let batches = Batches::default();
+		let first = batches.start(ReqId::new(0, 7), 2, true);
+		let failure = DriverMessage::SystemFailure {
+			error: SystemError::PeerRestarted { peer: 1 },
+		};
+		assert_eq!(batches.received(vec![failure]).len(), 1);
+
+		let late = DriverMessage::FindDone {
+			req_id: ReqId::batched(0, first),
+			response: Key::new(0, 0),
+		};
+		let own = DriverMessage::FindDone {
+			req_id: ReqId::new(0, 8),
+			response: Key::new(0, 0),
+		};
+		let received = batches.received(vec![late, own]);
+		assert!(matches!(
+			received[..],
+			[DriverMessage::FindDone { req_id, .. }] if req_id == ReqId::new(0, 8)
+		));
+	}
+}
@@ -1,31 +1,132 @@
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::*;
+use crate::{prelude::*, verify::NodeLinks};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum DriverMessage {
-	UnionDone { req_id: ReqId },
-	FindDone { req_id: ReqId, response: Key },
-	AddNodeDone { req_id: ReqId, response: Key },
-	ShutdownDone { req_id: ReqId },
+	UnionDone {
+		req_id: ReqId,
+	},
+	FindDone {
+		req_id: ReqId,
+		response: Key,
+	},
+	AddNodeDone {
+		req_id: ReqId,
+		response: Key,
+	},
+	/// The answer to `Driver::find_detailed`
+	FindDetailedDone {
+		req_id: ReqId,
+		response: Key,
+		/// Parents followed from the node to `response`
+		hops: u32,
+		/// Storing the nodes on the way, in the order they were first visited
+		shards: Vec<u16>,
+		/// Nodes in the component of `response`, eventually consistent: it may
+		/// not count the unions still running or just answered. 0 when unknown,
+		/// see `Storage::get_size`
+		component_size: u64,
+	},
+	ShutdownDone {
+		req_id: ReqId,
+	},
+	/// Number of shard to shard messages the shard has sent and received so far
+	BarrierAck {
+		req_id: ReqId,
+		shard: u16,
+		sent: u64,
+		received: u64,
+	},
+	/// The shard is now served by the system it was moved to
+	MoveShardDone {
+		req_id: ReqId,
+		shard: u16,
+	},
+	/// The next nodes of the shard, in the order of their ids, out of `n_nodes`
+	/// sent in chunks for `Driver::verify`
+	VerifyNodes {
+		req_id: ReqId,
+		shard: u16,
+		n_nodes: u64,
+		nodes: Vec<NodeLinks>,
+	},
+	/// The answer to `Driver::find_many`, with the root of each node in the
+	/// order they were given
+	FindManyDone {
+		req_id: ReqId,
+		roots: Vec<Key>,
+	},
+	/// The answer to `Driver::union_many`, once every union is done
+	UnionManyDone {
+		req_id: ReqId,
+	},
+	/// Sent once to every driver when the system detects a failure: pending
+	/// requests may never be answered
+	SystemFailure {
+		error: SystemError,
+	},
+	/// The shard stays where it was, still serving its requests
+	MoveShardFailed {
+		req_id: ReqId,
+		shard: u16,
+		reason: String,
+	},
+	/// A system refused a message of the request, which a peer sent it for a
+	/// shard or node it doesn't know: the request gets no other answer, though
+	/// a union may already have linked its roots
+	RequestRefused {
+		req_id: ReqId,
+		reason: String,
+	},
 }
 
 impl DriverMessage {
-	pub(crate) fn target_driver(&self) -> usize {
-		match *self {
+	/// Whether this is the answer to an `add_node`, `union`, `find` or
+	/// `find_detailed`, refused or not
+	///
+	/// The answers to `find_many` and `union_many` are not, as the receiver
+	/// makes them from the answers of their finds and unions.
+	pub(crate) fn answers_request(&self) -> bool {
+		matches!(
+			self,
+			DriverMessage::UnionDone { .. }
+				| DriverMessage::FindDone { .. }
+				| DriverMessage::FindDetailedDone { .. }
+				| DriverMessage::AddNodeDone { .. }
+				| DriverMessage::RequestRefused { .. }
+		)
+	}
+
+	/// None for a failure, which is for every driver
+	pub(crate) fn target_driver(&self) -> Option<usize> {
+		Some(match *self {
 			DriverMessage::UnionDone { req_id } => req_id.driver(),
 			DriverMessage::FindDone { req_id, .. } => req_id.driver(),
 			DriverMessage::AddNodeDone { req_id, .. } => req_id.driver(),
+			DriverMessage::FindDetailedDone { req_id, .. } => req_id.driver(),
+			DriverMessage::FindManyDone { req_id, .. } => req_id.driver(),
+			DriverMessage::UnionManyDone { req_id } => req_id.driver(),
 			DriverMessage::ShutdownDone { req_id, .. } => req_id.driver(),
-		}
+			DriverMessage::BarrierAck { req_id, .. } => req_id.driver(),
+			DriverMessage::MoveShardDone { req_id, .. } => req_id.driver(),
+			DriverMessage::MoveShardFailed { req_id, .. } => req_id.driver(),
+			DriverMessage::VerifyNodes { req_id, .. } => req_id.driver(),
+			DriverMessage::RequestRefused { req_id, .. } => req_id.driver(),
+			DriverMessage::SystemFailure { .. } => return None,
+		})
 	}
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Deserialize, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Deserialize, Serialize)]
 pub struct ReqId {
-	inner: u64,
+	pub(crate) inner: u64,
 }
 
+/// Marks the requests a driver sends for `find_many` and `union_many`, which
+/// are numbered by the driver past the ids `new` takes
+const BATCHED: u64 = 1 << 47;
+
 impl ReqId {
 	pub fn new(driver: usize, shard_specific_id: u64) -> Self {
 		assert!(driver <= (u16::MAX as usize) && shard_specific_id <= 0x0000FFFFFFFF);
@@ -36,16 +137,57 @@ impl ReqId {
 	pub fn driver(self) -> usize {
 		(self.inner >> 48) as usize
 	}
+	/// Stands for the request of a `SetParent` from a peer that didn't send it,
+	/// never returned by `new`
+	pub(crate) fn unknown() -> Self {
+		Self { inner: u64::MAX }
+	}
 	pub fn driver_specific_id(self) -> u64 {
 		self.inner & 0x0000FFFFFFFF
 	}
+	pub(crate) fn batched(driver: usize, id: u64) -> Self {
+		assert!(driver <= (u16::MAX as usize) && id < BATCHED);
+		Self {
+			inner: ((driver as u64) << 48) | BATCHED | id,
+		}
+	}
+	/// The id given to `batched`
+	pub(crate) fn batched_id(self) -> Option<u64> {
+		(self.inner & BATCHED != 0 && self != Self::unknown()).then_some(self.inner & (BATCHED - 1))
+	}
 }
 
+/// The requests of `find_many` and `union_many` show their `batched_id`, as
+/// their ids don't fit `driver_specific_id`
 impl std::fmt::Debug for ReqId {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		f.debug_struct("ReqId")
-			.field("driver", &self.driver())
-			.field("driver_specific_id", &self.driver_specific_id())
-			.finish()
+		let mut debug = f.debug_struct("ReqId");
+		debug.field("driver", &self.driver());
+		match self.batched_id() {
+			Some(id) => debug.field("batched_id", &id),
+			None => debug.field("driver_specific_id", &self.driver_specific_id()),
+		};
+		debug.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn batched_ids_are_told_apart() {
+		assert_eq!(
+			format!("{:?}", ReqId::new(3, 7)),
+			"ReqId { driver: 3, driver_specific_id: 7 }"
+		);
+		assert_eq!(
+			format!("{:?}", ReqId::batched(3, 7)),
+			"ReqId { driver: 3, batched_id: 7 }"
+		);
+		assert_eq!(
+			format!("{:?}", ReqId::batched(3, 1 << 40)),
+			"ReqId { driver: 3, batched_id: 1099511627776 }"
+		);
 	}
 }
@@ -1,13 +1,47 @@
+mod batches;
 pub(crate) mod message;
+mod receiver;
+mod session;
+
+use std::{
+	sync::{Arc, Mutex, MutexGuard, Weak},
+	time::Instant,
+};
 
 use futures::SinkExt;
 
-use crate::{network_message::NetworkMessage, prelude::*};
+pub use receiver::DriverReceiver;
+
+use {
+	crate::{
+		network_message::NetworkMessage,
+		prelude::*,
+		trace,
+		verify::{ForestCheck, Report},
+	},
+	batches::Batches,
+	receiver::Credits,
+	session::Session,
+};
+
+/// Consecutive waves with the same totals that make a barrier of a degraded
+/// cluster, where they can't be checked against each other
+const DEGRADED_STABLE_WAVES: usize = 3;
 
 pub struct Driver {
-	message_batching: MessageBatching,
+	/// Shared with the thread that flushes the late batches
+	message_batching: Arc<Mutex<MessageBatching>>,
+	system: Arc<System>,
 	driver_id: usize,
-	receiver: crossbeam_channel::Receiver<Vec<DriverMessage>>,
+	receiver: DriverReceiver,
+	credits: Arc<Credits>,
+	/// Shared with the receiver, which sends the finds it holds back
+	session: Arc<Session>,
+	/// Shared with the receiver, which aggregates their answers
+	batches: Arc<Batches>,
+	/// Tags the `Barrier` messages of each wave, so that the acks of a barrier
+	/// that failed are not counted by the next one
+	next_barrier_wave: u64,
 }
 
 impl Driver {
@@ -16,10 +50,27 @@ impl Driver {
 		driver_id: usize,
 		receiver: crossbeam_channel::Receiver<Vec<DriverMessage>>,
 	) -> Self {
+		let credits = Arc::new(Credits::new());
+		let system = message_batching.system.clone();
+		let message_batching = Arc::new(Mutex::new(message_batching));
+		let flusher = spawn_flusher(Arc::downgrade(&message_batching));
+		message_batching.lock().unwrap().set_flusher(flusher);
+		let session = Arc::new(Session::new(Arc::downgrade(&message_batching)));
+		let batches = Arc::new(Batches::default());
 		Driver {
 			message_batching,
+			system,
 			driver_id,
-			receiver,
+			receiver: DriverReceiver::new(
+				receiver,
+				credits.clone(),
+				session.clone(),
+				batches.clone(),
+			),
+			credits,
+			session,
+			batches,
+			next_barrier_wave: 0,
 		}
 	}
 
@@ -27,48 +78,326 @@ impl Driver {
 		self.driver_id
 	}
 
-	pub fn receiver(&self) -> &crossbeam_channel::Receiver<Vec<DriverMessage>> {
+	pub fn receiver(&self) -> &DriverReceiver {
 		&self.receiver
 	}
 
-	// You should flush if you want stuff to happen
+	/// How many `add_node`, `union` and `find` can wait for their answer, a
+	/// million by default, each node of a `find_many` or `union_many` counting
+	/// as one
+	///
+	/// Past that, these calls block until answers are read from `receiver()`.
+	/// This is admission control for this driver only: the queues between the
+	/// drivers, shards and systems are unbounded, and the messages a request
+	/// causes after its answer, such as path compressions, take no credit.
+	///
+	/// A thread that sends requests and only then reads their answers blocks
+	/// forever once it reaches the maximum: read the answers on another thread,
+	/// or read them before sending more than `max_in_flight` requests.
+	pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+		self.credits.set_max_in_flight(max_in_flight);
+	}
+
+	/// How requests are batched before being sent to the shards
+	///
+	/// With a maximum delay, the default, a background thread sends the batches
+	/// that waited too long, so `flush` is only needed to send them sooner.
+	pub fn set_batching_policy(&mut self, policy: BatchingPolicy) {
+		self.batching().set_policy(policy);
+	}
+
+	/// Makes every `find` see the unions this driver sent before it, off by
+	/// default
+	///
+	/// Without it, a find sent before the `UnionDone` of a union can overtake
+	/// it, as their messages take other routes. With it, the driver holds the
+	/// find back until these unions are answered, then the `receiver()` that
+	/// reads the last answer sends it. A find sent after reading a `UnionDone`
+	/// always sees that union. The unions in flight need distinct `req_id`s.
+	/// Once a `SystemFailure` is read, the finds held back are sent without
+	/// waiting.
+	pub fn set_read_your_writes(&mut self, read_your_writes: bool) {
+		self.session.set_enabled(read_your_writes);
+	}
+
+	fn batching(&self) -> MutexGuard<'_, MessageBatching> {
+		self.message_batching.lock().unwrap()
+	}
+
+	fn send_to_shard(&self, message: ShardMessage) {
+		trace::sent(self.system.system_id(), &message);
+		self.batching().send_to_shard(message);
+	}
+
+	/// Blocks until a request can be sent, after sending the ones still batched
+	/// so that their answers can come back
+	fn acquire_credit(&mut self) {
+		if !self.credits.try_acquire() {
+			self.flush();
+			self.credits.acquire();
+		}
+	}
+
+	/// Sent with the next batch, see `set_batching_policy`
 	pub fn add_node(&mut self, req_id: u64, shard: u16) {
-		self.message_batching.send_to_shard(ShardMessage::AddNode {
+		self.acquire_credit();
+		self.send_to_shard(ShardMessage::AddNode {
 			shard,
 			req_id: self.req_id(req_id),
 		})
 	}
 
-	/// You should flush if you want stuff to happen
+	/// Sent with the next batch, see `set_batching_policy`
+	///
+	/// Answered with `UnionDone` once `node` and `to` are in the same component,
+	/// even if they already were
 	pub fn union(&mut self, req_id: u64, node: Key, to: Key) {
-		self.message_batching.send_to_shard(ShardMessage::Union {
+		self.send_union(self.req_id(req_id), node, to);
+	}
+
+	fn send_union(&mut self, req_id: ReqId, node: Key, to: Key) {
+		self.acquire_credit();
+		self.session.union_sent(req_id);
+		self.send_to_shard(ShardMessage::Union {
 			node,
 			to,
 			child: node,
-			req_id: self.req_id(req_id),
+			req_id,
+			hops: 0,
 		})
 	}
 
-	/// You should flush if you want to get a result at some point
+	/// Sent with the next batch, see `set_batching_policy`
+	///
+	/// Answered with `FindDone` and a root that `node` had at some point since
+	/// the request was sent, the smallest node of its component when no union
+	/// is running
 	pub fn find(&mut self, req_id: u64, node: Key) {
-		self.message_batching.send_to_shard(ShardMessage::Find {
+		self.send_find(ShardMessage::Find {
 			node,
 			child: node,
 			req_id: self.req_id(req_id),
+			hops: 0,
+		});
+	}
+
+	fn send_find(&mut self, message: ShardMessage) {
+		self.acquire_credit();
+		if let Some(message) = self.session.find(message) {
+			self.send_to_shard(message);
+		}
+	}
+
+	/// A `find` answered with `FindDetailedDone`, which also tells the hops it
+	/// took, the shards it went through and the size of the component
+	///
+	/// Sizes are eventually consistent: a union adds to the size of its root
+	/// with a message of its own, which may arrive after its `UnionDone`.
+	///
+	/// Its message grows with the shards it visits, so `find` is cheaper when
+	/// only the root is needed.
+	pub fn find_detailed(&mut self, req_id: u64, node: Key) {
+		self.send_find(ShardMessage::FindDetailed {
+			node,
+			child: node,
+			req_id: self.req_id(req_id),
+			hops: 0,
+			shards: Vec::new(),
+		});
+	}
+
+	/// A `find` of each node, answered with a single `FindManyDone` holding
+	/// their roots in the same order once they are all found
+	///
+	/// The finds are sent like separate ones, each with a credit, but the
+	/// `receiver()` keeps their answers to itself, so only one `req_id` is needed
+	/// for the whole batch. An empty batch is answered right away.
+	pub fn find_many(&mut self, req_id: u64, nodes: &[Key]) {
+		if nodes.is_empty() {
+			return self.answer_empty_batch(DriverMessage::FindManyDone {
+				req_id: self.req_id(req_id),
+				roots: Vec::new(),
+			});
+		}
+		let first = self.batches.start(self.req_id(req_id), nodes.len(), true);
+		for (i, &node) in nodes.iter().enumerate() {
+			self.send_find(ShardMessage::Find {
+				node,
+				child: node,
+				req_id: ReqId::batched(self.driver_id, first + i as u64),
+				hops: 0,
+			});
+		}
+	}
+
+	/// A `union` of each pair, answered with a single `UnionManyDone` once they
+	/// are all done, see `find_many`
+	pub fn union_many(&mut self, req_id: u64, unions: &[(Key, Key)]) {
+		if unions.is_empty() {
+			return self.answer_empty_batch(DriverMessage::UnionManyDone {
+				req_id: self.req_id(req_id),
+			});
+		}
+		let first = self.batches.start(self.req_id(req_id), unions.len(), false);
+		for (i, &(node, to)) in unions.iter().enumerate() {
+			self.send_union(ReqId::batched(self.driver_id, first + i as u64), node, to);
+		}
+	}
+
+	/// Through the `receiver()`, as the answers of the batches with requests
+	fn answer_empty_batch(&self, message: DriverMessage) {
+		self.system
+			.driver(self.driver_id)
+			.send_messages(vec![message]);
+	}
+
+	/// Moves the shard and its nodes to another system, answered with
+	/// `MoveShardDone` once that system serves it
+	///
+	/// The messages the shard receives in the meantime are forwarded to its new
+	/// system, so requests can keep flowing during the migration. If that system
+	/// isn't a peer, refuses the shard or is lost before it serves it, the
+	/// answer is `MoveShardFailed` and the shard keeps being served where it
+	/// was.
+	pub fn move_shard(&mut self, req_id: u64, shard: u16, to_system: u16) {
+		self.send_to_shard(ShardMessage::Migrate {
+			shard,
+			to_system,
+			req_id: self.req_id(req_id),
 		});
 	}
 
+	/// Blocks until every shard has processed every message caused by the
+	/// requests sent before, including the path compression `SetParent` messages
+	/// that never get an answer
+	///
+	/// Shards count the shard to shard messages they send and receive, and we ask
+	/// for these counters in waves until two consecutive waves report the same
+	/// totals with as many messages received as sent.
+	///
+	/// This reads the driver channel, so nothing else should be reading from
+	/// `receiver()` at the same time. The other messages received while waiting
+	/// are returned.
+	///
+	/// Fails if the system reports a failure while waiting, as the barrier could
+	/// then never be reached, and once the cluster has failed. Once degraded, the
+	/// shards taken over from a lost system count from zero again and the
+	/// messages lost with it are never received, so the totals no longer add up:
+	/// we then wait for `DEGRADED_STABLE_WAVES` waves with the same totals.
+	pub fn barrier(&mut self) -> Result<Vec<DriverMessage>, SystemError> {
+		let n_shards = self.system().n_shards();
+		let mut other_messages = Vec::new();
+		let mut previous_wave = None;
+		let mut n_stable_waves = 0;
+		loop {
+			let degraded = match self.system().health() {
+				ClusterHealth::Healthy => false,
+				ClusterHealth::Degraded(_) => true,
+				ClusterHealth::Failed(error) => return Err(error),
+			};
+			let wave = self.req_id(self.next_barrier_wave);
+			self.next_barrier_wave = (self.next_barrier_wave + 1) & 0xFFFF_FFFF;
+			for shard in 0..n_shards {
+				self.send_to_shard(ShardMessage::Barrier {
+					shard: shard as u16,
+					req_id: wave,
+				});
+			}
+			self.flush();
+
+			let (mut sent, mut received, mut n_acks) = (0, 0, 0);
+			while n_acks < n_shards {
+				for message in self.receiver.recv().expect("Lost the driver channel") {
+					match message {
+						DriverMessage::BarrierAck {
+							req_id,
+							sent: shard_sent,
+							received: shard_received,
+							..
+						} => {
+							if req_id == wave {
+								sent += shard_sent;
+								received += shard_received;
+								n_acks += 1;
+							}
+						}
+						DriverMessage::SystemFailure { error } => return Err(error),
+						message => other_messages.push(message),
+					}
+				}
+			}
+			if previous_wave == Some((sent, received)) {
+				n_stable_waves += 1;
+			} else {
+				n_stable_waves = 0;
+			}
+			if (!degraded && sent == received && n_stable_waves > 0)
+				|| (degraded && n_stable_waves >= DEGRADED_STABLE_WAVES)
+			{
+				return Ok(other_messages);
+			}
+			previous_wave = Some((sent, received));
+		}
+	}
+
+	/// Checks the structure stored by the shards, see `ForestCheck`
+	///
+	/// Runs a `barrier` first, so that the requests sent before are complete,
+	/// but the requests other drivers send in the meantime may show as
+	/// violations. Every shard sends its nodes to this driver, which holds about
+	/// 32 bytes per node of the union find during the check. As with `barrier`,
+	/// the other messages received while waiting are returned.
+	pub fn verify(&mut self, req_id: u64) -> Result<(Report, Vec<DriverMessage>), SystemError> {
+		let mut other_messages = self.barrier()?;
+		let n_shards = self.system().n_shards();
+		for shard in 0..n_shards {
+			self.send_to_shard(ShardMessage::Verify {
+				shard: shard as u16,
+				req_id: self.req_id(req_id),
+			});
+		}
+		self.flush();
+
+		let mut forest = ForestCheck::new(n_shards);
+		// Known from the first chunk of each shard
+		let mut remaining_nodes = vec![None; n_shards];
+		while remaining_nodes
+			.iter()
+			.any(|&remaining| remaining != Some(0))
+		{
+			for message in self.receiver.recv().expect("Lost the driver channel") {
+				match message {
+					DriverMessage::VerifyNodes {
+						shard,
+						n_nodes,
+						nodes,
+						..
+					} => {
+						let Some(remaining) = remaining_nodes.get_mut(shard as usize) else {
+							continue;
+						};
+						let remaining = remaining.get_or_insert(n_nodes);
+						*remaining = remaining.saturating_sub(nodes.len() as u64);
+						forest.add_nodes(shard as usize, nodes);
+					}
+					DriverMessage::SystemFailure { error } => return Err(error),
+					message => other_messages.push(message),
+				}
+			}
+		}
+		Ok((forest.check(), other_messages))
+	}
+
 	/// Expects that all the message queues are empty (all sent messages have already
 	/// been processed), otherwise may trigger a panic
 	pub fn shutdown_all_and_wait_for_completion(mut self) {
 		for shard in 0..(self.system().n_shards() as u64) {
-			self.message_batching
-				.send_to_shard(ShardMessage::GracefulShutdown {
-					shard: shard as u16,
-					req_id: self.req_id(shard),
-				});
+			self.send_to_shard(ShardMessage::GracefulShutdown {
+				shard: shard as u16,
+				req_id: self.req_id(shard),
+			});
 		}
-		self.message_batching.flush();
+		self.flush();
 
 		let mut messages = self.receiver().into_iter().flatten();
 		for _ in 0..self.system().n_shards() {
@@ -81,8 +410,10 @@ impl Driver {
 			}
 		}
 	}
+
+	/// Sends the batched requests now
 	pub fn flush(&mut self) {
-		self.message_batching.flush();
+		self.batching().flush();
 	}
 
 	pub(crate) fn req_id(&self, req_id: u64) -> ReqId {
@@ -90,18 +421,53 @@ impl Driver {
 	}
 
 	pub(crate) fn system(&self) -> &System {
-		&self.message_batching.system
+		&self.system
 	}
 }
 
+/// Flushes the batches that waited longer than the maximum delay of the
+/// policy, until the driver is dropped. Parked while nothing is batched.
+fn spawn_flusher(message_batching: Weak<Mutex<MessageBatching>>) -> std::thread::Thread {
+	std::thread::spawn(move || loop {
+		let Some(message_batching) = message_batching.upgrade() else {
+			return;
+		};
+		let deadline = message_batching.lock().unwrap().flush_if_late();
+		drop(message_batching);
+		// A message batched since then has left its unpark token
+		match deadline {
+			Some(deadline) => {
+				std::thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+			}
+			None => std::thread::park(),
+		}
+	})
+	.thread()
+	.clone()
+}
+
 pub(crate) trait DriverAccess: Sync + Send {
 	fn send_messages(&self, batch: Vec<DriverMessage>);
+	/// Best effort: the driver may already be gone
+	fn send_failure(&self, error: SystemError);
+	/// Batches waiting to be read, when the driver is local
+	fn queue_len(&self) -> Option<usize> {
+		None
+	}
 }
 
 impl DriverAccess for crossbeam_channel::Sender<Vec<DriverMessage>> {
 	fn send_messages(&self, batch: Vec<DriverMessage>) {
 		self.send(batch).unwrap()
 	}
+
+	fn queue_len(&self) -> Option<usize> {
+		Some(self.len())
+	}
+
+	fn send_failure(&self, error: SystemError) {
+		let _ = self.send(vec![DriverMessage::SystemFailure { error }]);
+	}
 }
 
 pub(crate) struct RemoteDriverAccess {
@@ -111,12 +477,18 @@ pub(crate) struct RemoteDriverAccess {
 
 impl DriverAccess for RemoteDriverAccess {
 	fn send_messages(&self, batch: Vec<DriverMessage>) {
-		futures::executor::block_on(self.system_channel.clone().send(
+		// If the link is down the batch is lost, but the forwarding task reports
+		// the failure to every driver
+		let _ = futures::executor::block_on(self.system_channel.clone().send(
 			NetworkMessage::DriverMessages {
+				seq: 0,
 				driver_idx: self.driver_idx,
 				batch,
 			},
-		))
-		.unwrap()
+		));
+	}
+
+	fn send_failure(&self, error: SystemError) {
+		self.send_messages(vec![DriverMessage::SystemFailure { error }]);
 	}
 }
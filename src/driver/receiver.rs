@@ -0,0 +1,163 @@
+use std::{
+	sync::{Arc, Condvar, Mutex},
+	time::Duration,
+};
+
+use crossbeam_channel::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+
+use super::{batches::Batches, session::Session};
+use crate::prelude::*;
+
+/// Requests a driver can have sent and not seen answered yet, unless
+/// configured otherwise with `Driver::set_max_in_flight`
+pub(crate) const DEFAULT_MAX_IN_FLIGHT: usize = 1 << 20;
+
+/// Credit-based admission control of the requests of a driver
+///
+/// Every `add_node`, `union` and `find`, also when part of a `find_many` or
+/// `union_many`, takes a credit, given back when its
+/// answer is read from the `DriverReceiver`. Each request causes at most three
+/// messages per hop while it runs, the next steps and a path compression, so
+/// this bounds the messages of the requests in flight, while the shards never
+/// wait on each other, which could deadlock. The queues themselves stay
+/// unbounded: the path compressions and size updates still queued when a
+/// request is answered, and the mutations streamed to replicas, take no
+/// credit.
+pub(crate) struct Credits {
+	state: Mutex<CreditState>,
+	released: Condvar,
+}
+
+struct CreditState {
+	in_flight: usize,
+	max_in_flight: usize,
+}
+
+impl Credits {
+	pub fn new() -> Self {
+		Self {
+			state: Mutex::new(CreditState {
+				in_flight: 0,
+				max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+			}),
+			released: Condvar::new(),
+		}
+	}
+
+	/// Returns false instead of waiting when there is no credit left
+	pub fn try_acquire(&self) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if state.in_flight < state.max_in_flight {
+			state.in_flight += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	pub fn acquire(&self) {
+		let mut state = self
+			.released
+			.wait_while(self.state.lock().unwrap(), |state| {
+				state.in_flight >= state.max_in_flight
+			})
+			.unwrap();
+		state.in_flight += 1;
+	}
+
+	fn release(&self, n: usize) {
+		if n > 0 {
+			let mut state = self.state.lock().unwrap();
+			state.in_flight = state.in_flight.saturating_sub(n);
+			self.released.notify_all();
+		}
+	}
+
+	/// After a failure some requests may never be answered, so we stop waiting
+	/// for them
+	fn release_all(&self) {
+		self.state.lock().unwrap().in_flight = 0;
+		self.released.notify_all();
+	}
+
+	pub fn set_max_in_flight(&self, max_in_flight: usize) {
+		assert!(max_in_flight > 0, "A driver needs at least one credit");
+		self.state.lock().unwrap().max_in_flight = max_in_flight;
+		self.released.notify_all();
+	}
+}
+
+/// The answers to the requests of a driver
+///
+/// Reading answers gives their credit back to the driver, so a driver that
+/// sends many requests must have them read on another thread, or read them
+/// before it reaches its maximum number of requests in flight.
+#[derive(Clone)]
+pub struct DriverReceiver {
+	receiver: Receiver<Vec<DriverMessage>>,
+	credits: Arc<Credits>,
+	session: Arc<Session>,
+	batches: Arc<Batches>,
+}
+
+impl DriverReceiver {
+	pub(crate) fn new(
+		receiver: Receiver<Vec<DriverMessage>>,
+		credits: Arc<Credits>,
+		session: Arc<Session>,
+		batches: Arc<Batches>,
+	) -> Self {
+		Self {
+			receiver,
+			credits,
+			session,
+			batches,
+		}
+	}
+
+	fn received(&self, batch: Vec<DriverMessage>) -> Vec<DriverMessage> {
+		if batch
+			.iter()
+			.any(|message| matches!(message, DriverMessage::SystemFailure { .. }))
+		{
+			self.credits.release_all();
+		} else {
+			let n_answers = batch
+				.iter()
+				.filter(|message| message.answers_request())
+				.count();
+			self.credits.release(n_answers);
+		}
+		self.session.received(&batch);
+		self.batches.received(batch)
+	}
+
+	pub fn recv(&self) -> Result<Vec<DriverMessage>, RecvError> {
+		self.receiver.recv().map(|batch| self.received(batch))
+	}
+
+	pub fn try_recv(&self) -> Result<Vec<DriverMessage>, TryRecvError> {
+		self.receiver.try_recv().map(|batch| self.received(batch))
+	}
+
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<DriverMessage>, RecvTimeoutError> {
+		self.receiver
+			.recv_timeout(timeout)
+			.map(|batch| self.received(batch))
+	}
+
+	/// Blocks on each batch, until the system is dropped
+	pub fn iter(&self) -> impl Iterator<Item = Vec<DriverMessage>> + '_ {
+		std::iter::from_fn(|| self.recv().ok())
+	}
+}
+
+impl<'a> IntoIterator for &'a DriverReceiver {
+	type Item = Vec<DriverMessage>;
+
+	type IntoIter = Box<dyn Iterator<Item = Vec<DriverMessage>> + 'a>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		Box::new(self.iter())
+	}
+}
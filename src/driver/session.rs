@@ -0,0 +1,132 @@
+use std::{
+	collections::{BTreeMap, HashMap, VecDeque},
+	sync::{Mutex, Weak},
+};
+
+use crate::{prelude::*, trace};
+
+/// Read-your-writes ordering of the requests of a driver, see
+/// `Driver::set_read_your_writes`
+///
+/// Each union is numbered in the order it's sent. A find waits with the number
+/// of the next union until every union numbered before is answered, and the
+/// `DriverReceiver` sends it when it reads the last of these answers.
+pub(crate) struct Session {
+	state: Mutex<SessionState>,
+	/// Weak so that the receiver doesn't keep the flusher of a dropped driver
+	message_batching: Weak<Mutex<MessageBatching>>,
+}
+
+#[derive(Default)]
+struct SessionState {
+	enabled: bool,
+	next_union: u64,
+	/// The unions not answered yet, by number
+	unanswered: BTreeMap<u64, ReqId>,
+	/// The numbers of `unanswered` by request id, oldest first as the user may
+	/// reuse an id
+	numbers: HashMap<ReqId, VecDeque<u64>>,
+	/// With the number of the first union they don't wait for, which only grows
+	held: VecDeque<(u64, ShardMessage)>,
+}
+
+impl SessionState {
+	fn oldest_unanswered(&self) -> u64 {
+		self.unanswered
+			.first_key_value()
+			.map_or(self.next_union, |(&union, _)| union)
+	}
+}
+
+impl Session {
+	pub fn new(message_batching: Weak<Mutex<MessageBatching>>) -> Self {
+		Self {
+			state: Mutex::default(),
+			message_batching,
+		}
+	}
+
+	pub fn set_enabled(&self, enabled: bool) {
+		self.state.lock().unwrap().enabled = enabled;
+	}
+
+	/// Before the union is sent, so that its answer can't come first
+	pub fn union_sent(&self, req_id: ReqId) {
+		let mut state = self.state.lock().unwrap();
+		if state.enabled {
+			let union = state.next_union;
+			state.next_union += 1;
+			state.unanswered.insert(union, req_id);
+			state.numbers.entry(req_id).or_default().push_back(union);
+		}
+	}
+
+	/// Gives the find back if it can be sent now
+	pub fn find(&self, message: ShardMessage) -> Option<ShardMessage> {
+		let mut state = self.state.lock().unwrap();
+		if !state.enabled || state.oldest_unanswered() == state.next_union {
+			return Some(message);
+		}
+		let union = state.next_union;
+		state.held.push_back((union, message));
+		None
+	}
+
+	/// Sends the finds that waited for the unions answered in `batch`
+	pub fn received(&self, batch: &[DriverMessage]) {
+		let released: Vec<ShardMessage> = {
+			let mut state = self.state.lock().unwrap();
+			if state.held.is_empty() && state.unanswered.is_empty() {
+				return;
+			}
+			for message in batch {
+				match *message {
+					// A refused union gets no other answer
+					DriverMessage::UnionDone { req_id }
+					| DriverMessage::RequestRefused { req_id, .. } => {
+						let Some(numbers) = state.numbers.get_mut(&req_id) else {
+							continue;
+						};
+						let union = numbers.pop_front();
+						if numbers.is_empty() {
+							state.numbers.remove(&req_id);
+						}
+						if let Some(union) = union {
+							state.unanswered.remove(&union);
+						}
+					}
+					// The unions the held finds wait for may never be answered,
+					// so they go now
+					DriverMessage::SystemFailure { .. } => {
+						state.unanswered.clear();
+						state.numbers.clear();
+					}
+					_ => {}
+				}
+			}
+			let oldest_unanswered = state.oldest_unanswered();
+			let n_released = state
+				.held
+				.iter()
+				.take_while(|&&(union, _)| union <= oldest_unanswered)
+				.count();
+			state
+				.held
+				.drain(..n_released)
+				.map(|(_, message)| message)
+				.collect()
+		};
+		if released.is_empty() {
+			return;
+		}
+		if let Some(message_batching) = self.message_batching.upgrade() {
+			let mut message_batching = message_batching.lock().unwrap();
+			for message in released {
+				trace::sent(message_batching.system.system_id(), &message);
+				message_batching.send_to_shard(message);
+			}
+			// The driver may never flush again, e.g. if it waits for these answers
+			message_batching.flush();
+		}
+	}
+}
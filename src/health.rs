@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+/// Time between two heartbeats, sent on every link whether it's busy or not
+pub(crate) const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// A peer we haven't heard from for that long is considered dead
+pub(crate) const HEARTBEAT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum SystemError {
+	/// We didn't receive anything from this peer for `HEARTBEAT_TIMEOUT`
+	HeartbeatTimeout { peer: u16 },
+	/// The link to this peer failed
+	PeerDisconnected { peer: u16, reason: String },
+	/// This peer restarted, the messages it was processing were lost
+	PeerRestarted { peer: u16 },
+}
+
+impl std::fmt::Display for SystemError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SystemError::HeartbeatTimeout { peer } => {
+				write!(f, "No heartbeat received from system {peer}")
+			}
+			SystemError::PeerDisconnected { peer, reason } => {
+				write!(f, "Lost the connection to system {peer}: {reason}")
+			}
+			SystemError::PeerRestarted { peer } => write!(f, "System {peer} restarted"),
+		}
+	}
+}
+
+impl std::error::Error for SystemError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterHealth {
+	Healthy,
+	/// A system was lost but replicas took over its shards: the system is usable
+	/// again, but requests that were pending at that time may never be answered
+	Degraded(SystemError),
+	/// Some messages may have been lost, pending requests will never be answered
+	Failed(SystemError),
+}
@@ -0,0 +1,228 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::transport::Listener;
+
+/// Requests with bigger headers are dropped
+const MAX_REQUEST_SIZE: usize = 8 << 10;
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Response {
+	pub status: u16,
+	pub content_type: &'static str,
+	pub body: String,
+}
+
+impl Response {
+	pub fn text(body: String) -> Self {
+		Self {
+			status: 200,
+			content_type: "text/plain; charset=utf-8",
+			body,
+		}
+	}
+
+	pub fn json(body: String) -> Self {
+		Self {
+			status: 200,
+			content_type: "application/json",
+			body,
+		}
+	}
+
+	pub fn error(status: u16, body: impl Into<String>) -> Self {
+		Self {
+			status,
+			content_type: "text/plain; charset=utf-8",
+			body: body.into(),
+		}
+	}
+}
+
+/// What the handler is given
+pub(crate) struct Request {
+	pub method: String,
+	pub path: String,
+	/// Percent-decoded
+	pub query: Vec<(String, String)>,
+	/// Names in lowercase
+	pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+	/// The request line and the headers, `None` if there is no request line
+	fn parse(head: &str) -> Option<Self> {
+		let mut lines = head.lines();
+		let mut request_line = lines.next()?.split(' ');
+		let (method, target) = (request_line.next()?, request_line.next()?);
+		let (path, query) = target.split_once('?').unwrap_or((target, ""));
+		Some(Self {
+			method: method.to_owned(),
+			path: path.to_owned(),
+			query: query
+				.split('&')
+				.filter(|pair| !pair.is_empty())
+				.map(|pair| {
+					let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+					(percent_decode(name), percent_decode(value))
+				})
+				.collect(),
+			headers: lines
+				.take_while(|line| !line.is_empty())
+				.filter_map(|line| {
+					let (name, value) = line.split_once(':')?;
+					Some((name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+				})
+				.collect(),
+		})
+	}
+
+	pub fn query(&self, name: &str) -> Option<&str> {
+		self.query
+			.iter()
+			.find(|(n, _)| n == name)
+			.map(|(_, value)| value.as_str())
+	}
+
+	/// The token of an `Authorization: Bearer` header
+	pub fn bearer_token(&self) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(name, _)| name == "authorization")?
+			.1
+			.strip_prefix("Bearer ")
+	}
+}
+
+/// Invalid escapes are kept as they are
+fn percent_decode(s: &str) -> String {
+	let mut bytes = Vec::with_capacity(s.len());
+	let mut input = s.as_bytes();
+	while let Some((&byte, rest)) = input.split_first() {
+		let hex = rest
+			.get(..2)
+			.and_then(|hex| std::str::from_utf8(hex).ok())
+			.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+		match (byte, hex) {
+			(b'%', Some(decoded)) => {
+				bytes.push(decoded);
+				input = &rest[2..];
+			}
+			(b'+', _) => {
+				bytes.push(b' ');
+				input = rest;
+			}
+			_ => {
+				bytes.push(byte);
+				input = rest;
+			}
+		}
+	}
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A minimal HTTP/1.1 server for the endpoints of a system: each connection
+/// gets one response from `handler`
+///
+/// Request bodies are ignored, parameters are passed in the query string.
+pub(crate) async fn serve<H, F>(mut listener: Box<dyn Listener>, handler: H)
+where
+	H: Fn(Request) -> F + Send + Sync + 'static,
+	F: Future<Output = Response> + Send + 'static,
+{
+	let handler = Arc::new(handler);
+	while let Ok(incoming) = listener.accept().await {
+		let handler = handler.clone();
+		tokio::spawn(async move {
+			let Ok(mut stream) = incoming.await else {
+				return;
+			};
+			let Ok(Some(request)) = tokio::time::timeout(REQUEST_TIMEOUT, async {
+				let mut request = Vec::new();
+				let mut buffer = [0; 1024];
+				while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+					let n = stream.read(&mut buffer).await.ok()?;
+					if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+						return None;
+					}
+					request.extend_from_slice(&buffer[..n]);
+				}
+				Some(request)
+			})
+			.await
+			else {
+				return;
+			};
+			let response = match Request::parse(&String::from_utf8_lossy(&request)) {
+				Some(request) => handler(request).await,
+				None => Response::error(400, "Bad request"),
+			};
+			let head = format!(
+				"HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+				response.status,
+				reason(response.status),
+				response.content_type,
+				response.body.len()
+			);
+			let _ = stream.write_all(head.as_bytes()).await;
+			let _ = stream.write_all(response.body.as_bytes()).await;
+			let _ = stream.shutdown().await;
+		});
+	}
+}
+
+fn reason(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		409 => "Conflict",
+		500 => "Internal Server Error",
+		503 => "Service Unavailable",
+		_ => "",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn percent_decoding() {
+		assert_eq!(percent_decode("a%20b+c"), "a b c");
+		assert_eq!(percent_decode("%2Ftmp%2fx"), "/tmp/x");
+		assert_eq!(percent_decode("%C3%A9"), "é");
+		// Invalid escapes are kept
+		assert_eq!(percent_decode("100%"), "100%");
+		assert_eq!(percent_decode("%zz%4"), "%zz%4");
+		assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+	}
+
+	#[test]
+	fn requests() {
+		let request = Request::parse(
+			"POST /checkpoint?dir=a%2Fb&flag&=x HTTP/1.1\r\n\
+			 Host: localhost\r\n\
+			 AUTHORIZATION:  Bearer secret \r\n\r\n",
+		)
+		.unwrap();
+		assert_eq!(request.method, "POST");
+		assert_eq!(request.path, "/checkpoint");
+		assert_eq!(request.query("dir"), Some("a/b"));
+		assert_eq!(request.query("flag"), Some(""));
+		assert_eq!(request.query(""), Some("x"));
+		assert_eq!(request.query("other"), None);
+		assert_eq!(request.bearer_token(), Some("secret"));
+
+		let request = Request::parse("GET /status HTTP/1.1\r\n\r\n").unwrap();
+		assert!(request.query.is_empty());
+		assert_eq!(request.bearer_token(), None);
+		assert!(Request::parse("").is_none());
+		assert!(Request::parse("GET\r\n\r\n").is_none());
+	}
+}
@@ -1,10 +1,25 @@
+mod address;
+mod admin;
+mod batch_pool;
+mod compact_batch;
 mod driver;
+mod health;
+mod http;
 mod key;
 mod message_batching;
+mod metrics;
+mod network_config;
+mod network_link;
 mod network_message;
+mod replication;
 mod shard;
+mod shared_memory;
+pub mod simulation;
 pub mod storage;
 mod system;
+mod trace;
+mod transport;
+pub mod verify;
 
 mod prelude {
 	use super::*;
@@ -14,8 +29,9 @@ mod prelude {
 			message::{DriverMessage, ReqId},
 			Driver, DriverAccess,
 		},
+		health::{ClusterHealth, SystemError},
 		key::Key,
-		message_batching::MessageBatching,
+		message_batching::{BatchingPolicy, MessageBatching},
 		shard::{message::ShardMessage, ShardAccess},
 		storage::Storage,
 		system::System,
@@ -23,6 +39,12 @@ mod prelude {
 }
 
 pub use {
-	driver::{message::DriverMessage, Driver},
+	address::Address,
+	driver::{message::DriverMessage, Driver, DriverReceiver},
+	health::{ClusterHealth, SystemError},
+	key::Key,
+	message_batching::BatchingPolicy,
+	network_config::NetworkConfig,
 	system::System,
+	transport::{Connection, Incoming, Listener, MemoryTransport, SocketTransport, Transport},
 };
@@ -6,7 +6,7 @@ fn main() {
 	let id_count: u64 = 1_000_000;
 
 	rayon::ThreadPoolBuilder::new()
-		.num_threads(2 * (driver_count as usize) + 1)
+		.num_threads(2 * driver_count + 1)
 		.build_global()
 		.unwrap();
 
@@ -16,7 +16,7 @@ fn main() {
 		shard_count,
 	);
 
-	let barrier = std::sync::Barrier::new(2 * (driver_count as usize) + 1);
+	let barrier = std::sync::Barrier::new(2 * driver_count + 1);
 
 	let start_time = rayon::scope(|s| {
 		drivers.iter_mut().for_each(|driver| {
@@ -48,6 +48,15 @@ fn main() {
 							} => {
 								panic!("There should be no find")
 							}
+							DriverMessage::FindDetailedDone { .. } => {
+								panic!("There should be no find")
+							}
+							DriverMessage::FindManyDone { .. } => {
+								panic!("There should be no find")
+							}
+							DriverMessage::UnionManyDone { .. } => {
+								panic!("There should be no unions")
+							}
 							DriverMessage::AddNodeDone {
 								req_id: _,
 								response: _,
@@ -57,6 +66,22 @@ fn main() {
 							DriverMessage::ShutdownDone { req_id: _ } => {
 								panic!("There should be no Shutdown");
 							}
+							DriverMessage::BarrierAck { .. } => {
+								panic!("There should be no Barrier");
+							}
+							DriverMessage::MoveShardDone { .. }
+							| DriverMessage::MoveShardFailed { .. } => {
+								panic!("There should be no shard migration");
+							}
+							DriverMessage::VerifyNodes { .. } => {
+								panic!("There should be no verification");
+							}
+							DriverMessage::SystemFailure { error } => {
+								panic!("The system failed: {error}");
+							}
+							DriverMessage::RequestRefused { reason, .. } => {
+								panic!("A request was refused: {reason}");
+							}
 						}
 					}
 				}
@@ -1,12 +1,94 @@
-use std::sync::Arc;
+use std::{
+	sync::Arc,
+	thread::Thread,
+	time::{Duration, Instant},
+};
 
-use crate::prelude::*;
+use crate::{
+	prelude::*,
+	replication::{Mutation, ReplicationLog},
+};
+
+/// When the messages batched for a shard or a driver are sent
+///
+/// Big batches give the best throughput, a maximum delay bounds the latency of
+/// the requests that don't fill a batch.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchingPolicy {
+	max_batch_len: usize,
+	max_delay: Option<Duration>,
+	adaptive: bool,
+}
+
+impl Default for BatchingPolicy {
+	/// Batches of up to 50,000 messages, sent within 2 ms, and smaller when the
+	/// receiver is idle
+	fn default() -> Self {
+		Self {
+			max_batch_len: 50_000,
+			max_delay: Some(Duration::from_millis(2)),
+			adaptive: true,
+		}
+	}
+}
+
+impl BatchingPolicy {
+	pub fn with_max_batch_len(mut self, max_batch_len: usize) -> Self {
+		assert!(max_batch_len > 0, "Batches hold at least one message");
+		self.max_batch_len = max_batch_len;
+		self
+	}
+
+	/// Batched messages are sent after about that delay, even when
+	/// `flush` isn't called. `None` waits for full batches or `flush`.
+	pub fn with_max_delay(mut self, max_delay: Option<Duration>) -> Self {
+		self.max_delay = max_delay;
+		self
+	}
+
+	/// Sends smaller batches to the shards and drivers of this system whose
+	/// queue is short: an idle receiver gets a batch as soon as it holds
+	/// 1/256th of the maximum, and every batch already queued doubles that, up
+	/// to the maximum
+	pub fn with_adaptive(mut self, adaptive: bool) -> Self {
+		self.adaptive = adaptive;
+		self
+	}
+
+	pub fn max_delay(&self) -> Option<Duration> {
+		self.max_delay
+	}
+
+	/// Whether a batch of `len` messages should be sent, given the number of
+	/// batches its receiver hasn't processed yet if we know it
+	fn is_full(&self, len: usize, queue_len: impl FnOnce() -> Option<usize>) -> bool {
+		if len >= self.max_batch_len {
+			return true;
+		}
+		// Not worth looking at the queue below the smallest adaptive batch
+		if !self.adaptive || len < (self.max_batch_len >> 8).max(1) {
+			return false;
+		}
+		match queue_len() {
+			Some(queue_len) => len >= self.max_batch_len >> 8usize.saturating_sub(queue_len),
+			None => false,
+		}
+	}
+}
 
 pub struct MessageBatching {
 	pub(crate) system: Arc<System>,
-	pub batch_len: usize,
+	policy: BatchingPolicy,
 	shard_message_batches: Vec<Vec<ShardMessage>>,
 	driver_message_batches: Vec<Vec<DriverMessage>>,
+	/// When the oldest message that wasn't sent yet was batched
+	oldest_pending: Option<Instant>,
+	/// Parked while no message is pending, woken up by the first one
+	flusher: Option<Thread>,
+	/// When batching for a shard, its mutations, sent to its replicas before
+	/// any batch so that no message leaves ahead of the mutations it follows
+	/// from, see `Replication`
+	replication_log: Option<ReplicationLog>,
 }
 
 impl MessageBatching {
@@ -14,46 +96,127 @@ impl MessageBatching {
 		MessageBatching {
 			shard_message_batches: (0..system.n_shards()).map(|_| Vec::new()).collect(),
 			driver_message_batches: (0..system.n_drivers()).map(|_| Vec::new()).collect(),
-			batch_len: 50_000,
+			policy: BatchingPolicy::default(),
+			oldest_pending: None,
+			flusher: None,
+			replication_log: None,
 			system,
 		}
 	}
 
+	/// Batches the messages of a shard, whose mutations go to `replicas`
+	pub(crate) fn for_shard(system: Arc<System>, shard_id: usize, replicas: Vec<u16>) -> Self {
+		let mut batching = Self::new(system);
+		batching.replication_log = Some(ReplicationLog::new(shard_id, replicas));
+		batching
+	}
+
+	pub(crate) fn log_mutation(&mut self, mutation: Mutation) {
+		if let Some(replication_log) = &mut self.replication_log {
+			replication_log.push(mutation);
+		}
+	}
+
+	/// Called before any batch is sent
+	fn send_replication_log(&mut self) {
+		if let Some(replication_log) = &mut self.replication_log {
+			replication_log.send(&self.system);
+		}
+	}
+
+	pub(crate) fn set_policy(&mut self, policy: BatchingPolicy) {
+		self.policy = policy;
+	}
+
+	pub(crate) fn set_flusher(&mut self, flusher: Thread) {
+		self.flusher = Some(flusher);
+	}
+
+	fn batched(&mut self) {
+		if self.oldest_pending.is_none() && self.policy.max_delay.is_some() {
+			self.oldest_pending = Some(Instant::now());
+			if let Some(flusher) = &self.flusher {
+				flusher.unpark();
+			}
+		}
+	}
+
 	pub(crate) fn send_to_shard(&mut self, message: ShardMessage) {
+		self.batched();
 		let target_shard = message.target_shard();
 		let batch = &mut self.shard_message_batches[target_shard];
+		if batch.capacity() == 0 {
+			// Only the batches being filled hold memory
+			*batch = self.system.shard_batches().take();
+		}
 		batch.push(message);
-		if batch.len() > self.batch_len {
-			self.system
-				.shard(target_shard)
-				.send_messages(std::mem::replace(batch, Vec::new()));
+		if self
+			.policy
+			.is_full(batch.len(), || self.system.shard(target_shard).queue_len())
+		{
+			let batch = std::mem::take(batch);
+			self.send_replication_log();
+			self.system.metrics().shard_batch_sent(batch.len());
+			self.system.shard(target_shard).send_messages(batch);
 		}
 	}
 
 	pub(crate) fn send_to_driver(&mut self, message: DriverMessage) {
-		let target_driver = message.target_driver();
+		let Some(target_driver) = message.target_driver() else {
+			self.send_replication_log();
+			for driver_id in 0..self.system.n_drivers() {
+				self.system
+					.driver(driver_id)
+					.send_messages(vec![message.clone()]);
+			}
+			return;
+		};
+		self.batched();
 		let batch = &mut self.driver_message_batches[target_driver];
 		batch.push(message);
-		if batch.len() > self.batch_len {
-			self.system
-				.driver(target_driver)
-				.send_messages(std::mem::replace(batch, Vec::new()));
+		let driver = self.system.driver(target_driver);
+		if self.policy.is_full(batch.len(), || driver.queue_len()) {
+			let batch = std::mem::take(batch);
+			self.send_replication_log();
+			self.system.metrics().driver_batch_sent(batch.len());
+			driver.send_messages(batch);
+		}
+	}
+
+	/// Whether messages have been waiting for longer than the maximum delay
+	pub(crate) fn is_late(&self) -> bool {
+		match (self.oldest_pending, self.policy.max_delay) {
+			(Some(oldest_pending), Some(max_delay)) => oldest_pending.elapsed() >= max_delay,
+			_ => false,
+		}
+	}
+
+	/// Flushes the late messages, and returns when the pending ones will be
+	/// late, `None` if no message is pending
+	pub(crate) fn flush_if_late(&mut self) -> Option<Instant> {
+		if self.is_late() {
+			self.flush();
 		}
+		Some(self.oldest_pending? + self.policy.max_delay?)
 	}
 
 	pub fn flush(&mut self) {
+		self.oldest_pending = None;
+		self.send_replication_log();
 		for (target_shard, batch) in self.shard_message_batches.iter_mut().enumerate() {
 			if !batch.is_empty() {
+				self.system.metrics().shard_batch_sent(batch.len());
 				self.system
 					.shard(target_shard)
-					.send_messages(std::mem::replace(batch, Vec::new()));
+					.send_messages(std::mem::take(batch));
 			}
 		}
 		for (target_driver, batch) in self.driver_message_batches.iter_mut().enumerate() {
 			if !batch.is_empty() {
+				self.system.metrics().driver_batch_sent(batch.len());
 				self.system
 					.driver(target_driver)
-					.send_messages(std::mem::replace(batch, Vec::new()));
+					.send_messages(std::mem::take(batch));
 			}
 		}
 	}
@@ -61,6 +224,69 @@ impl MessageBatching {
 
 impl Drop for MessageBatching {
 	fn drop(&mut self) {
-		self.flush()
+		self.flush();
+		// Lets it see that the driver is gone
+		if let Some(flusher) = &self.flusher {
+			flusher.unpark();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::ram::RamStorage;
+
+	#[test]
+	fn full_batches() {
+		let policy = BatchingPolicy::default()
+			.with_max_batch_len(1024)
+			.with_adaptive(false);
+		assert!(!policy.is_full(1023, || Some(0)));
+		assert!(policy.is_full(1024, || unreachable!()));
+	}
+
+	#[test]
+	fn adaptive_batches() {
+		let policy = BatchingPolicy::default().with_max_batch_len(1024);
+		// The queue is only looked at from 1/256th of the maximum
+		assert!(!policy.is_full(3, || unreachable!()));
+		assert!(policy.is_full(4, || Some(0)));
+		assert!(!policy.is_full(4, || Some(1)));
+		assert!(policy.is_full(8, || Some(1)));
+		assert!(!policy.is_full(1023, || Some(8)));
+		// Remote receivers only get full batches
+		assert!(!policy.is_full(1023, || None));
+	}
+
+	/// The flusher sends the batches that waited the maximum delay, and only
+	/// `flush` sends them without one
+	#[test]
+	fn late_batches() {
+		let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 1, 1);
+		let driver = &mut drivers[0];
+		let timeout = Duration::from_secs(10);
+		driver.set_batching_policy(
+			BatchingPolicy::default().with_max_delay(Some(Duration::from_millis(10))),
+		);
+		driver.add_node(0, 0);
+		assert!(driver.receiver().recv_timeout(timeout).is_ok());
+
+		driver.set_batching_policy(BatchingPolicy::default().with_max_delay(None));
+		driver.add_node(1, 0);
+		assert!(driver
+			.receiver()
+			.recv_timeout(Duration::from_millis(100))
+			.is_err());
+		driver.flush();
+		assert!(driver.receiver().recv_timeout(timeout).is_ok());
+
+		drivers
+			.pop()
+			.unwrap()
+			.shutdown_all_and_wait_for_completion();
+		for shard in shards {
+			shard.join().unwrap();
+		}
 	}
 }
@@ -0,0 +1,453 @@
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	sync::{
+		atomic::{AtomicU64, AtomicU8, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use crate::prelude::*;
+
+/// Histogram buckets are powers of two, up to 2^(N_BUCKETS - 1)
+const N_BUCKETS: usize = 40;
+
+/// Counts the values below each power of two
+pub(crate) struct Histogram {
+	buckets: [AtomicU64; N_BUCKETS],
+	sum: AtomicU64,
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Self {
+			buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+			sum: AtomicU64::new(0),
+		}
+	}
+}
+
+/// Bucket `i` holds the values below 2^i
+fn bucket(value: u64) -> usize {
+	((u64::BITS - value.leading_zeros()) as usize).min(N_BUCKETS - 1)
+}
+
+impl Histogram {
+	pub fn observe(&self, value: u64) {
+		self.buckets[bucket(value)].fetch_add(1, Ordering::Relaxed);
+		self.sum.fetch_add(value, Ordering::Relaxed);
+	}
+
+	fn add(&self, local: &LocalHistogram) {
+		for (bucket, &count) in self.buckets.iter().zip(&local.buckets) {
+			if count > 0 {
+				bucket.fetch_add(count, Ordering::Relaxed);
+			}
+		}
+		self.sum.fetch_add(local.sum, Ordering::Relaxed);
+	}
+
+	/// Buckets past the biggest value observed are left out, the values are
+	/// divided by `unit` to get the unit of the metric
+	fn render(&self, out: &mut String, name: &str, labels: &str, unit: f64) {
+		let counts: Vec<u64> = self
+			.buckets
+			.iter()
+			.map(|bucket| bucket.load(Ordering::Relaxed))
+			.collect();
+		let used = counts
+			.iter()
+			.rposition(|&count| count > 0)
+			.map_or(0, |i| i + 1);
+		let separator = if labels.is_empty() { "" } else { "," };
+		let sum_labels = if labels.is_empty() {
+			String::new()
+		} else {
+			format!("{{{labels}}}")
+		};
+		let mut cumulated = 0;
+		for (i, count) in counts[..used].iter().enumerate() {
+			cumulated += count;
+			let bound = ((1u64 << i) - 1) as f64 / unit;
+			let _ = writeln!(
+				out,
+				"{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulated}"
+			);
+		}
+		let count: u64 = counts.iter().sum();
+		let _ = writeln!(
+			out,
+			"{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {count}"
+		);
+		let sum = self.sum.load(Ordering::Relaxed) as f64 / unit;
+		let _ = writeln!(out, "{name}_sum{sum_labels} {sum}");
+		let _ = writeln!(out, "{name}_count{sum_labels} {count}");
+	}
+}
+
+/// A `Histogram` filled by a single thread, added to a shared one from time to
+/// time
+pub(crate) struct LocalHistogram {
+	buckets: [u64; N_BUCKETS],
+	sum: u64,
+}
+
+impl Default for LocalHistogram {
+	fn default() -> Self {
+		Self {
+			buckets: [0; N_BUCKETS],
+			sum: 0,
+		}
+	}
+}
+
+impl LocalHistogram {
+	pub fn observe(&mut self, value: u64) {
+		self.buckets[bucket(value)] += 1;
+		self.sum += value;
+	}
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+/// The `Storage` operations whose latency is measured
+#[derive(Clone, Copy)]
+pub(crate) enum StorageOp {
+	AddNode,
+	GetParent,
+	SetParent,
+	SwapChild,
+	SetSibling,
+	GetSize,
+	SetSize,
+}
+
+impl StorageOp {
+	const NAMES: [&'static str; 7] = [
+		"add_node",
+		"get_parent",
+		"set_parent",
+		"swap_child",
+		"set_sibling",
+		"get_size",
+		"set_size",
+	];
+}
+
+/// One storage operation out of that many is timed, as reading the clock costs
+/// about as much as an operation on `RamStorage`
+pub(crate) const STORAGE_SAMPLE_PERIOD: u64 = 64;
+
+/// What a shard thread counted since it last flushed, added to the metrics of
+/// the system on every flush so that shards don't contend on every message
+#[derive(Default)]
+pub(crate) struct ShardCounters {
+	pub messages: [u64; ShardMessage::KINDS.len()],
+	/// Messages processed by each find and union that ended here, over all
+	/// the shards they went through
+	pub find_hops: LocalHistogram,
+	pub union_hops: LocalHistogram,
+}
+
+/// Where the link to a peer stands
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PeerState {
+	Connected,
+	/// The connection was lost, the link tries to reconnect
+	Reconnecting,
+	/// The peer left the system, see `System::remove_worker`
+	Left,
+	/// The peer couldn't be reached again, replicas took over its shards if any
+	Lost,
+}
+
+impl PeerState {
+	const ALL: [PeerState; 4] = [
+		PeerState::Connected,
+		PeerState::Reconnecting,
+		PeerState::Left,
+		PeerState::Lost,
+	];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			PeerState::Connected => "connected",
+			PeerState::Reconnecting => "reconnecting",
+			PeerState::Left => "left",
+			PeerState::Lost => "lost",
+		}
+	}
+}
+
+/// Bytes exchanged with a peer, messages it didn't acknowledge yet and the
+/// state of the link
+#[derive(Default)]
+pub(crate) struct LinkMetrics {
+	pub sent_bytes: AtomicU64,
+	pub received_bytes: AtomicU64,
+	pub unacked: AtomicU64,
+	/// Messages of the peer dropped as invalid
+	pub refused: AtomicU64,
+	/// A `PeerState`
+	state: AtomicU8,
+}
+
+impl LinkMetrics {
+	pub fn state(&self) -> PeerState {
+		PeerState::ALL[self.state.load(Ordering::Relaxed) as usize]
+	}
+
+	pub fn set_state(&self, state: PeerState) {
+		self.state.store(state as u8, Ordering::Relaxed);
+	}
+}
+
+/// What the system did since it started, exposed in the Prometheus text format
+#[derive(Default)]
+pub(crate) struct Metrics {
+	shard_messages: [AtomicU64; ShardMessage::KINDS.len()],
+	find_hops: Histogram,
+	union_hops: Histogram,
+	shard_batch_len: Histogram,
+	driver_batch_len: Histogram,
+	/// In nanoseconds, by `StorageOp`
+	storage_latency: [Histogram; StorageOp::NAMES.len()],
+	links: Mutex<BTreeMap<u16, Arc<LinkMetrics>>>,
+	/// By shard id, updated by the shards on every flush
+	nodes: Mutex<BTreeMap<usize, u64>>,
+}
+
+impl Metrics {
+	pub fn publish(&self, counters: &mut ShardCounters) {
+		let counters = std::mem::take(counters);
+		for (total, count) in self.shard_messages.iter().zip(counters.messages) {
+			if count > 0 {
+				total.fetch_add(count, Ordering::Relaxed);
+			}
+		}
+		self.find_hops.add(&counters.find_hops);
+		self.union_hops.add(&counters.union_hops);
+	}
+
+	pub fn shard_batch_sent(&self, len: usize) {
+		self.shard_batch_len.observe(len as u64);
+	}
+
+	pub fn driver_batch_sent(&self, len: usize) {
+		self.driver_batch_len.observe(len as u64);
+	}
+
+	pub fn storage_op(&self, op: StorageOp, latency: Duration) {
+		self.storage_latency[op as usize].observe(latency.as_nanos() as u64);
+	}
+
+	/// Kept across reconnections, so that the counters keep growing
+	pub fn link(&self, peer: u16) -> Arc<LinkMetrics> {
+		self.links.lock().unwrap().entry(peer).or_default().clone()
+	}
+
+	pub fn links(&self) -> Vec<(u16, Arc<LinkMetrics>)> {
+		let links = self.links.lock().unwrap();
+		links
+			.iter()
+			.map(|(&peer, link)| (peer, link.clone()))
+			.collect()
+	}
+
+	pub fn set_nodes(&self, shard_id: usize, n_nodes: u64) {
+		self.nodes.lock().unwrap().insert(shard_id, n_nodes);
+	}
+
+	/// As last published by the shard, if it ever was served here
+	pub fn nodes(&self, shard_id: usize) -> Option<u64> {
+		self.nodes.lock().unwrap().get(&shard_id).copied()
+	}
+
+	pub fn render(&self, system: &System) -> String {
+		let mut out = String::new();
+
+		header(
+			&mut out,
+			"big_uf_shard_messages_total",
+			"counter",
+			"Messages processed by the shards of this system",
+		);
+		for (count, kind) in self.shard_messages.iter().zip(ShardMessage::KINDS) {
+			let count = count.load(Ordering::Relaxed);
+			let _ = writeln!(
+				out,
+				"big_uf_shard_messages_total{{kind=\"{kind}\"}} {count}"
+			);
+		}
+
+		// Each request is counted by the system where it ends, so the histograms
+		// of the systems add up to the cluster's
+		for (name, request, hops) in [
+			("big_uf_find_hops", "find", &self.find_hops),
+			("big_uf_union_hops", "union", &self.union_hops),
+		] {
+			header(
+				&mut out,
+				name,
+				"histogram",
+				&format!("Messages processed by each {request} that ended on this system"),
+			);
+			hops.render(&mut out, name, "", 1.);
+		}
+
+		header(
+			&mut out,
+			"big_uf_batch_len",
+			"histogram",
+			"Messages in each batch sent by this system",
+		);
+		self.shard_batch_len
+			.render(&mut out, "big_uf_batch_len", "to=\"shard\"", 1.);
+		self.driver_batch_len
+			.render(&mut out, "big_uf_batch_len", "to=\"driver\"", 1.);
+
+		header(
+			&mut out,
+			"big_uf_storage_latency_seconds",
+			"histogram",
+			"Latency of a sample of the storage operations",
+		);
+		for (histogram, op) in self.storage_latency.iter().zip(StorageOp::NAMES) {
+			histogram.render(
+				&mut out,
+				"big_uf_storage_latency_seconds",
+				&format!("op=\"{op}\""),
+				1e9,
+			);
+		}
+
+		header(
+			&mut out,
+			"big_uf_nodes",
+			"gauge",
+			"Nodes of each shard served by this system",
+		);
+		for (&shard_id, n_nodes) in self.nodes.lock().unwrap().iter() {
+			if system.shard_owner(shard_id) == system.system_id() {
+				let _ = writeln!(out, "big_uf_nodes{{shard=\"{shard_id}\"}} {n_nodes}");
+			}
+		}
+
+		header(
+			&mut out,
+			"big_uf_queue_len",
+			"gauge",
+			"Batches waiting for the local shards and drivers",
+		);
+		for shard_id in 0..system.n_shards() {
+			if let Some(len) = system.shard(shard_id).queue_len() {
+				let _ = writeln!(out, "big_uf_queue_len{{shard=\"{shard_id}\"}} {len}");
+			}
+		}
+		for driver_id in 0..system.n_drivers() {
+			if let Some(len) = system.driver(driver_id).queue_len() {
+				let _ = writeln!(out, "big_uf_queue_len{{driver=\"{driver_id}\"}} {len}");
+			}
+		}
+
+		let links = self.links.lock().unwrap();
+		for (name, kind, help, value) in [
+			(
+				"big_uf_link_sent_bytes_total",
+				"counter",
+				"Bytes sent to each peer",
+				(|link| &link.sent_bytes) as fn(&LinkMetrics) -> &AtomicU64,
+			),
+			(
+				"big_uf_link_received_bytes_total",
+				"counter",
+				"Bytes received from each peer",
+				|link| &link.received_bytes,
+			),
+			(
+				"big_uf_link_unacked",
+				"gauge",
+				"Messages sent to each peer and not acknowledged yet",
+				|link| &link.unacked,
+			),
+			(
+				"big_uf_link_refused_total",
+				"counter",
+				"Messages of each peer dropped as invalid",
+				|link| &link.refused,
+			),
+		] {
+			header(&mut out, name, kind, help);
+			for (peer, link) in links.iter() {
+				let value = value(link).load(Ordering::Relaxed);
+				let _ = writeln!(out, "{name}{{peer=\"{peer}\"}} {value}");
+			}
+		}
+		header(
+			&mut out,
+			"big_uf_link_up",
+			"gauge",
+			"Whether the link to each peer is connected",
+		);
+		for (peer, link) in links.iter() {
+			let up = (link.state() == PeerState::Connected) as u8;
+			let _ = writeln!(out, "big_uf_link_up{{peer=\"{peer}\"}} {up}");
+		}
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::ram::RamStorage;
+
+	#[test]
+	fn histogram_buckets() {
+		let histogram = Histogram::default();
+		for value in [0, 1, 5, 6] {
+			histogram.observe(value);
+		}
+		let mut out = String::new();
+		histogram.render(&mut out, "big_uf_test", "op=\"a\"", 1.);
+		assert_eq!(
+			out,
+			"big_uf_test_bucket{op=\"a\",le=\"0\"} 1\n\
+			 big_uf_test_bucket{op=\"a\",le=\"1\"} 2\n\
+			 big_uf_test_bucket{op=\"a\",le=\"3\"} 2\n\
+			 big_uf_test_bucket{op=\"a\",le=\"7\"} 4\n\
+			 big_uf_test_bucket{op=\"a\",le=\"+Inf\"} 4\n\
+			 big_uf_test_sum{op=\"a\"} 12\n\
+			 big_uf_test_count{op=\"a\"} 4\n"
+		);
+	}
+
+	#[test]
+	fn hops_are_histograms() {
+		let (drivers, _shards) = System::local_shards(|_| RamStorage::default, 1, 1);
+		let system = drivers[0].system();
+		let mut counters = ShardCounters::default();
+		counters.find_hops.observe(1);
+		counters.find_hops.observe(3);
+		counters.union_hops.observe(2);
+		system.metrics().publish(&mut counters);
+
+		let out = system.render_metrics();
+		for line in [
+			"# TYPE big_uf_find_hops histogram",
+			"big_uf_find_hops_bucket{le=\"1\"} 1",
+			"big_uf_find_hops_bucket{le=\"3\"} 2",
+			"big_uf_find_hops_bucket{le=\"+Inf\"} 2",
+			"big_uf_find_hops_sum 4",
+			"big_uf_find_hops_count 2",
+			"big_uf_union_hops_bucket{le=\"3\"} 1",
+			"big_uf_union_hops_count 1",
+			"big_uf_shard_messages_total{kind=\"find\"} 0",
+		] {
+			assert!(out.lines().any(|l| l == line), "{line} missing from\n{out}");
+		}
+	}
+}
@@ -0,0 +1,383 @@
+use std::{
+	fs::File,
+	io::BufReader,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio_rustls::{
+	rustls::{
+		self,
+		pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+		server::WebPkiClientVerifier,
+		RootCertStore,
+	},
+	TlsAcceptor, TlsConnector,
+};
+
+use crate::{
+	address::Address,
+	network_link::DEFAULT_RECONNECT_TIMEOUT,
+	network_message::{features, Codec, Protocol, DEFAULT_MAX_FRAME_SIZE},
+	transport::{Listener, SocketTransport, Tls, Transport},
+};
+
+/// How the links between systems are set up, every system of a cluster must use
+/// the same settings
+///
+/// By default links are plain uncompressed TCP (or Unix sockets) and any peer
+/// that speaks the protocol is accepted.
+#[derive(Clone)]
+pub struct NetworkConfig {
+	transport: Arc<dyn Transport>,
+	tls: Option<Tls>,
+	cluster_token: Option<String>,
+	max_frame_size: u32,
+	reconnect_timeout: Duration,
+	compression_level: Option<i32>,
+	shared_memory_dir: Option<PathBuf>,
+	metrics_endpoint: Option<Address>,
+	admin_endpoint: Option<Address>,
+	checkpoint_dir: Option<PathBuf>,
+	replication_factor: u16,
+}
+
+impl Default for NetworkConfig {
+	fn default() -> Self {
+		Self {
+			transport: Arc::new(SocketTransport),
+			tls: None,
+			cluster_token: None,
+			max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+			reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+			compression_level: None,
+			shared_memory_dir: None,
+			metrics_endpoint: None,
+			admin_endpoint: None,
+			checkpoint_dir: None,
+			replication_factor: 0,
+		}
+	}
+}
+
+impl NetworkConfig {
+	/// Carries the links over `transport` instead of TCP and Unix sockets
+	pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+		self.transport = Arc::new(transport);
+		self
+	}
+
+	/// Encrypts the links with TLS, whatever their transport, and only accepts
+	/// peers presenting a certificate signed by one of the `ca_certs`
+	///
+	/// Each system presents `cert_chain` both when it dials and when it is
+	/// dialed, so the certificate must be valid for `server_name`, the name the
+	/// whole cluster is reached as. Files are in PEM format.
+	pub fn with_tls(
+		mut self,
+		cert_chain: impl AsRef<Path>,
+		private_key: impl AsRef<Path>,
+		ca_certs: impl AsRef<Path>,
+		server_name: &str,
+	) -> Result<Self> {
+		let cert_chain = read_certs(cert_chain.as_ref())?;
+		let private_key = read_private_key(private_key.as_ref())?;
+		let mut roots = RootCertStore::empty();
+		for cert in read_certs(ca_certs.as_ref())? {
+			roots.add(cert)?;
+		}
+		let roots = Arc::new(roots);
+		let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+		let client_config = rustls::ClientConfig::builder_with_provider(provider.clone())
+			.with_safe_default_protocol_versions()?
+			.with_root_certificates(roots.clone())
+			.with_client_auth_cert(cert_chain.clone(), private_key.clone_key())?;
+		let client_verifier =
+			WebPkiClientVerifier::builder_with_provider(roots, provider.clone()).build()?;
+		let server_config = rustls::ServerConfig::builder_with_provider(provider)
+			.with_safe_default_protocol_versions()?
+			.with_client_cert_verifier(client_verifier)
+			.with_single_cert(cert_chain, private_key)?;
+
+		self.tls = Some(Tls {
+			connector: TlsConnector::from(Arc::new(client_config)),
+			acceptor: TlsAcceptor::from(Arc::new(server_config)),
+			server_name: ServerName::try_from(server_name.to_owned())?,
+		});
+		Ok(self)
+	}
+
+	/// Peers must present the same token when they connect
+	///
+	/// Without TLS the token is sent in clear.
+	pub fn with_cluster_token(mut self, token: impl Into<String>) -> Self {
+		self.cluster_token = Some(token.into());
+		self
+	}
+
+	/// Peers sending bigger messages are disconnected, 256 MiB by default
+	///
+	/// Moving a shard sends all its nodes in one message, so it must fit.
+	pub fn with_max_frame_size(mut self, bytes: u32) -> Self {
+		self.max_frame_size = bytes;
+		self
+	}
+
+	/// How long a lost link is retried before its peer is declared dead, 10 s by
+	/// default
+	///
+	/// A restarted worker rejoins the system only if it's back within that
+	/// time. After it, replicas take over its shards, or the system fails.
+	pub fn with_reconnect_timeout(mut self, reconnect_timeout: Duration) -> Self {
+		self.reconnect_timeout = reconnect_timeout;
+		self
+	}
+
+	/// Compresses the bigger frames with zstd at that level, on the links to
+	/// peers that compress too
+	///
+	/// Worth it when the network is slower than the CPUs, 1 to 3 is usually
+	/// enough.
+	pub fn with_compression(mut self, level: i32) -> Self {
+		self.compression_level = Some(level);
+		self
+	}
+
+	/// Sends the shard and driver batches through shared memory rings, created
+	/// in `dir`, to the peers on the same host that use the same directory
+	///
+	/// `dir` should be on a memory file system such as `/dev/shm`. The links
+	/// still carry the rest of the messages and detect failures, but batches in
+	/// the rings aren't resent when a peer restarts.
+	pub fn with_shared_memory(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.shared_memory_dir = Some(dir.into());
+		self
+	}
+
+	/// Serves the metrics of the system in the Prometheus text format at
+	/// `/metrics`, over plain HTTP: a port on localhost, an `(IpAddr, u16)` or
+	/// an `Address`
+	pub fn with_metrics_endpoint(mut self, listen: impl Into<Address>) -> Self {
+		self.metrics_endpoint = Some(listen.into());
+		self
+	}
+
+	/// Serves the admin API of the system over plain HTTP: a port on localhost,
+	/// a loopback `(IpAddr, u16)` or an `Address`
+	///
+	/// - `GET /status`: the local shards with their nodes and queues, the peers
+	///   and the uptime, as JSON
+	/// - `POST /flush`: flushes the local shards and their storages
+	/// - `POST /checkpoint?dir=...`: checkpoints the storage of every local shard
+	///   to `dir/shard_{id}`, `dir` being relative to `with_checkpoint_dir`
+	/// - `POST /shutdown`: a worker that doesn't serve shards anymore leaves the
+	///   system, as with `System::remove_worker`
+	///
+	/// Requests must carry the cluster token in an `Authorization: Bearer`
+	/// header, so the system doesn't start without `with_cluster_token`. As the
+	/// token travels in clear, it also refuses to listen on other addresses than
+	/// loopback ones and Unix sockets.
+	pub fn with_admin_endpoint(mut self, listen: impl Into<Address>) -> Self {
+		self.admin_endpoint = Some(listen.into());
+		self
+	}
+
+	/// The directory that the checkpoints of the admin API are written into,
+	/// without it they are refused
+	pub fn with_checkpoint_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.checkpoint_dir = Some(dir.into());
+		self
+	}
+
+	/// Copies the shards of each worker on that many other systems, so that
+	/// the loss of one worker is survived, 0 by default
+	///
+	/// Only read by the master, which tells the workers. The copies are
+	/// streamed asynchronously: a union acknowledged just before its worker
+	/// dies may be missing from the replica that takes over.
+	pub fn with_replication_factor(mut self, replication_factor: u16) -> Self {
+		self.replication_factor = replication_factor;
+		self
+	}
+
+	/// Configures TLS when `BIG_UF_TLS_CERT`, `BIG_UF_TLS_KEY`, `BIG_UF_TLS_CA`
+	/// and `BIG_UF_TLS_SERVER_NAME` are set, the cluster token from
+	/// `BIG_UF_CLUSTER_TOKEN`, the maximum frame size from
+	/// `BIG_UF_MAX_FRAME_SIZE`, the reconnection timeout in milliseconds from
+	/// `BIG_UF_RECONNECT_TIMEOUT_MS`, the zstd level from `BIG_UF_COMPRESSION`, the
+	/// shared memory directory from `BIG_UF_SHARED_MEMORY_DIR`, the metrics
+	/// endpoint from `BIG_UF_METRICS`, the admin endpoint from `BIG_UF_ADMIN`, its
+	/// checkpoint directory from `BIG_UF_CHECKPOINT_DIR` and the replication
+	/// factor from `BIG_UF_REPLICATION_FACTOR`
+	pub fn from_env() -> Result<Self> {
+		let mut config = Self::default();
+		if let Ok(cert_chain) = std::env::var("BIG_UF_TLS_CERT") {
+			let var = |name| std::env::var(name).with_context(|| format!("{name} is not set"));
+			config = config.with_tls(
+				cert_chain,
+				var("BIG_UF_TLS_KEY")?,
+				var("BIG_UF_TLS_CA")?,
+				&var("BIG_UF_TLS_SERVER_NAME")?,
+			)?;
+		}
+		if let Ok(token) = std::env::var("BIG_UF_CLUSTER_TOKEN") {
+			config = config.with_cluster_token(token);
+		}
+		if let Ok(bytes) = std::env::var("BIG_UF_MAX_FRAME_SIZE") {
+			config = config.with_max_frame_size(
+				bytes
+					.parse()
+					.context("BIG_UF_MAX_FRAME_SIZE should be a number of bytes")?,
+			);
+		}
+		if let Ok(millis) = std::env::var("BIG_UF_RECONNECT_TIMEOUT_MS") {
+			config = config.with_reconnect_timeout(Duration::from_millis(
+				millis
+					.parse()
+					.context("BIG_UF_RECONNECT_TIMEOUT_MS should be a number of milliseconds")?,
+			));
+		}
+		if let Ok(level) = std::env::var("BIG_UF_COMPRESSION") {
+			config = config.with_compression(
+				level
+					.parse()
+					.context("BIG_UF_COMPRESSION should be a zstd level")?,
+			);
+		}
+		if let Ok(dir) = std::env::var("BIG_UF_SHARED_MEMORY_DIR") {
+			config = config.with_shared_memory(dir);
+		}
+		if let Ok(listen) = std::env::var("BIG_UF_METRICS") {
+			config = config.with_metrics_endpoint(
+				listen
+					.parse::<Address>()
+					.context("BIG_UF_METRICS should be a port, ip:port or unix:path")?,
+			);
+		}
+		if let Ok(listen) = std::env::var("BIG_UF_ADMIN") {
+			config = config.with_admin_endpoint(
+				listen
+					.parse::<Address>()
+					.context("BIG_UF_ADMIN should be a port, ip:port or unix:path")?,
+			);
+		}
+		if let Ok(dir) = std::env::var("BIG_UF_CHECKPOINT_DIR") {
+			config = config.with_checkpoint_dir(dir);
+		}
+		if let Ok(factor) = std::env::var("BIG_UF_REPLICATION_FACTOR") {
+			config = config.with_replication_factor(
+				factor
+					.parse()
+					.context("BIG_UF_REPLICATION_FACTOR should be a number of systems")?,
+			);
+		}
+		Ok(config)
+	}
+
+	pub(crate) fn transport(&self) -> Arc<dyn Transport> {
+		match &self.tls {
+			Some(tls) => tls.over(self.transport.clone()),
+			None => self.transport.clone(),
+		}
+	}
+
+	/// The transport without TLS, which `server_with_listener` adds itself
+	pub(crate) fn plain_transport(&self) -> &dyn Transport {
+		&*self.transport
+	}
+
+	/// Runs the TLS handshake, if any, on the connections of `listener`
+	pub(crate) fn secure(&self, listener: Box<dyn Listener>) -> Box<dyn Listener> {
+		match &self.tls {
+			Some(tls) => tls.listener(listener),
+			None => listener,
+		}
+	}
+
+	/// The features we offer in the handshake
+	pub(crate) fn features(&self) -> u64 {
+		let mut features = features::SUPPORTED;
+		if self.compression_level.is_none() {
+			features &= !features::COMPRESSION;
+		}
+		if self.shared_memory_dir.is_none() {
+			features &= !features::SHARED_MEMORY;
+		}
+		if !cfg!(feature = "tracing") {
+			features &= !features::TRACE_CONTEXT;
+		}
+		features
+	}
+
+	pub(crate) fn reconnect_timeout(&self) -> Duration {
+		self.reconnect_timeout
+	}
+
+	pub(crate) fn codec(&self, protocol: Protocol) -> Codec {
+		Codec::new(protocol, self.max_frame_size, self.compression_level)
+	}
+
+	/// Frames in shared memory aren't worth compressing
+	pub(crate) fn shared_memory_codec(&self, protocol: Protocol) -> Codec {
+		Codec::new(protocol, self.max_frame_size, None)
+	}
+
+	pub(crate) fn shared_memory_dir(&self) -> Option<&Path> {
+		self.shared_memory_dir.as_deref()
+	}
+
+	pub(crate) fn metrics_endpoint(&self) -> Option<&Address> {
+		self.metrics_endpoint.as_ref()
+	}
+
+	pub(crate) fn admin_endpoint(&self) -> Option<&Address> {
+		self.admin_endpoint.as_ref()
+	}
+
+	pub(crate) fn checkpoint_dir(&self) -> Option<&Path> {
+		self.checkpoint_dir.as_deref()
+	}
+
+	pub(crate) fn replication_factor(&self) -> u16 {
+		self.replication_factor
+	}
+
+	pub(crate) fn cluster_token(&self) -> Option<String> {
+		self.cluster_token.clone()
+	}
+
+	/// Compares in constant time, so that the token can't be guessed byte by
+	/// byte
+	pub(crate) fn accepts_token(&self, token: Option<&str>) -> bool {
+		match (self.cluster_token.as_deref(), token) {
+			(None, _) => true,
+			(Some(expected), Some(token)) => {
+				expected.len() == token.len()
+					&& expected
+						.bytes()
+						.zip(token.bytes())
+						.fold(0, |diff, (a, b)| diff | (a ^ b))
+						== 0
+			}
+			(Some(_), None) => false,
+		}
+	}
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+	let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+	let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+	if certs.is_empty() {
+		return Err(anyhow!("No certificate in {}", path.display()));
+	}
+	Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+	let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+	rustls_pemfile::private_key(&mut BufReader::new(file))?
+		.ok_or_else(|| anyhow!("No private key in {}", path.display()))
+}
@@ -0,0 +1,793 @@
+use std::{
+	collections::VecDeque,
+	sync::{atomic::Ordering, Arc, Mutex},
+	time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures::{
+	channel::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender},
+	stream::{FusedStream, SplitSink, SplitStream},
+	SinkExt, StreamExt,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+	address::Address,
+	health::{HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT},
+	metrics::{LinkMetrics, PeerState},
+	network_config::NetworkConfig,
+	network_message::{negotiate_protocol, Codec, NetworkMessage, Protocol},
+	prelude::*,
+	replication::Mutation,
+	trace,
+	transport::{Connection, Listener},
+};
+
+pub(crate) type Socket = Framed<Box<dyn Connection>, Codec>;
+
+/// How long we try to re-establish a lost connection before declaring the peer
+/// dead, unless configured otherwise with `NetworkConfig::with_reconnect_timeout`
+pub(crate) const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+/// From opening a connection to reading the first message of the peer, so that
+/// a peer that stalls can't hold a connection attempt forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Numbered messages we receive before acknowledging them without waiting for
+/// the next heartbeat, which bounds the messages the peer keeps to resend
+const ACK_EVERY: u64 = 16;
+
+/// Identifies a run of the process, so that peers notice when it restarted
+pub(crate) fn new_session() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_nanos() as u64
+}
+
+/// Connects to `peer`, introducing ourselves with `identity` (`Hello` or `Id`),
+/// and returns the socket with the session of the peer
+pub(crate) async fn dial(
+	addr: &Address,
+	identity: NetworkMessage,
+	peer: u16,
+	config: &NetworkConfig,
+) -> Result<(Socket, u64)> {
+	tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(addr, identity, peer, config))
+		.await
+		.map_err(|_| {
+			anyhow!(
+				"System {peer} at {addr} didn't answer the handshake within {HANDSHAKE_TIMEOUT:?}"
+			)
+		})?
+}
+
+async fn handshake(
+	addr: &Address,
+	identity: NetworkMessage,
+	peer: u16,
+	config: &NetworkConfig,
+) -> Result<(Socket, u64)> {
+	let mut stream = config.transport().connect(addr).await?;
+	let protocol = negotiate_protocol(&mut stream, config.features())
+		.await
+		.with_context(|| format!("Handshake with system {peer} at {addr} failed"))?;
+	let mut socket = Framed::new(stream, config.codec(protocol));
+	let n_shards = identity.n_shards();
+	socket.send(identity).await?;
+	match socket
+		.next()
+		.await
+		.ok_or_else(|| anyhow!("The stream was empty"))??
+	{
+		NetworkMessage::Id {
+			id,
+			session,
+			n_shards: peer_n_shards,
+			ref cluster_token,
+		} if id == peer => {
+			if !config.accepts_token(cluster_token.as_deref()) {
+				bail!("System {peer} at {addr} presented a wrong cluster token");
+			}
+			if n_shards != Some(peer_n_shards as usize) {
+				bail!(
+					"System {peer} at {addr} has {peer_n_shards} shards, we have {}",
+					n_shards.unwrap_or_default()
+				);
+			}
+			Ok((socket, session))
+		}
+		NetworkMessage::Id { id, .. } => bail!("Expected system {peer} at {addr}, got {id}"),
+		_ => bail!("A peer should answer with Id"),
+	}
+}
+
+/// Hands every new connection to `incoming` along with its first message
+///
+/// Connections that fail the TLS handshake, speak an incompatible protocol,
+/// don't present the cluster token or take longer than `HANDSHAKE_TIMEOUT` are
+/// dropped.
+pub(crate) async fn accept_connections(
+	mut listener: Box<dyn Listener>,
+	config: NetworkConfig,
+	incoming: UnboundedSender<(NetworkMessage, Socket)>,
+) -> Result<()> {
+	loop {
+		let stream = listener.accept().await?;
+		let incoming = incoming.clone();
+		let config = config.clone();
+		tokio::spawn(async move {
+			let handshake = async {
+				let mut stream = stream.await.ok()?;
+				let protocol = negotiate_protocol(&mut stream, config.features())
+					.await
+					.ok()?;
+				let mut socket = Framed::new(stream, config.codec(protocol));
+				let message = socket.next().await?.ok()?;
+				config
+					.accepts_token(message.cluster_token())
+					.then_some((message, socket))
+			};
+			if let Ok(Some(connection)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+				let _ = incoming.unbounded_send(connection);
+			}
+		});
+	}
+}
+
+/// How a lost connection to a peer is re-established
+pub(crate) enum Reconnect {
+	/// We dialed this peer in the first place, so we dial it again
+	///
+	/// The `Hello` of the master is built again from the current state of the
+	/// system at each attempt.
+	Dial {
+		addr: Address,
+		identity: NetworkMessage,
+	},
+	/// The peer dials us: the listener hands us its new connections with the
+	/// session of the peer, and we answer with our `identity`
+	Accept {
+		connections: UnboundedReceiver<(Socket, u64)>,
+		identity: NetworkMessage,
+	},
+}
+
+impl Reconnect {
+	async fn reconnect(&mut self, peer: u16, system: &System) -> Result<(Socket, u64)> {
+		match self {
+			Reconnect::Dial { addr, identity } => {
+				let mut backoff = MIN_RECONNECT_BACKOFF;
+				loop {
+					// A restarted worker must not serve the shards moved away from it
+					if let NetworkMessage::Hello { .. } = identity {
+						*identity = system.hello(peer);
+					}
+					if let Ok(connection) =
+						dial(addr, identity.clone(), peer, system.config()).await
+					{
+						return Ok(connection);
+					}
+					tokio::time::sleep(backoff).await;
+					backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+				}
+			}
+			Reconnect::Accept {
+				connections,
+				identity,
+			} => {
+				let (mut socket, peer_session) = connections
+					.next()
+					.await
+					.ok_or_else(|| anyhow!("The listener was closed"))?;
+				socket.send(identity.clone()).await?;
+				Ok((socket, peer_session))
+			}
+		}
+	}
+}
+
+struct LinkState {
+	next_seq: u64,
+	/// Messages sent but not acknowledged yet, resent after a reconnection
+	unacked: VecDeque<Arc<NetworkMessage>>,
+	last_received: u64,
+	/// Received since we last acknowledged
+	n_unacknowledged: u64,
+	metrics: Arc<LinkMetrics>,
+}
+
+impl LinkState {
+	fn heartbeat(&mut self) -> NetworkMessage {
+		self.n_unacknowledged = 0;
+		NetworkMessage::Heartbeat {
+			ack: self.last_received,
+		}
+	}
+
+	fn unacked_changed(&self) {
+		self.metrics
+			.unacked
+			.store(self.unacked.len() as u64, Ordering::Relaxed);
+	}
+}
+
+/// Forwards the messages between the local system and `peer`, reconnecting when
+/// the connection is lost
+///
+/// If the peer can't be reached again within the reconnection timeout of the
+/// `NetworkConfig` it is declared
+/// dead, which fails the link unless replicas can take over its shards. A link
+/// to a system that left the cluster ends without error. The messages of the
+/// peer that we refuse are dropped, see `accept`.
+pub(crate) async fn handle_network_forwarding(
+	mut receiver: UnboundedReceiver<NetworkMessage>,
+	(mut socket, mut peer_session): (Socket, u64),
+	mut reconnect: Reconnect,
+	system: Arc<System>,
+	peer: u16,
+) -> Result<()> {
+	let metrics = system.metrics().link(peer);
+	let state = Mutex::new(LinkState {
+		next_seq: 1,
+		unacked: VecDeque::new(),
+		last_received: 0,
+		n_unacknowledged: 0,
+		metrics: metrics.clone(),
+	});
+	// Asks the sending half of the link to acknowledge what we received
+	let (ack_sender, mut ack_requests) = futures::channel::mpsc::channel(0);
+	system.offer_shared_memory(peer, socket.codec().protocol());
+	loop {
+		metrics.set_state(PeerState::Connected);
+		let err = forward_network_messages(
+			&mut receiver,
+			socket,
+			&state,
+			(ack_sender.clone(), &mut ack_requests),
+			&system,
+			peer,
+		)
+		.await;
+		// The socket may close before the channel of a peer that left does
+		if receiver.is_terminated() || system.has_left(peer) {
+			return if system.has_left(peer) {
+				metrics.set_state(PeerState::Left);
+				Ok(())
+			} else {
+				metrics.set_state(PeerState::Lost);
+				Err(err)
+			};
+		}
+		metrics.set_state(PeerState::Reconnecting);
+		let reconnect_timeout = system.config().reconnect_timeout();
+		match tokio::time::timeout(reconnect_timeout, reconnect.reconnect(peer, &system)).await {
+			Ok(Ok((new_socket, session))) => {
+				socket = new_socket;
+				if session != peer_session {
+					// The peer lost what it was processing, and resending our
+					// messages could apply some of them twice
+					peer_session = session;
+					let mut state = state.lock().unwrap();
+					state.last_received = 0;
+					state.unacked.clear();
+					state.unacked_changed();
+					drop(state);
+					system.report_failure(SystemError::PeerRestarted { peer }, true);
+					// The rings of its previous run are gone
+					system.close_shared_memory(peer);
+					system.offer_shared_memory(peer, socket.codec().protocol());
+				}
+			}
+			_ if system.has_left(peer) => {
+				metrics.set_state(PeerState::Left);
+				return Ok(());
+			}
+			_ => {
+				metrics.set_state(PeerState::Lost);
+				let error = match err.downcast_ref::<SystemError>() {
+					Some(error) => error.clone(),
+					None => SystemError::PeerDisconnected {
+						peer,
+						reason: err.to_string(),
+					},
+				};
+				// Loading the copies would hold up the other links
+				let recovered = tokio::task::spawn_blocking({
+					let system = system.clone();
+					move || system.take_over_shards_of(peer)
+				})
+				.await
+				.unwrap_or(false);
+				system.report_failure(error, recovered);
+				// So that new workers don't try to connect to it, and that no one
+				// waits for room in its rings
+				system.remove_peer(peer);
+				return if recovered { Ok(()) } else { Err(err) };
+			}
+		}
+	}
+}
+
+/// Runs until the connection fails, and returns why
+async fn forward_network_messages(
+	receiver: &mut UnboundedReceiver<NetworkMessage>,
+	mut socket: Socket,
+	state: &Mutex<LinkState>,
+	(ack_sender, ack_requests): (Sender<()>, &mut Receiver<()>),
+	system: &Arc<System>,
+	peer: u16,
+) -> anyhow::Error {
+	socket
+		.codec_mut()
+		.count_bytes(state.lock().unwrap().metrics.clone());
+	let protocol = socket.codec().protocol();
+	let (sink, stream) = socket.split();
+	match futures::try_join!(
+		forward_to_remote(receiver, sink, state, ack_requests),
+		forward_from_remote(stream, protocol, state, ack_sender, system, peer)
+	) {
+		Ok(_) => unreachable!("forwarding only stops on errors"),
+		Err(err) => err,
+	}
+}
+
+async fn forward_to_remote(
+	receiver: &mut UnboundedReceiver<NetworkMessage>,
+	mut sink: SplitSink<Socket, Arc<NetworkMessage>>,
+	state: &Mutex<LinkState>,
+	ack_requests: &mut Receiver<()>,
+) -> Result<()> {
+	let unacked: Vec<_> = state.lock().unwrap().unacked.iter().cloned().collect();
+	for message in unacked {
+		sink.feed(message).await?;
+	}
+	sink.flush().await?;
+
+	let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+	loop {
+		let message = tokio::select! {
+			message = receiver.next() => {
+				let mut message = message.ok_or_else(|| anyhow!("The channel was closed"))?;
+				let mut state = state.lock().unwrap();
+				let numbered = message.set_seq(state.next_seq);
+				let message = Arc::new(message);
+				if numbered {
+					state.next_seq += 1;
+					state.unacked.push_back(message.clone());
+					state.unacked_changed();
+				}
+				message
+			}
+			_ = heartbeat.tick() => Arc::new(state.lock().unwrap().heartbeat()),
+			Some(()) = ack_requests.next() => Arc::new(state.lock().unwrap().heartbeat()),
+		};
+		sink.send(message).await?;
+	}
+}
+
+async fn forward_from_remote(
+	mut stream: SplitStream<Socket>,
+	protocol: Protocol,
+	state: &Mutex<LinkState>,
+	mut ack_sender: Sender<()>,
+	system: &Arc<System>,
+	peer: u16,
+) -> Result<()> {
+	loop {
+		let Some(m) = tokio::time::timeout(HEARTBEAT_TIMEOUT, stream.next())
+			.await
+			.map_err(|_| SystemError::HeartbeatTimeout { peer })?
+		else {
+			bail!("The socket was closed");
+		};
+		let m = m?;
+		let seq = m.seq();
+		if seq.is_some_and(|seq| seq <= state.lock().unwrap().last_received) {
+			// Already received before a reconnection
+			continue;
+		}
+		if let Err(error) = accept(m, protocol, state, system, peer).await {
+			state
+				.lock()
+				.unwrap()
+				.metrics
+				.refused
+				.fetch_add(1, Ordering::Relaxed);
+			trace::refused(peer, &error);
+		}
+		// Also when refused, as the peer would send it again after every
+		// reconnection
+		if let Some(seq) = seq {
+			let mut state = state.lock().unwrap();
+			state.last_received = seq;
+			state.n_unacknowledged += 1;
+			if state.n_unacknowledged >= ACK_EVERY {
+				// Already asked if full
+				let _ = ack_sender.try_send(());
+			}
+		}
+	}
+}
+
+/// Acts on a message received from `peer`, failing if it's invalid
+///
+/// A message we refuse is dropped, without failing the link or the system: it
+/// comes from a peer that routed it with shards or nodes we don't know, and
+/// only the requests it carries fail, see `deliver_batch`.
+async fn accept(
+	m: NetworkMessage,
+	protocol: Protocol,
+	state: &Mutex<LinkState>,
+	system: &Arc<System>,
+	peer: u16,
+) -> Result<()> {
+	// Indexing with what a peer sends would panic on a bad value
+	let n_shards = system.n_shards();
+	let check_shard = |shard_id: u16| -> Result<()> {
+		ensure!(
+			(shard_id as usize) < n_shards,
+			"System {peer} sent a message for unknown shard {shard_id}"
+		);
+		Ok(())
+	};
+	match m {
+		NetworkMessage::Hello { .. } => {
+			bail!("There should be no Hello messages at this point")
+		}
+		NetworkMessage::Id { .. } => {
+			bail!("There should be no Id messages at this point")
+		}
+		m @ (NetworkMessage::DriverMessages { .. } | NetworkMessage::ShardMessages { .. }) => {
+			deliver_batch(system, peer, m)?
+		}
+		NetworkMessage::Heartbeat { ack } => {
+			let mut state = state.lock().unwrap();
+			while state
+				.unacked
+				.front()
+				.is_some_and(|message| message.seq() <= Some(ack))
+			{
+				let message = state.unacked.pop_front().map(Arc::try_unwrap);
+				if let Some(Ok(NetworkMessage::ShardMessages { batch, .. })) = message {
+					system.shard_batches().recycle(batch);
+				}
+			}
+			state.unacked_changed();
+		}
+		NetworkMessage::ReplicaMutations {
+			seq: _,
+			shard_id,
+			batch,
+		} => {
+			check_shard(shard_id)?;
+			system.apply_replica_mutations(shard_id, batch)?
+		}
+		NetworkMessage::ShardMoved {
+			seq: _,
+			shard_id,
+			system_id,
+		} => {
+			check_shard(shard_id)?;
+			system.move_shard(shard_id, system_id)?
+		}
+		NetworkMessage::ReplicaSnapshot {
+			seq: _,
+			shard_id,
+			nodes,
+		} => {
+			check_shard(shard_id)?;
+			system.replace_replica(shard_id, nodes)?
+		}
+		NetworkMessage::ShardData {
+			seq: _,
+			shard_id,
+			nodes,
+			message_counters,
+			req_id,
+		} => {
+			check_shard(shard_id)?;
+			Mutation::check_all(&nodes, shard_id, n_shards, 0)?;
+			ensure!(
+				req_id.driver() < system.n_drivers(),
+				"System {peer} moved shard {shard_id} for unknown driver {}",
+				req_id.driver()
+			);
+			// Opening the storage and loading the nodes would hold up the runtime.
+			// The link waits, so that the messages after this one find the shard.
+			let installed = tokio::task::spawn_blocking({
+				let system = system.clone();
+				move || system.install_shard(shard_id, nodes, message_counters, req_id)
+			})
+			.await
+			.unwrap_or_else(|error| Err(error.into()));
+			if let Err(error) = installed {
+				system.send_to_peer(
+					peer,
+					NetworkMessage::ShardRefused {
+						seq: 0,
+						shard_id,
+						reason: format!("{error:#}"),
+					},
+				);
+			}
+		}
+		NetworkMessage::ShardRefused {
+			seq: _,
+			shard_id,
+			reason,
+		} => {
+			check_shard(shard_id)?;
+			system.migration_refused(shard_id, reason)
+		}
+		NetworkMessage::SystemLeft { seq: _, system_id } => system.system_left(peer, system_id),
+		NetworkMessage::SharedMemory {
+			seq: _,
+			path,
+			nonce,
+		} => system.accept_shared_memory(peer, &path, nonce, protocol),
+		NetworkMessage::SharedMemoryAccepted { seq: _, nonce } => {
+			system.start_shared_memory(peer, nonce)?
+		}
+	}
+	Ok(())
+}
+
+/// Hands a batch sent by `peer`, through its link or shared memory, to the
+/// shard or driver it's for
+///
+/// The messages that refer to shards or nodes we don't know are refused, and
+/// the requests waiting for them are answered with `RequestRefused`. The others
+/// are still delivered.
+pub(crate) fn deliver_batch(system: &System, peer: u16, message: NetworkMessage) -> Result<()> {
+	// Indexing with what a peer sends would panic on a bad value
+	let n_shards = system.n_shards();
+	match message {
+		NetworkMessage::DriverMessages {
+			seq: _,
+			driver_idx,
+			batch,
+		} => {
+			ensure!(
+				(driver_idx as usize) < system.n_drivers(),
+				"System {peer} sent a message for unknown driver {driver_idx}"
+			);
+			system.driver(driver_idx as usize).send_messages(batch)
+		}
+		NetworkMessage::ShardMessages {
+			seq: _,
+			shard_id,
+			mut batch,
+		} => {
+			let is_known = |message: &ShardMessage| {
+				(shard_id as usize) < n_shards
+					&& message.target_shard() == shard_id as usize
+					&& message.refers_to_existing(n_shards, |key| system.node_exists(key))
+			};
+			let mut refused = Vec::new();
+			if !batch.iter().all(is_known) {
+				let known;
+				(known, refused) = batch.into_iter().partition(is_known);
+				batch = known;
+			}
+			if !batch.is_empty() {
+				system.shard(shard_id as usize).send_messages(batch)
+			}
+			system.refuse(
+				&refused,
+				&format!("System {peer} sent it for an unknown shard or node"),
+			);
+			ensure!(
+				refused.is_empty(),
+				"System {peer} sent {} messages for unknown shards or nodes",
+				refused.len()
+			);
+		}
+		_ => bail!("System {peer} sent a batch of an unknown kind"),
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		storage::ram::RamStorage,
+		transport::{MemoryTransport, Transport},
+	};
+
+	/// What a peer sends about the shards we serve is checked against their nodes
+	#[test]
+	fn unknown_nodes_are_refused() {
+		let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 1, 2);
+		let mut driver = drivers.pop().unwrap();
+		driver.add_node(0, 1);
+		driver.flush();
+		let key = match driver.receiver().recv().unwrap()[..] {
+			[DriverMessage::AddNodeDone { response, .. }] => response,
+			ref other => panic!("Expected the new node, got {other:?}"),
+		};
+		let set_parent = |node| NetworkMessage::ShardMessages {
+			seq: 0,
+			shard_id: 1,
+			batch: vec![ShardMessage::SetParent {
+				node,
+				to: key,
+				req_id: ReqId::unknown(),
+			}],
+		};
+		let system = driver.system();
+		assert!(deliver_batch(system, 1, set_parent(Key::new(1, 1))).is_err());
+		let unused_bits = Key {
+			inner: key.inner | 1 << 40,
+		};
+		assert!(deliver_batch(system, 1, set_parent(unused_bits)).is_err());
+		assert!(deliver_batch(system, 1, set_parent(key)).is_ok());
+		// Only the requests of the refused messages fail
+		let find = |node, req_id| ShardMessage::Find {
+			node,
+			child: node,
+			req_id: ReqId::new(0, req_id),
+			hops: 0,
+		};
+		let finds = NetworkMessage::ShardMessages {
+			seq: 0,
+			shard_id: 1,
+			batch: vec![find(Key::new(1, 1), 1), find(key, 2)],
+		};
+		assert!(deliver_batch(system, 1, finds).is_err());
+		let mut answers = Vec::new();
+		while answers.len() < 2 {
+			answers.extend(driver.receiver().recv().unwrap());
+		}
+		assert!(answers.iter().any(|answer| matches!(
+			answer,
+			DriverMessage::RequestRefused { req_id, .. } if *req_id == ReqId::new(0, 1)
+		)));
+		assert!(answers.iter().any(|answer| matches!(
+			answer,
+			DriverMessage::FindDone { req_id, response } if *req_id == ReqId::new(0, 2) && *response == key
+		)));
+		let other_shard = vec![Mutation::SetSize { key, size: 2 }];
+		assert!(system.replace_replica(0, other_shard).is_err());
+
+		driver.shutdown_all_and_wait_for_completion();
+		for shard in shards {
+			shard.join().unwrap();
+		}
+	}
+
+	/// A refused message is dropped, and the link keeps working
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn refused_messages_are_dropped() {
+		let transport = MemoryTransport::default();
+		let config = NetworkConfig::default().with_transport(transport.clone());
+		let address = Address::from(1);
+		let listener = transport.listen(&address).await.unwrap();
+		let (incoming_sender, mut incoming) = futures::channel::mpsc::unbounded();
+		tokio::spawn(accept_connections(
+			listener,
+			config.clone(),
+			incoming_sender,
+		));
+		// A worker that sends a batch for a driver that doesn't exist, then an
+		// answer for ours
+		let worker = tokio::spawn(async move {
+			let mut sockets = Vec::new();
+			while let Some((hello, mut socket)) = incoming.next().await {
+				let id = NetworkMessage::Id {
+					id: 1,
+					session: 1,
+					n_shards: hello.n_shards().unwrap() as u32,
+					cluster_token: None,
+				};
+				let batch = |seq, driver_idx| NetworkMessage::DriverMessages {
+					seq,
+					driver_idx,
+					batch: vec![DriverMessage::UnionDone {
+						req_id: ReqId::new(0, 3),
+					}],
+				};
+				socket.send(id).await.unwrap();
+				socket.send(batch(1, 7)).await.unwrap();
+				socket.send(batch(2, 0)).await.unwrap();
+				sockets.push(socket);
+			}
+		});
+
+		let (driver, system, _shards, links) =
+			System::connect(1, vec![address], config).await.unwrap();
+		let links = tokio::spawn(links);
+		let received = tokio::task::block_in_place(|| {
+			driver
+				.receiver()
+				.recv_timeout(Duration::from_secs(20))
+				.expect("The answer never reached the driver")
+		});
+		assert!(matches!(
+			received[..],
+			[DriverMessage::UnionDone { req_id }] if req_id == ReqId::new(0, 3)
+		));
+		assert_eq!(system.health(), ClusterHealth::Healthy);
+		assert_eq!(system.metrics().link(1).refused.load(Ordering::Relaxed), 1);
+		links.abort();
+		worker.abort();
+	}
+
+	/// A peer that still routes a moving shard to its old owner may send it the
+	/// nodes that the new owner created, which the old owner forwards
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn nodes_created_after_a_move_are_forwarded() {
+		let transport = MemoryTransport::default();
+		let config = NetworkConfig::default().with_transport(transport.clone());
+		let address = Address::from(1);
+		let listener = transport.listen(&address).await.unwrap();
+		let (incoming_sender, mut incoming) = futures::channel::mpsc::unbounded();
+		tokio::spawn(accept_connections(
+			listener,
+			config.clone(),
+			incoming_sender,
+		));
+		let created = Key::new(0, 1);
+		// A worker that takes shard 0, creates a node in it and unites it with
+		// the first one as a stale peer would, through the master, before it
+		// announces the move
+		let worker = tokio::spawn(async move {
+			let (hello, mut socket) = incoming.next().await.unwrap();
+			let id = NetworkMessage::Id {
+				id: 1,
+				session: 1,
+				n_shards: hello.n_shards().unwrap() as u32,
+				cluster_token: None,
+			};
+			socket.send(id).await.unwrap();
+			while let Some(message) = socket.next().await {
+				match message.unwrap() {
+					NetworkMessage::ShardData { shard_id: 0, .. } => {
+						let union = NetworkMessage::ShardMessages {
+							seq: 1,
+							shard_id: 0,
+							batch: vec![ShardMessage::Union {
+								node: created,
+								to: Key::new(0, 0),
+								child: created,
+								req_id: ReqId::new(0, 2),
+								hops: 0,
+							}],
+						};
+						let moved = NetworkMessage::ShardMoved {
+							seq: 2,
+							shard_id: 0,
+							system_id: 1,
+						};
+						socket.send(union).await.unwrap();
+						socket.send(moved).await.unwrap();
+					}
+					NetworkMessage::ShardMessages {
+						shard_id: 0, batch, ..
+					} => return batch,
+					_ => {}
+				}
+			}
+			panic!("The master closed the link");
+		});
+
+		let (mut driver, system, _shards, links) =
+			System::connect(1, vec![address], config).await.unwrap();
+		let links = tokio::spawn(links);
+		driver.add_node(0, 0);
+		driver.move_shard(1, 0, 1);
+		driver.flush();
+		let forwarded = tokio::time::timeout(Duration::from_secs(20), worker)
+			.await
+			.expect("The union was never forwarded")
+			.unwrap();
+		assert!(matches!(
+			forwarded[..],
+			[ShardMessage::Union { node, .. }] if node == created
+		));
+		assert_eq!(system.metrics().link(1).refused.load(Ordering::Relaxed), 0);
+		links.abort();
+	}
+}
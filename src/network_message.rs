@@ -1,33 +1,447 @@
-use std::net::IpAddr;
+use std::{
+	io::Read,
+	sync::{atomic::Ordering, Arc},
+};
 
+use anyhow::{bail, ensure};
+use bincode::Options;
 use bytes::{Buf, BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{prelude::ShardMessage, DriverMessage};
+use crate::{
+	address::Address,
+	compact_batch,
+	metrics::LinkMetrics,
+	prelude::{ReqId, ShardMessage},
+	replication::{Mutation, Replication},
+	DriverMessage,
+};
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Handshake messages carry the session of the sender, which changes when its
+/// process restarts, and the cluster token it was configured with. The other
+/// messages that carry a `seq` are numbered by the link they are sent on, so
+/// that they can be resent after a reconnection.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub(crate) enum NetworkMessage {
 	Hello {
 		id: u16,
-		num_shard_per_system: u16,
-		connect_to: Vec<(IpAddr, u16)>,
+		session: u64,
+		/// System serving each shard
+		shard_owners: Vec<u16>,
+		replication: Option<Replication>,
+		/// The workers the new one connects to itself, by system id
+		connect_to: Vec<(u16, Address)>,
+		cluster_token: Option<String>,
 	},
 	Id {
 		id: u16,
+		session: u64,
+		/// Checked against the shard count of the receiver
+		n_shards: u32,
+		cluster_token: Option<String>,
 	},
 	DriverMessages {
+		seq: u64,
 		driver_idx: u16,
 		batch: Vec<DriverMessage>,
 	},
 	ShardMessages {
+		seq: u64,
 		shard_id: u16,
 		batch: Vec<ShardMessage>,
 	},
+	/// Sent on every link, see `HEARTBEAT_INTERVAL`
+	Heartbeat {
+		/// Also acknowledges all the messages up to this one
+		ack: u64,
+	},
+	/// Storage mutations of a shard we hold a replica of
+	ReplicaMutations {
+		seq: u64,
+		shard_id: u16,
+		batch: Vec<Mutation>,
+	},
+	/// The shard is now served by that system
+	ShardMoved {
+		seq: u64,
+		shard_id: u16,
+		system_id: u16,
+	},
+	/// Replaces our copy of the shard, sent when a system starts serving it
+	ReplicaSnapshot {
+		seq: u64,
+		shard_id: u16,
+		nodes: Vec<Mutation>,
+	},
+	/// The whole content of a shard migrating to the receiving system
+	ShardData {
+		seq: u64,
+		shard_id: u16,
+		nodes: Vec<Mutation>,
+		/// Shard to shard messages sent and received, so that barriers keep
+		/// adding up
+		message_counters: (u64, u64),
+		/// The `Driver::move_shard` request to answer once the shard is served
+		req_id: ReqId,
+	},
+	/// The system was removed from the cluster
+	SystemLeft {
+		seq: u64,
+		/// Links to it are closed
+		system_id: u16,
+	},
+	/// Shard and driver batches can be sent to us through the ring mapped from
+	/// that file, if it's on the same host
+	SharedMemory { seq: u64, path: String, nonce: u64 },
+	/// Answers `ShardData` when the shard couldn't be installed, so that its
+	/// system keeps serving it
+	ShardRefused {
+		seq: u64,
+		shard_id: u16,
+		reason: String,
+	},
+	/// Follows the last batch sent through the link instead of the ring offered
+	/// with that nonce
+	SharedMemoryAccepted { seq: u64, nonce: u64 },
+}
+
+impl NetworkMessage {
+	/// The shard count of the sender of a handshake message
+	pub fn n_shards(&self) -> Option<usize> {
+		match self {
+			NetworkMessage::Hello { shard_owners, .. } => Some(shard_owners.len()),
+			NetworkMessage::Id { n_shards, .. } => Some(*n_shards as usize),
+			_ => None,
+		}
+	}
+
+	/// The token presented by a handshake message
+	pub fn cluster_token(&self) -> Option<&str> {
+		match self {
+			NetworkMessage::Hello { cluster_token, .. }
+			| NetworkMessage::Id { cluster_token, .. } => cluster_token.as_deref(),
+			_ => None,
+		}
+	}
+
+	pub fn seq(&self) -> Option<u64> {
+		match *self {
+			NetworkMessage::DriverMessages { seq, .. }
+			| NetworkMessage::ShardMessages { seq, .. }
+			| NetworkMessage::ReplicaMutations { seq, .. }
+			| NetworkMessage::ShardMoved { seq, .. }
+			| NetworkMessage::ReplicaSnapshot { seq, .. }
+			| NetworkMessage::ShardData { seq, .. }
+			| NetworkMessage::SystemLeft { seq, .. }
+			| NetworkMessage::SharedMemory { seq, .. }
+			| NetworkMessage::ShardRefused { seq, .. }
+			| NetworkMessage::SharedMemoryAccepted { seq, .. } => Some(seq),
+			NetworkMessage::Hello { .. }
+			| NetworkMessage::Id { .. }
+			| NetworkMessage::Heartbeat { .. } => None,
+		}
+	}
+
+	/// Returns whether the message is numbered
+	pub fn set_seq(&mut self, new_seq: u64) -> bool {
+		match self {
+			NetworkMessage::DriverMessages { seq, .. }
+			| NetworkMessage::ShardMessages { seq, .. }
+			| NetworkMessage::ReplicaMutations { seq, .. }
+			| NetworkMessage::ShardMoved { seq, .. }
+			| NetworkMessage::ReplicaSnapshot { seq, .. }
+			| NetworkMessage::ShardData { seq, .. }
+			| NetworkMessage::SystemLeft { seq, .. }
+			| NetworkMessage::SharedMemory { seq, .. }
+			| NetworkMessage::ShardRefused { seq, .. }
+			| NetworkMessage::SharedMemoryAccepted { seq, .. } => {
+				*seq = new_seq;
+				true
+			}
+			NetworkMessage::Hello { .. }
+			| NetworkMessage::Id { .. }
+			| NetworkMessage::Heartbeat { .. } => false,
+		}
+	}
+}
+
+/// Opens every connection, in a layout that never changes so that peers
+/// running another build get a clear error instead of garbage
+const MAGIC: [u8; 4] = *b"BGUF";
+/// Bumped when a release changes `NetworkMessage`
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+/// The oldest version this build can still talk to, which allows rolling
+/// upgrades when a new version only adds features
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional capabilities, negotiated so that a cluster can run two builds while
+/// it is upgraded
+pub(crate) mod features {
+	/// Streams the shard mutations to replicas and takes over dead systems
+	pub const REPLICATION: u64 = 1 << 0;
+	/// Receives migrated shards and links to workers added at runtime
+	pub const ELASTIC: u64 = 1 << 1;
+	/// Compresses the bigger frames with zstd, only offered when configured
+	pub const COMPRESSION: u64 = 1 << 2;
+	/// Encodes the keys of shard batches as deltas, see `compact_batch`
+	pub const COMPACT_KEYS: u64 = 1 << 3;
+	/// Sends the batches to peers on the same host through shared memory, only
+	/// offered when configured. The rings are read once the peer sends
+	/// `SharedMemoryAccepted`.
+	pub const SHARED_MEMORY: u64 = 1 << 4;
+	/// Compact batches carry the request of the path compression messages too,
+	/// only offered with the `tracing` feature
+	pub const TRACE_CONTEXT: u64 = 1 << 5;
+
+	pub const SUPPORTED: u64 =
+		REPLICATION | ELASTIC | COMPRESSION | COMPACT_KEYS | SHARED_MEMORY | TRACE_CONTEXT;
 }
 
-#[derive(Default)]
-pub(crate) struct Codec {}
+/// What both ends of a connection agreed on
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Protocol {
+	features: u64,
+}
+
+impl Protocol {
+	pub fn supports(self, feature: u64) -> bool {
+		self.features & feature == feature
+	}
+}
+
+/// Both ends send their header then check the other's: magic, highest and
+/// lowest protocol versions they speak, and the features they offer. Features
+/// are used when both ends offer them.
+pub(crate) async fn negotiate_protocol(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	offered_features: u64,
+) -> anyhow::Result<Protocol> {
+	let mut header = [0u8; 16];
+	header[..4].copy_from_slice(&MAGIC);
+	header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+	header[6..8].copy_from_slice(&MIN_PROTOCOL_VERSION.to_le_bytes());
+	header[8..].copy_from_slice(&offered_features.to_le_bytes());
+	stream.write_all(&header).await?;
+	stream.flush().await?;
+
+	let mut peer_header = [0u8; 16];
+	stream.read_exact(&mut peer_header).await?;
+	if peer_header[..4] != MAGIC {
+		bail!("The peer doesn't speak the big_uf protocol");
+	}
+	let version = u16::from_le_bytes([peer_header[4], peer_header[5]]);
+	let min_version = u16::from_le_bytes([peer_header[6], peer_header[7]]);
+	if version < MIN_PROTOCOL_VERSION || min_version > PROTOCOL_VERSION {
+		bail!(
+			"Incompatible protocol versions: we speak {MIN_PROTOCOL_VERSION} to \
+			 {PROTOCOL_VERSION}, the peer speaks {min_version} to {version}"
+		);
+	}
+	let peer_features = u64::from_le_bytes(peer_header[8..].try_into().unwrap());
+	Ok(Protocol {
+		features: peer_features & offered_features & features::SUPPORTED,
+	})
+}
+
+/// Frames above this size are refused unless configured otherwise. It must fit
+/// the biggest batch, and the biggest shard moved with `Driver::move_shard`.
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: u32 = 256 << 20;
+/// We don't reserve more than that ahead of the bytes actually received, so
+/// that a forged length can't make us allocate much
+const MAX_RESERVE: usize = 1 << 20;
+
+/// The encoding of `bincode::serialize`, but bounded
+fn bincode_options(limit: u64) -> impl bincode::Options {
+	bincode::DefaultOptions::new()
+		.with_fixint_encoding()
+		.with_limit(limit)
+}
+
+/// Payloads smaller than that are sent as they are, compressing them isn't
+/// worth it
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Flags of a frame when `COMPRESSION` or `COMPACT_KEYS` was negotiated, they
+/// follow the length
+mod frame_flags {
+	/// The payload is compressed with zstd
+	pub const COMPRESSED: u8 = 1 << 0;
+	/// The payload is a `ShardMessages` in the `compact_batch` encoding rather
+	/// than bincode
+	pub const COMPACT: u8 = 1 << 1;
+}
+
+pub(crate) struct Codec {
+	protocol: Protocol,
+	max_frame_size: u32,
+	compression_level: Option<i32>,
+	/// Reused between frames to hold the payload before compression
+	buffer: Vec<u8>,
+	/// Where the bytes of the frames are counted, for the sockets of a link
+	metrics: Option<Arc<LinkMetrics>>,
+}
+
+impl Codec {
+	pub fn new(protocol: Protocol, max_frame_size: u32, compression_level: Option<i32>) -> Self {
+		Self {
+			protocol,
+			max_frame_size,
+			compression_level,
+			buffer: Vec::new(),
+			metrics: None,
+		}
+	}
+
+	pub fn count_bytes(&mut self, metrics: Arc<LinkMetrics>) {
+		self.metrics = Some(metrics);
+	}
+
+	pub fn protocol(&self) -> Protocol {
+		self.protocol
+	}
+
+	/// Frames carry flags after their length
+	fn flagged_frames(&self) -> bool {
+		self.protocol.supports(features::COMPRESSION)
+			|| self.protocol.supports(features::COMPACT_KEYS)
+	}
+
+	fn decode_payload(&self, flags: u8, payload: &[u8]) -> anyhow::Result<NetworkMessage> {
+		ensure!(
+			flags & !(frame_flags::COMPRESSED | frame_flags::COMPACT) == 0,
+			"Unknown frame flags {flags:#x}"
+		);
+		let decompressed;
+		let payload = if flags & frame_flags::COMPRESSED != 0 {
+			ensure!(
+				self.protocol.supports(features::COMPRESSION),
+				"Received a compressed frame but compression wasn't negotiated"
+			);
+			// Bounded, so that a small frame can't expand into gigabytes
+			let mut buffer = Vec::new();
+			zstd::stream::read::Decoder::new(payload)?
+				.take(self.max_frame_size as u64 + 1)
+				.read_to_end(&mut buffer)?;
+			ensure!(
+				buffer.len() <= self.max_frame_size as usize,
+				"A frame decompresses above the limit of {} bytes",
+				self.max_frame_size
+			);
+			decompressed = buffer;
+			&decompressed[..]
+		} else {
+			payload
+		};
+		if flags & frame_flags::COMPACT != 0 {
+			ensure!(
+				self.protocol.supports(features::COMPACT_KEYS),
+				"Received a compact batch but compact keys weren't negotiated"
+			);
+			let (seq, shard_id, batch) =
+				compact_batch::decode(payload, self.protocol.supports(features::TRACE_CONTEXT))?;
+			Ok(NetworkMessage::ShardMessages {
+				seq,
+				shard_id,
+				batch,
+			})
+		} else {
+			// Collections can't claim more elements than the payload could hold
+			Ok(bincode_options(payload.len() as u64).deserialize(payload)?)
+		}
+	}
+
+	/// Frames are `[length][flags][payload]`
+	fn encode_flagged(&mut self, item: &NetworkMessage, dst: &mut BytesMut) -> anyhow::Result<()> {
+		let mut flags = 0;
+		let mut payload = std::mem::take(&mut self.buffer);
+		payload.clear();
+		match item {
+			NetworkMessage::ShardMessages {
+				seq,
+				shard_id,
+				batch,
+			} if self.protocol.supports(features::COMPACT_KEYS) => {
+				flags |= frame_flags::COMPACT;
+				compact_batch::encode(
+					*seq,
+					*shard_id,
+					batch,
+					self.protocol.supports(features::TRACE_CONTEXT),
+					&mut payload,
+				);
+			}
+			_ => bincode_options(u64::MAX).serialize_into(&mut payload, item)?,
+		}
+		let result = self.write_frame(flags, &payload, dst);
+		self.buffer = payload;
+		result
+	}
+
+	/// Compresses the payload if that was negotiated and makes it smaller
+	fn write_frame(&self, mut flags: u8, payload: &[u8], dst: &mut BytesMut) -> anyhow::Result<()> {
+		// The receiver refuses to decompress above the limit
+		self.check_frame_size(payload.len() as u64 + 1)?;
+		let compressed = match self.compression_level {
+			Some(level)
+				if self.protocol.supports(features::COMPRESSION)
+					&& payload.len() >= COMPRESSION_THRESHOLD =>
+			{
+				Some(zstd::bulk::compress(payload, level)?)
+					.filter(|compressed| compressed.len() < payload.len())
+			}
+			_ => None,
+		};
+		let payload = match &compressed {
+			Some(compressed) => {
+				flags |= frame_flags::COMPRESSED;
+				compressed
+			}
+			None => payload,
+		};
+		dst.reserve(5 + payload.len());
+		dst.extend_from_slice(&u32::to_le_bytes(payload.len() as u32 + 1));
+		dst.put_u8(flags);
+		dst.extend_from_slice(payload);
+		Ok(())
+	}
+
+	fn encode_frame(&mut self, item: &NetworkMessage, dst: &mut BytesMut) -> anyhow::Result<()> {
+		if self.flagged_frames() {
+			return self.encode_flagged(item, dst);
+		}
+		let len = bincode_options(u64::MAX).serialized_size(item)?;
+		self.check_frame_size(len)?;
+		let len_slice = u32::to_le_bytes(len as u32);
+		dst.reserve(4 + len as usize);
+
+		dst.extend_from_slice(&len_slice);
+		bincode_options(u64::MAX).serialize_into(dst.writer(), item)?;
+		Ok(())
+	}
+
+	fn encode_counted(&mut self, item: &NetworkMessage, dst: &mut BytesMut) -> anyhow::Result<()> {
+		let len_before = dst.len();
+		let result = self.encode_frame(item, dst);
+		if let Some(metrics) = &self.metrics {
+			metrics
+				.sent_bytes
+				.fetch_add((dst.len() - len_before) as u64, Ordering::Relaxed);
+		}
+		result
+	}
+
+	fn check_frame_size(&self, len: u64) -> anyhow::Result<()> {
+		if len > self.max_frame_size as u64 {
+			bail!(
+				"Can't send a frame of {len} bytes, above the limit of {} bytes",
+				self.max_frame_size
+			);
+		}
+		Ok(())
+	}
+}
 
 impl Decoder for Codec {
 	type Item = NetworkMessage;
@@ -43,14 +457,21 @@ impl Decoder for Codec {
 		// Read length marker.
 		let mut length_bytes = [0u8; 4];
 		length_bytes.copy_from_slice(&src[..4]);
-		let length = u32::from_le_bytes(length_bytes) as usize;
+		let length = u32::from_le_bytes(length_bytes);
+		if length > self.max_frame_size {
+			bail!(
+				"Received a frame of {length} bytes, above the limit of {} bytes",
+				self.max_frame_size
+			);
+		}
+		let length = length as usize;
 
 		if src.len() < 4 + length {
 			// The full string has not yet arrived.
 			//
 			// We reserve more space in the buffer. This is not strictly
 			// necessary, but is a good idea performance-wise.
-			src.reserve(4 + length - src.len());
+			src.reserve((4 + length - src.len()).min(MAX_RESERVE));
 
 			// We inform the Framed that we need more bytes to form the next
 			// frame.
@@ -59,11 +480,26 @@ impl Decoder for Codec {
 
 		let data = &src[4..4 + length];
 
-		let data = bincode::deserialize(data);
+		let data = if self.flagged_frames() {
+			match data.split_first() {
+				Some((&flags, payload)) => self.decode_payload(flags, payload),
+				None => Err(anyhow::anyhow!("Received a frame without flags")),
+			}
+		} else {
+			// Collections can't claim more elements than the frame could hold
+			bincode_options(length as u64)
+				.deserialize(data)
+				.map_err(Into::into)
+		};
 
 		// Use advance to modify src such that it no longer contains
 		// this frame.
 		src.advance(4 + length);
+		if let Some(metrics) = &self.metrics {
+			metrics
+				.received_bytes
+				.fetch_add(4 + length as u64, Ordering::Relaxed);
+		}
 
 		Ok(Some(data?))
 	}
@@ -73,12 +509,397 @@ impl Encoder<NetworkMessage> for Codec {
 	type Error = anyhow::Error;
 
 	fn encode(&mut self, item: NetworkMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-		let len = bincode::serialized_size(&item)?;
-		let len_slice = u32::to_le_bytes(len as u32);
-		dst.reserve(4 + len as usize);
+		self.encode_counted(&item, dst)
+	}
+}
 
-		dst.extend_from_slice(&len_slice);
-		bincode::serialize_into(dst.writer(), &item)?;
-		Ok(())
+/// Links keep the messages they send until they are acknowledged, shared with
+/// the sink instead of copied
+impl Encoder<Arc<NetworkMessage>> for Codec {
+	type Error = anyhow::Error;
+
+	fn encode(&mut self, item: Arc<NetworkMessage>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+		self.encode_counted(&item, dst)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{prelude::Key, simulation::Rng, verify::NodeLinks};
+
+	const MAX_FRAME_SIZE: u32 = 1 << 16;
+
+	fn codec() -> Codec {
+		Codec::new(
+			Protocol {
+				features: features::REPLICATION | features::ELASTIC,
+			},
+			MAX_FRAME_SIZE,
+			None,
+		)
+	}
+
+	/// Compact keys, and compression of every frame big enough
+	fn compact_codec() -> Codec {
+		Codec::new(
+			Protocol {
+				features: features::SUPPORTED,
+			},
+			MAX_FRAME_SIZE,
+			Some(3),
+		)
+	}
+
+	fn messages() -> Vec<NetworkMessage> {
+		let key = Key::new(3, 42);
+		let req_id = ReqId::new(0, 7);
+		vec![
+			NetworkMessage::Hello {
+				id: 2,
+				session: 1234,
+				shard_owners: vec![0, 1, 2],
+				replication: Some(Replication {
+					n_systems: 3,
+					factor: 1,
+				}),
+				connect_to: vec![
+					(1, "127.0.0.1:10000".parse().unwrap()),
+					(2, "unix:/run/big_uf/worker_2".parse().unwrap()),
+				],
+				cluster_token: Some("token".to_owned()),
+			},
+			NetworkMessage::Id {
+				id: 1,
+				session: 5678,
+				n_shards: 3,
+				cluster_token: None,
+			},
+			NetworkMessage::DriverMessages {
+				seq: 1,
+				driver_idx: 0,
+				batch: vec![
+					DriverMessage::FindDone {
+						req_id,
+						response: key,
+					},
+					DriverMessage::VerifyNodes {
+						req_id,
+						shard: 3,
+						n_nodes: 43,
+						nodes: vec![NodeLinks {
+							parent: Key::new(1, 0),
+							child: key,
+							sibling: key,
+							size: 2,
+						}],
+					},
+					DriverMessage::FindDetailedDone {
+						req_id,
+						response: key,
+						hops: 2,
+						shards: vec![3, 1],
+						component_size: 7,
+					},
+				],
+			},
+			NetworkMessage::ShardMessages {
+				seq: 2,
+				shard_id: 3,
+				batch: vec![
+					ShardMessage::Union {
+						node: key,
+						to: Key::new(1, 0),
+						child: key,
+						req_id,
+						hops: 3,
+					},
+					// Only carried by compact batches
+					ShardMessage::SetParent {
+						node: key,
+						to: key,
+						req_id: ReqId::unknown(),
+					},
+				],
+			},
+			NetworkMessage::Heartbeat { ack: 2 },
+			NetworkMessage::ShardData {
+				seq: 3,
+				shard_id: 3,
+				nodes: vec![
+					Mutation::AddNode { shard: 3 },
+					Mutation::SetParent { key, value: key },
+					Mutation::SetSize { key, size: 2 },
+				],
+				message_counters: (10, 9),
+				req_id,
+			},
+		]
+	}
+
+	/// A batch big enough to be compressed, with keys close to each other
+	fn big_batch() -> NetworkMessage {
+		let mut rng = Rng::new(0x1234_5678_9abc_def1);
+		let batch = (0..500)
+			.map(|i| ShardMessage::Find {
+				node: Key::new(2, 1000 + rng.below(64) as u64),
+				child: Key::new(rng.below(4), rng.below(1 << 20) as u64),
+				req_id: ReqId::new(1, i),
+				hops: rng.below(8) as u32,
+			})
+			.collect();
+		NetworkMessage::ShardMessages {
+			seq: 4,
+			shard_id: 2,
+			batch,
+		}
+	}
+
+	fn all_shard_messages() -> NetworkMessage {
+		let key = Key::new(1, 5);
+		let req_id = ReqId::new(3, 9);
+		NetworkMessage::ShardMessages {
+			seq: u64::MAX,
+			shard_id: u16::MAX,
+			batch: vec![
+				ShardMessage::AddNode { shard: 1, req_id },
+				ShardMessage::Union {
+					node: key,
+					to: Key::new(0, 0),
+					child: Key::new(u16::MAX as usize, 0xFFFF_FFFF),
+					req_id,
+					hops: u32::MAX,
+				},
+				ShardMessage::SwapUnion {
+					node: Key::new(0, 0),
+					to: key,
+					req_id,
+					hops: 0,
+				},
+				ShardMessage::FindDetailed {
+					node: key,
+					child: Key::new(0, 0),
+					req_id,
+					hops: u32::MAX,
+					shards: vec![0, u16::MAX, 1],
+				},
+				ShardMessage::AddSize {
+					node: key,
+					size: u64::MAX,
+					req_id,
+				},
+				ShardMessage::SetChild {
+					node: key,
+					to: key,
+					req_id: ReqId::new(0, 0),
+				},
+				ShardMessage::SetSibling {
+					node: key,
+					to: key,
+					req_id,
+				},
+				ShardMessage::SetParent {
+					node: key,
+					to: key,
+					req_id,
+				},
+				ShardMessage::Find {
+					node: key,
+					child: key,
+					req_id,
+					hops: 1,
+				},
+				ShardMessage::GracefulShutdown { shard: 1, req_id },
+				ShardMessage::Barrier { shard: 1, req_id },
+				ShardMessage::Migrate {
+					shard: 1,
+					to_system: 4,
+					req_id,
+				},
+				ShardMessage::Verify { shard: 1, req_id },
+			],
+		}
+	}
+
+	fn encode_with(codec: &mut Codec, messages: &[NetworkMessage]) -> BytesMut {
+		let mut bytes = BytesMut::new();
+		for message in messages {
+			codec.encode(message.clone(), &mut bytes).unwrap();
+		}
+		bytes
+	}
+
+	fn encode(messages: &[NetworkMessage]) -> BytesMut {
+		encode_with(&mut codec(), messages)
+	}
+
+	/// Decodes until the input runs out or is refused, which must never panic
+	fn decode_all(bytes: &[u8]) -> Vec<NetworkMessage> {
+		decode_all_with(codec(), bytes)
+	}
+
+	fn decode_all_with(mut codec: Codec, bytes: &[u8]) -> Vec<NetworkMessage> {
+		let mut src = BytesMut::from(bytes);
+		let mut decoded = Vec::new();
+		while let Ok(Some(message)) = codec.decode(&mut src) {
+			decoded.push(message);
+		}
+		decoded
+	}
+
+	#[test]
+	fn round_trip() {
+		let messages = messages();
+		let decoded = decode_all(&encode(&messages));
+		assert_eq!(format!("{decoded:?}"), format!("{messages:?}"));
+	}
+
+	#[test]
+	fn compact_round_trip() {
+		let mut messages = messages();
+		messages.push(big_batch());
+		messages.push(all_shard_messages());
+		let bytes = encode_with(&mut compact_codec(), &messages);
+		let decoded = decode_all_with(compact_codec(), &bytes);
+		assert_eq!(format!("{decoded:?}"), format!("{messages:?}"));
+		assert!(bytes.len() < encode(&messages).len() / 2);
+	}
+
+	#[test]
+	fn compression_is_only_used_when_negotiated() {
+		let compact_only = Protocol {
+			features: features::SUPPORTED & !features::COMPRESSION,
+		};
+		let mut codec = Codec::new(compact_only, MAX_FRAME_SIZE, Some(3));
+		let bytes = encode_with(&mut codec, &[big_batch()]);
+		assert_eq!(bytes[4] & frame_flags::COMPRESSED, 0);
+		// A peer that didn't negotiate compression refuses compressed frames
+		let compressed = encode_with(&mut compact_codec(), &[big_batch()]);
+		assert_ne!(compressed[4] & frame_flags::COMPRESSED, 0);
+		let mut src = BytesMut::from(&compressed[..]);
+		assert!(Codec::new(compact_only, MAX_FRAME_SIZE, None)
+			.decode(&mut src)
+			.is_err());
+	}
+
+	#[test]
+	fn set_parent_requests_need_trace_context() {
+		let without = Protocol {
+			features: features::SUPPORTED & !features::TRACE_CONTEXT,
+		};
+		let bytes = encode_with(
+			&mut Codec::new(without, MAX_FRAME_SIZE, None),
+			&[all_shard_messages()],
+		);
+		let decoded = decode_all_with(Codec::new(without, MAX_FRAME_SIZE, None), &bytes);
+		let NetworkMessage::ShardMessages { batch, .. } = &decoded[0] else {
+			panic!("Expected a batch, got {decoded:?}");
+		};
+		assert!(batch.iter().any(|message| matches!(
+			message,
+			ShardMessage::SetParent { req_id, .. } if *req_id == ReqId::unknown()
+		)));
+		assert!(bytes.len() < encode_with(&mut compact_codec(), &[all_shard_messages()]).len());
+	}
+
+	#[test]
+	fn decompression_is_bounded() {
+		// Zeros compress very well, far below the limit they expand above
+		let payload = zstd::bulk::compress(&vec![0; MAX_FRAME_SIZE as usize * 4], 3).unwrap();
+		let mut src = BytesMut::new();
+		src.put_u32_le(payload.len() as u32 + 1);
+		src.put_u8(frame_flags::COMPRESSED);
+		src.extend_from_slice(&payload);
+		assert!(compact_codec().decode(&mut src).is_err());
+	}
+
+	#[test]
+	fn fuzz_mutated_compact_frames() {
+		let valid = encode_with(
+			&mut compact_codec(),
+			&[all_shard_messages(), big_batch(), all_shard_messages()],
+		);
+		let mut rng = Rng::new(0x3c6e_f372_fe94_f82b);
+		for _ in 0..5_000 {
+			let mut bytes = valid.to_vec();
+			for _ in 0..1 + rng.below(8) {
+				let position = rng.below(bytes.len());
+				match rng.below(4) {
+					0 => bytes[position] = rng.next_u64() as u8,
+					1 => bytes[position] ^= 1 << rng.below(8),
+					2 => bytes.truncate(position),
+					_ => bytes.insert(position, rng.next_u64() as u8),
+				}
+				if bytes.is_empty() {
+					break;
+				}
+			}
+			decode_all_with(compact_codec(), &bytes);
+		}
+	}
+
+	#[test]
+	fn oversized_frames_are_refused() {
+		let mut src = BytesMut::from(&u32::MAX.to_le_bytes()[..]);
+		assert!(codec().decode(&mut src).is_err());
+
+		let too_big = NetworkMessage::ReplicaMutations {
+			seq: 1,
+			shard_id: 0,
+			batch: vec![Mutation::AddNode { shard: 0 }; MAX_FRAME_SIZE as usize],
+		};
+		assert!(codec().encode(too_big, &mut BytesMut::new()).is_err());
+	}
+
+	#[test]
+	fn incomplete_frames_reserve_little() {
+		let mut src = BytesMut::from(&(MAX_FRAME_SIZE - 1).to_le_bytes()[..]);
+		let mut codec = Codec::new(Protocol { features: 0 }, u32::MAX, None);
+		assert!(codec.decode(&mut src).unwrap().is_none());
+		assert!(src.capacity() <= MAX_RESERVE + 4);
+	}
+
+	#[test]
+	fn collection_lengths_are_bounded_by_the_frame() {
+		// A `ShardMessages` frame whose batch claims u64::MAX elements
+		let mut frame = encode(&[NetworkMessage::ShardMessages {
+			seq: 1,
+			shard_id: 0,
+			batch: Vec::new(),
+		}]);
+		let batch_length = frame.len() - 8;
+		frame[batch_length..].copy_from_slice(&u64::MAX.to_le_bytes());
+		assert!(codec().decode(&mut frame).is_err());
+	}
+
+	#[test]
+	fn fuzz_random_bytes() {
+		let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+		for _ in 0..20_000 {
+			let bytes: Vec<u8> = (0..rng.below(300)).map(|_| rng.next_u64() as u8).collect();
+			decode_all(&bytes);
+		}
+	}
+
+	#[test]
+	fn fuzz_mutated_frames() {
+		let valid = encode(&messages());
+		let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+		for _ in 0..20_000 {
+			let mut bytes = valid.to_vec();
+			for _ in 0..1 + rng.below(8) {
+				let position = rng.below(bytes.len());
+				match rng.below(4) {
+					0 => bytes[position] = rng.next_u64() as u8,
+					1 => bytes[position] ^= 1 << rng.below(8),
+					2 => bytes.truncate(position),
+					_ => bytes.insert(position, rng.next_u64() as u8),
+				}
+				if bytes.is_empty() {
+					break;
+				}
+			}
+			decode_all(&bytes);
+		}
 	}
 }
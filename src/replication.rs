@@ -0,0 +1,146 @@
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{network_message::NetworkMessage, prelude::*};
+
+/// A change made to a shard's storage, streamed to the replicas of that shard
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) enum Mutation {
+	AddNode { shard: u16 },
+	SetParent { key: Key, value: Key },
+	SetSibling { key: Key, value: Key },
+	SetChild { key: Key, value: Key },
+	SetSize { key: Key, size: u64 },
+}
+
+impl Mutation {
+	pub fn apply(self, storage: &mut impl Storage) {
+		match self {
+			Mutation::AddNode { shard } => {
+				storage.add_node(shard as usize);
+			}
+			Mutation::SetParent { key, value } => storage.set_parent(key, value),
+			Mutation::SetSibling { key, value } => storage.set_sibling(key, value),
+			Mutation::SetChild { key, value } => {
+				storage.swap_child(key, value);
+			}
+			Mutation::SetSize { key, size } => storage.set_size(key, size),
+		}
+	}
+
+	/// Fails unless the mutations, applied to a copy of `shard` holding
+	/// `n_nodes` nodes, only touch nodes of that shard added by then and only
+	/// link to shards below `n_shards`: the storage would panic on the others
+	pub fn check_all(
+		mutations: &[Mutation],
+		shard: u16,
+		n_shards: usize,
+		mut n_nodes: u64,
+	) -> Result<()> {
+		let exists = |key: Key, n_nodes: u64| {
+			key.shard() == shard as usize && key.shard_specific_id() < n_nodes
+		};
+		for mutation in mutations {
+			let valid = match *mutation {
+				Mutation::AddNode { shard: added } => {
+					n_nodes += 1;
+					added == shard
+				}
+				Mutation::SetParent { key, value }
+				| Mutation::SetSibling { key, value }
+				| Mutation::SetChild { key, value } => exists(key, n_nodes) && value.shard() < n_shards,
+				Mutation::SetSize { key, .. } => exists(key, n_nodes),
+			};
+			ensure!(valid, "Invalid mutation of shard {shard}: {mutation:?}");
+		}
+		Ok(())
+	}
+
+	/// The mutations that rebuild `storage` from scratch, used to ship a whole
+	/// shard to another system
+	pub fn snapshot(storage: &impl Storage, shard: usize) -> Vec<Mutation> {
+		let mut mutations = Vec::new();
+		for id in 0..storage.n_nodes() {
+			let key = Key::new(shard, id);
+			mutations.push(Mutation::AddNode {
+				shard: shard as u16,
+			});
+			if let Some(value) = storage.get_parent(key) {
+				mutations.push(Mutation::SetParent { key, value });
+			}
+			if let Some(value) = storage.get_sibling(key) {
+				mutations.push(Mutation::SetSibling { key, value });
+			}
+			if let Some(value) = storage.get_child(key) {
+				mutations.push(Mutation::SetChild { key, value });
+			}
+			let size = storage.get_size(key);
+			if size != 1 {
+				mutations.push(Mutation::SetSize { key, size });
+			}
+		}
+		mutations
+	}
+}
+
+/// Where the copies of each shard live
+///
+/// The shards served by a system are replicated on the `factor` systems that
+/// follow it among the `n_systems` the cluster started with, and the first of
+/// them takes over these shards if the system dies.
+/// Replication is asynchronous: the mutations are shipped whenever the shard
+/// flushes its batches, so the last ones may be lost with the primary.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub(crate) struct Replication {
+	pub n_systems: u16,
+	pub factor: u16,
+}
+
+impl Replication {
+	/// Systems holding a copy of the shards of `owner`, in promotion order
+	pub fn replicas_of(&self, owner: u16) -> impl Iterator<Item = u16> {
+		let n_systems = self.n_systems;
+		(1..=self.factor.min(n_systems - 1)).map(move |i| (owner + i) % n_systems)
+	}
+}
+
+/// The mutations of a shard that weren't streamed to its replicas yet
+pub(crate) struct ReplicationLog {
+	shard_id: u16,
+	replicas: Vec<u16>,
+	mutations: Vec<Mutation>,
+}
+
+impl ReplicationLog {
+	pub fn new(shard_id: usize, replicas: Vec<u16>) -> Self {
+		Self {
+			shard_id: shard_id as u16,
+			replicas,
+			mutations: Vec::new(),
+		}
+	}
+
+	pub fn push(&mut self, mutation: Mutation) {
+		if !self.replicas.is_empty() {
+			self.mutations.push(mutation);
+		}
+	}
+
+	/// Sends the mutations logged since the last call to every replica
+	pub fn send(&mut self, system: &System) {
+		if self.mutations.is_empty() {
+			return;
+		}
+		let batch = std::mem::take(&mut self.mutations);
+		for &replica in &self.replicas {
+			system.send_to_peer(
+				replica,
+				NetworkMessage::ReplicaMutations {
+					seq: 0,
+					shard_id: self.shard_id,
+					batch: batch.clone(),
+				},
+			);
+		}
+	}
+}
@@ -1,7 +1,7 @@
 use crate::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub(crate) enum ShardMessage {
 	AddNode {
 		shard: u16,
@@ -12,6 +12,17 @@ pub(crate) enum ShardMessage {
 		to: Key,
 		child: Key,
 		req_id: ReqId,
+		/// Messages of the request processed before this one, for the metrics
+		hops: u32,
+	},
+	/// A union that reached the root `to`, smaller than the node it unites
+	/// with, so it continues from that node: the larger root is linked under the
+	/// smaller one
+	SwapUnion {
+		node: Key,
+		to: Key,
+		req_id: ReqId,
+		hops: u32,
 	},
 	SetChild {
 		node: Key,
@@ -23,31 +34,197 @@ pub(crate) enum ShardMessage {
 		to: Key,
 		req_id: ReqId,
 	},
+	/// Path compression, `req_id` is the request that went through `node`
 	SetParent {
 		node: Key,
 		to: Key,
+		/// Only sent in compact batches, to peers that negotiated
+		/// `TRACE_CONTEXT`
+		#[serde(skip, default = "ReqId::unknown")]
+		req_id: ReqId,
 	},
 	Find {
 		node: Key,
 		child: Key,
 		req_id: ReqId,
+		/// Messages of the request processed before this one, for the metrics
+		hops: u32,
+	},
+	/// A `Find` that counts its hops and the shards it goes through, for
+	/// `Driver::find_detailed`
+	FindDetailed {
+		node: Key,
+		child: Key,
+		req_id: ReqId,
+		hops: u32,
+		/// In the order they are first visited
+		shards: Vec<u16>,
+	},
+	/// Adds the size of a root that was linked under `node` to the size of its
+	/// root
+	AddSize {
+		node: Key,
+		size: u64,
+		req_id: ReqId,
 	},
 	GracefulShutdown {
 		shard: u16,
 		req_id: ReqId,
 	},
+	Barrier {
+		shard: u16,
+		req_id: ReqId,
+	},
+	/// Ship the shard to `to_system`, which serves it from then on
+	Migrate {
+		shard: u16,
+		to_system: u16,
+		req_id: ReqId,
+	},
+	/// Send the nodes of the shard to the driver, see `Driver::verify`
+	Verify {
+		shard: u16,
+		req_id: ReqId,
+	},
 }
 
 impl ShardMessage {
+	/// Names of the variants, in the order of `kind`
+	pub const KINDS: [&'static str; 13] = [
+		"add_node",
+		"union",
+		"set_child",
+		"set_sibling",
+		"set_parent",
+		"find",
+		"graceful_shutdown",
+		"barrier",
+		"migrate",
+		"verify",
+		"swap_union",
+		"find_detailed",
+		"add_size",
+	];
+
+	pub fn kind(&self) -> usize {
+		match self {
+			ShardMessage::AddNode { .. } => 0,
+			ShardMessage::Union { .. } => 1,
+			ShardMessage::SetChild { .. } => 2,
+			ShardMessage::SetSibling { .. } => 3,
+			ShardMessage::SetParent { .. } => 4,
+			ShardMessage::Find { .. } => 5,
+			ShardMessage::GracefulShutdown { .. } => 6,
+			ShardMessage::Barrier { .. } => 7,
+			ShardMessage::Migrate { .. } => 8,
+			ShardMessage::Verify { .. } => 9,
+			ShardMessage::SwapUnion { .. } => 10,
+			ShardMessage::FindDetailed { .. } => 11,
+			ShardMessage::AddSize { .. } => 12,
+		}
+	}
+
+	/// The request that caused this message
+	#[cfg(feature = "tracing")]
+	pub fn req_id(&self) -> ReqId {
+		match *self {
+			ShardMessage::AddNode { req_id, .. }
+			| ShardMessage::Union { req_id, .. }
+			| ShardMessage::SwapUnion { req_id, .. }
+			| ShardMessage::SetChild { req_id, .. }
+			| ShardMessage::SetSibling { req_id, .. }
+			| ShardMessage::SetParent { req_id, .. }
+			| ShardMessage::Find { req_id, .. }
+			| ShardMessage::FindDetailed { req_id, .. }
+			| ShardMessage::AddSize { req_id, .. }
+			| ShardMessage::GracefulShutdown { req_id, .. }
+			| ShardMessage::Barrier { req_id, .. }
+			| ShardMessage::Migrate { req_id, .. }
+			| ShardMessage::Verify { req_id, .. } => req_id,
+		}
+	}
+
 	pub fn target_shard(&self) -> usize {
 		match *self {
 			ShardMessage::Union { node, .. } => node.shard(),
+			ShardMessage::SwapUnion { node, .. } => node.shard(),
 			ShardMessage::SetChild { node, .. } => node.shard(),
 			ShardMessage::SetSibling { node, .. } => node.shard(),
 			ShardMessage::SetParent { node, .. } => node.shard(),
 			ShardMessage::Find { node, .. } => node.shard(),
+			ShardMessage::FindDetailed { node, .. } => node.shard(),
+			ShardMessage::AddSize { node, .. } => node.shard(),
 			ShardMessage::AddNode { shard, .. } => shard as usize,
 			ShardMessage::GracefulShutdown { shard, .. } => shard as usize,
+			ShardMessage::Barrier { shard, .. } => shard as usize,
+			ShardMessage::Migrate { shard, .. } => shard as usize,
+			ShardMessage::Verify { shard, .. } => shard as usize,
+		}
+	}
+
+	/// Whether all the shards below `n_shards` and the nodes `exists` accepts
+	/// this message refers to exist, checked on the messages received from other
+	/// systems before they are routed, as the storage would panic on the others
+	pub fn refers_to_existing(&self, n_shards: usize, exists: impl Fn(Key) -> bool) -> bool {
+		match *self {
+			ShardMessage::Union {
+				node, to, child, ..
+			} => [node, to, child].into_iter().all(exists),
+			ShardMessage::SwapUnion { node, to, .. }
+			| ShardMessage::SetChild { node, to, .. }
+			| ShardMessage::SetSibling { node, to, .. }
+			| ShardMessage::SetParent { node, to, .. } => exists(node) && exists(to),
+			ShardMessage::Find { node, child, .. }
+			| ShardMessage::FindDetailed { node, child, .. } => exists(node) && exists(child),
+			ShardMessage::AddSize { node, .. } => exists(node),
+			ShardMessage::AddNode { shard, .. }
+			| ShardMessage::GracefulShutdown { shard, .. }
+			| ShardMessage::Barrier { shard, .. }
+			| ShardMessage::Migrate { shard, .. }
+			| ShardMessage::Verify { shard, .. } => (shard as usize) < n_shards,
+		}
+	}
+
+	/// The request that waits for this message to be answered, none for a path
+	/// compression or a size update, which follow requests answered without
+	/// them
+	pub fn awaited_by(&self) -> Option<ReqId> {
+		match *self {
+			ShardMessage::SetParent { .. } | ShardMessage::AddSize { .. } => None,
+			ShardMessage::AddNode { req_id, .. }
+			| ShardMessage::Union { req_id, .. }
+			| ShardMessage::SwapUnion { req_id, .. }
+			| ShardMessage::SetChild { req_id, .. }
+			| ShardMessage::SetSibling { req_id, .. }
+			| ShardMessage::Find { req_id, .. }
+			| ShardMessage::FindDetailed { req_id, .. }
+			| ShardMessage::GracefulShutdown { req_id, .. }
+			| ShardMessage::Barrier { req_id, .. }
+			| ShardMessage::Migrate { req_id, .. }
+			| ShardMessage::Verify { req_id, .. } => Some(req_id),
+		}
+	}
+
+	/// Whether this message was sent directly by a driver, as opposed to being
+	/// produced by a shard while processing another message
+	///
+	/// `Union` and `Find` use `child == node` to mark the starting point of the
+	/// search, so that's also what tells us that they come from a driver.
+	pub fn is_from_driver(&self) -> bool {
+		match *self {
+			ShardMessage::AddNode { .. }
+			| ShardMessage::GracefulShutdown { .. }
+			| ShardMessage::Barrier { .. }
+			| ShardMessage::Migrate { .. }
+			| ShardMessage::Verify { .. } => true,
+			ShardMessage::Union { node, child, .. }
+			| ShardMessage::Find { node, child, .. }
+			| ShardMessage::FindDetailed { node, child, .. } => node == child,
+			ShardMessage::SwapUnion { .. }
+			| ShardMessage::AddSize { .. }
+			| ShardMessage::SetChild { .. }
+			| ShardMessage::SetSibling { .. }
+			| ShardMessage::SetParent { .. } => false,
 		}
 	}
 }
@@ -1,37 +1,102 @@
 pub(crate) mod message;
 
-use std::sync::Arc;
+use std::{
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
-use futures::SinkExt;
+use anyhow::Result;
+use futures::{channel::oneshot, SinkExt};
 
-use crate::{network_message::NetworkMessage, prelude::*};
+use crate::{
+	metrics::{ShardCounters, StorageOp, STORAGE_SAMPLE_PERIOD},
+	network_message::NetworkMessage,
+	prelude::*,
+	replication::Mutation,
+	trace,
+	verify::{self, NodeLinks},
+};
+
+/// Why a shard thread stops
+#[derive(Clone, Copy)]
+pub(crate) enum Stop {
+	Shutdown(ReqId),
+	Migrate { to_system: u16, req_id: ReqId },
+}
+
+/// Asked by the admin endpoint, answered on the channel they carry once the
+/// shard is done with the batch it is processing
+pub(crate) enum Command {
+	/// Sends the pending batches and flushes the storage
+	Flush(oneshot::Sender<Result<()>>),
+	/// Copies the storage into that directory, see `Storage::checkpoint`
+	Checkpoint(PathBuf, oneshot::Sender<Result<()>>),
+	/// The system the shard is migrating to won't serve it
+	MigrationRefused(String),
+}
+
+/// How often a migrating shard checks that its new system is still there
+const MIGRATION_POLL_PERIOD: Duration = Duration::from_millis(100);
 
 pub(crate) fn spawn<S: Storage, F: FnOnce() -> S + Send + 'static>(
 	storage_fn: F,
 	system: Arc<System>,
 	receiver: crossbeam_channel::Receiver<Vec<ShardMessage>>,
 	shard_id: usize,
+	replicas: Vec<u16>,
+	(n_sent_shard_messages, n_received_shard_messages): (u64, u64),
 ) -> std::thread::JoinHandle<()> {
+	let (command_sender, commands) = crossbeam_channel::unbounded();
+	system.register_shard_commands(shard_id, command_sender.clone());
 	std::thread::spawn(move || {
-		let mut shard_data = UnionFindShardData {
-			other_shard_batching: MessageBatching::new(system),
-			current_shard_pending_messages: Vec::new(),
+		let mut shard_data = UnionFindShardData::new(
+			storage_fn(),
+			system,
 			shard_id,
-			storage: storage_fn(),
-		};
+			replicas,
+			(n_sent_shard_messages, n_received_shard_messages),
+		);
 		let mut n_processed_messages_without_flush = 0;
+		// What reached us during a migration that failed
+		let mut resumed = None;
 
 		loop {
+			let batch = match resumed.take() {
+				Some(batch) => batch,
+				None => crossbeam_channel::select! {
+					recv(receiver) -> batch => match batch {
+						Ok(batch) => batch,
+						// The system is gone, so is everything we could still answer to
+						Err(crossbeam_channel::RecvError) => break,
+					},
+					recv(commands) -> command => {
+						// The system holds a sender until we stop
+						shard_data.run(command.unwrap());
+						continue;
+					}
+				},
+			};
 			let mut maybe_flush = |shard_data: &mut UnionFindShardData<S>| {
 				n_processed_messages_without_flush += 1;
-				if n_processed_messages_without_flush > 100_000 {
-					shard_data.other_shard_batching.flush();
+				// Looking at the clock now and then bounds the latency under load
+				if n_processed_messages_without_flush > 100_000
+					|| (n_processed_messages_without_flush % 1024 == 0
+						&& shard_data.other_shard_batching.is_late())
+				{
+					shard_data.flush();
 					n_processed_messages_without_flush = 0;
 				}
 			};
 			let mut should_stop = None;
-			let mut process_received_batch = |batch: Vec<ShardMessage>| {
-				for msg in batch {
+			// Once migrating, the following messages are for the new system
+			let mut deferred = Vec::new();
+			let mut process_received_batch = |mut batch: Vec<ShardMessage>| {
+				for msg in batch.drain(..) {
+					if let Some(Stop::Migrate { .. }) = should_stop {
+						deferred.push(msg);
+						continue;
+					}
 					should_stop = should_stop.or(shard_data.process_message(msg));
 					maybe_flush(&mut shard_data);
 					while let Some(msg) = shard_data.current_shard_pending_messages.pop() {
@@ -39,39 +104,111 @@ pub(crate) fn spawn<S: Storage, F: FnOnce() -> S + Send + 'static>(
 						maybe_flush(&mut shard_data);
 					}
 				}
+				let system = &shard_data.other_shard_batching.system;
+				system.shard_batches().recycle(batch);
 			};
-			let batch = receiver.recv().expect("Sender disconnected");
 			process_received_batch(batch);
-			loop {
-				match receiver.try_recv() {
-					Ok(batch) => process_received_batch(batch),
-					Err(crossbeam_channel::TryRecvError::Disconnected) => {
-						panic!("Sender disconnected");
-					}
-					Err(crossbeam_channel::TryRecvError::Empty) => {
+			// A disconnection is seen by the next `select!`
+			while let Ok(batch) = receiver.try_recv() {
+				process_received_batch(batch);
+			}
+			shard_data.flush();
+			match should_stop {
+				Some(Stop::Shutdown(req_id)) => {
+					// The commands sent from now on fail instead of waiting for us
+					let system = &shard_data.other_shard_batching.system;
+					system.unregister_shard_commands(shard_id, &command_sender);
+					shard_data.send_to_driver(DriverMessage::ShutdownDone { req_id });
+					break;
+				}
+				Some(Stop::Migrate { to_system, req_id }) => {
+					resumed = shard_data.migrate(to_system, req_id, deferred, &receiver, &commands);
+					if resumed.is_none() {
 						break;
 					}
 				}
-			}
-			shard_data.other_shard_batching.flush();
-			if let Some(req_id) = should_stop {
-				shard_data.send_to_driver(DriverMessage::ShutdownDone { req_id });
-				break;
+				None => {}
 			}
 			n_processed_messages_without_flush = 0;
 		}
+		let system = &shard_data.other_shard_batching.system;
+		system.unregister_shard_commands(shard_id, &command_sender);
 	})
 }
 
-struct UnionFindShardData<S> {
+pub(crate) struct UnionFindShardData<S> {
 	other_shard_batching: MessageBatching,
 	current_shard_pending_messages: Vec<ShardMessage>,
 	shard_id: usize,
 	storage: S,
+	/// Shard to shard messages sent and received, used for barrier detection
+	n_sent_shard_messages: u64,
+	n_received_shard_messages: u64,
+	/// Systems the storage mutations are streamed to, through
+	/// `other_shard_batching`
+	replicas: Vec<u16>,
+	/// Published to the metrics of the system on every flush
+	counters: ShardCounters,
+	n_storage_ops: u64,
 }
 
 impl<S: Storage> UnionFindShardData<S> {
+	pub(crate) fn new(
+		storage: S,
+		system: Arc<System>,
+		shard_id: usize,
+		replicas: Vec<u16>,
+		(n_sent_shard_messages, n_received_shard_messages): (u64, u64),
+	) -> Self {
+		let shard_data = Self {
+			other_shard_batching: MessageBatching::for_shard(system, shard_id, replicas.clone()),
+			current_shard_pending_messages: Vec::new(),
+			shard_id,
+			storage,
+			n_sent_shard_messages,
+			n_received_shard_messages,
+			replicas,
+			counters: ShardCounters::default(),
+			n_storage_ops: 0,
+		};
+		shard_data.publish_nodes();
+		shard_data.send_snapshot();
+		shard_data
+	}
+
+	/// Gives the replicas a copy of what the storage holds when the shard
+	/// starts, the mutations that follow are streamed by `flush`. Sent even when
+	/// empty, so that a replica can tell an empty shard from a lost copy.
+	fn send_snapshot(&self) {
+		if self.replicas.is_empty() {
+			return;
+		}
+		let system = &self.other_shard_batching.system;
+		let nodes = Mutation::snapshot(&self.storage, self.shard_id);
+		for &replica in &self.replicas {
+			system.send_to_peer(
+				replica,
+				NetworkMessage::ReplicaSnapshot {
+					seq: 0,
+					shard_id: self.shard_id as u16,
+					nodes: nodes.clone(),
+				},
+			);
+		}
+	}
+
+	pub(crate) fn storage_ref(&self) -> &S {
+		&self.storage
+	}
+
+	/// The messages this shard sent to itself, which the shard thread processes
+	/// before the next message it received
+	pub(crate) fn take_pending_messages(&mut self) -> Vec<ShardMessage> {
+		std::mem::take(&mut self.current_shard_pending_messages)
+	}
+
 	fn send(&mut self, message: ShardMessage) {
+		self.n_sent_shard_messages += 1;
 		let target_shard = message.target_shard();
 		if target_shard == self.shard_id {
 			self.current_shard_pending_messages.push(message);
@@ -81,14 +218,232 @@ impl<S: Storage> UnionFindShardData<S> {
 	}
 
 	fn send_to_driver(&mut self, message: DriverMessage) {
+		trace::answered(&message);
 		self.other_shard_batching.send_to_driver(message);
 	}
 
-	fn process_message(&mut self, message: ShardMessage) -> Option<ReqId> {
+	pub(crate) fn flush(&mut self) {
+		self.other_shard_batching.flush();
+		let system = &self.other_shard_batching.system;
+		system.metrics().publish(&mut self.counters);
+		self.publish_nodes();
+	}
+
+	fn publish_nodes(&self) {
+		let system = &self.other_shard_batching.system;
+		system
+			.metrics()
+			.set_nodes(self.shard_id, self.storage.n_nodes());
+		system.set_n_nodes(self.shard_id, self.storage.n_nodes());
+	}
+
+	fn run(&mut self, command: Command) {
+		match command {
+			Command::Flush(done) => {
+				self.flush();
+				let _ = done.send(self.storage.flush());
+			}
+			Command::Checkpoint(dir, done) => {
+				let _ = done.send(self.storage.checkpoint(&dir));
+			}
+			// For a migration that already failed, when its system was lost
+			Command::MigrationRefused(_) => {}
+		}
+	}
+
+	/// Ships the storage to `to_system`, then forwards the messages that still
+	/// reach us until the route to this shard is replaced, which disconnects
+	/// `receiver`
+	///
+	/// If `to_system` refuses the shard or is lost before serving it, answers
+	/// `MoveShardFailed` and returns the messages received in the meantime,
+	/// which this shard keeps serving, but for those about the nodes that
+	/// `to_system` created.
+	fn migrate(
+		&mut self,
+		to_system: u16,
+		req_id: ReqId,
+		mut deferred: Vec<ShardMessage>,
+		receiver: &crossbeam_channel::Receiver<Vec<ShardMessage>>,
+		commands: &crossbeam_channel::Receiver<Command>,
+	) -> Option<Vec<ShardMessage>> {
+		let system = self.other_shard_batching.system.clone();
+		// Once `to_system` serves the shard it creates nodes, which the peers
+		// that still route the shard here may send us before the route changes
+		system.set_n_nodes(self.shard_id, u64::MAX);
+		system.send_to_peer(
+			to_system,
+			NetworkMessage::ShardData {
+				seq: 0,
+				shard_id: self.shard_id as u16,
+				nodes: Mutation::snapshot(&self.storage, self.shard_id),
+				message_counters: (self.n_sent_shard_messages, self.n_received_shard_messages),
+				req_id,
+			},
+		);
+		let reason = loop {
+			crossbeam_channel::select! {
+				recv(receiver) -> batch => match batch {
+					Ok(batch) => deferred.extend(batch),
+					Err(crossbeam_channel::RecvError) => break None,
+				},
+				recv(commands) -> command => match command.unwrap() {
+					Command::MigrationRefused(reason) => break Some(reason),
+					command => self.run(command),
+				},
+				default(MIGRATION_POLL_PERIOD) => {
+					if system.has_left(to_system) {
+						break Some(format!("System {to_system} was lost"));
+					}
+				}
+			}
+		};
+		match reason {
+			None => {
+				if !deferred.is_empty() {
+					system.shard(self.shard_id).send_messages(deferred);
+				}
+				None
+			}
+			Some(reason) => {
+				system.set_n_nodes(self.shard_id, self.storage.n_nodes());
+				// Only `to_system` had the nodes it created in the meantime
+				let n_shards = system.n_shards();
+				let lost;
+				(deferred, lost) = deferred.into_iter().partition(|message| {
+					message.refers_to_existing(n_shards, |key| system.node_exists(key))
+				});
+				system.refuse(&lost, &format!("Its node was lost with system {to_system}"));
+				self.send_to_driver(DriverMessage::MoveShardFailed {
+					req_id,
+					shard: self.shard_id as u16,
+					reason,
+				});
+				Some(deferred)
+			}
+		}
+	}
+
+	fn log_mutation(&mut self, mutation: Mutation) {
+		self.other_shard_batching.log_mutation(mutation);
+	}
+
+	/// Times one operation out of `STORAGE_SAMPLE_PERIOD`
+	fn storage<T>(&mut self, op: StorageOp, f: impl FnOnce(&mut S) -> T) -> T {
+		self.n_storage_ops += 1;
+		if !self.n_storage_ops.is_multiple_of(STORAGE_SAMPLE_PERIOD) {
+			return f(&mut self.storage);
+		}
+		let start = Instant::now();
+		let result = f(&mut self.storage);
+		let system = &self.other_shard_batching.system;
+		system.metrics().storage_op(op, start.elapsed());
+		result
+	}
+
+	fn set_parent(&mut self, key: Key, value: Key) {
+		self.storage(StorageOp::SetParent, |storage| {
+			storage.set_parent(key, value)
+		});
+		self.log_mutation(Mutation::SetParent { key, value });
+	}
+
+	/// Walks from `node` to its root, then links the larger of that root and
+	/// `to` under the smaller one, walking from `to` first if it's the larger
+	///
+	/// Every parent is smaller than its children, so parents can't loop, and
+	/// the root of a component is its smallest node. A root is only linked by
+	/// the shard that stores it, while it's still a root, so two unions can't
+	/// link the same root, and a union within a component ends when it finds
+	/// that `to` is its root.
+	fn union(&mut self, node: Key, to: Key, child: Key, req_id: ReqId, hops: u32) {
+		// The new parent of `child`, which is already `node` while it stays a root
+		let parent = match self.storage(StorageOp::GetParent, |storage| storage.get_parent(node)) {
+			Some(parent) => {
+				self.send(ShardMessage::Union {
+					node: parent,
+					to,
+					child: node,
+					req_id,
+					hops: hops + 1,
+				});
+				Some(parent)
+			}
+			None if node > to => {
+				self.counters.union_hops.observe(hops as u64 + 1);
+				self.set_parent(node, to);
+				self.send(ShardMessage::SetChild {
+					node: to,
+					to: node,
+					req_id,
+				});
+				let size = self.storage(StorageOp::GetSize, |storage| storage.get_size(node));
+				self.send(ShardMessage::AddSize {
+					node: to,
+					size,
+					req_id,
+				});
+				Some(to)
+			}
+			None if node < to => {
+				self.send(ShardMessage::SwapUnion {
+					node: to,
+					to: node,
+					req_id,
+					hops: hops + 1,
+				});
+				None
+			}
+			None => {
+				// Already united
+				self.counters.union_hops.observe(hops as u64 + 1);
+				self.send_to_driver(DriverMessage::UnionDone { req_id });
+				None
+			}
+		};
+		// Path compression
+		if let Some(parent) = parent.filter(|_| child != node) {
+			// node is a special value for child that specifies that it's the starting point
+			// of our search so nothing to compress in that case
+			self.send(ShardMessage::SetParent {
+				node: child,
+				to: parent,
+				req_id,
+			});
+		}
+	}
+
+	/// Points `child` to `parent`, the parent of `node` that a find reached
+	/// from `child`
+	fn compress_path(&mut self, child: Key, node: Key, parent: Key, req_id: ReqId) {
+		// node is a special value for child that specifies that it's the starting
+		// point of our search so nothing to compress in that case
+		if child != node {
+			self.send(ShardMessage::SetParent {
+				node: child,
+				to: parent,
+				req_id,
+			});
+		}
+	}
+
+	pub(crate) fn process_message(&mut self, message: ShardMessage) -> Option<Stop> {
+		if !message.is_from_driver() {
+			self.n_received_shard_messages += 1;
+		}
+		self.counters.messages[message.kind()] += 1;
+		let system_id = self.other_shard_batching.system.system_id();
+		let _hop = trace::hop(system_id, self.shard_id, &message);
 		match message {
 			ShardMessage::AddNode { shard, req_id } => {
 				debug_assert!(self.shard_id == shard as usize);
-				let new_node = self.storage.add_node(shard as usize);
+				let new_node = self.storage(StorageOp::AddNode, |storage| {
+					storage.add_node(shard as usize)
+				});
+				self.log_mutation(Mutation::AddNode { shard });
+				// Before the key can come back from another system
+				let system = &self.other_shard_batching.system;
+				system.set_n_nodes(self.shard_id, new_node.shard_specific_id() + 1);
 				self.send_to_driver(DriverMessage::AddNodeDone {
 					req_id,
 					response: new_node,
@@ -99,39 +454,21 @@ impl<S: Storage> UnionFindShardData<S> {
 				to,
 				child,
 				req_id,
-			} => {
-				let parent = match self.storage.get_parent(node) {
-					None => {
-						self.storage.set_parent(node, to);
-						self.send(ShardMessage::SetChild {
-							node: to,
-							to: node,
-							req_id,
-						});
-						to
-					}
-					Some(parent) => {
-						self.send(ShardMessage::Union {
-							node: parent,
-							to,
-							child: node,
-							req_id,
-						});
-						parent
-					}
-				};
-				// Path compression
-				if child != node {
-					// node is a special value for child that specifies that it's the starting point
-					// of our search so nothing to compress in that case
-					self.send(ShardMessage::SetParent {
-						node: child,
-						to: parent,
-					});
-				}
-			}
+				hops,
+			} => self.union(node, to, child, req_id, hops),
+			ShardMessage::SwapUnion {
+				node,
+				to,
+				req_id,
+				hops,
+			} => self.union(node, to, node, req_id, hops),
 			ShardMessage::SetChild { node, to, req_id } => {
-				let prev_child = self.storage.swap_child(node, to);
+				let prev_child =
+					self.storage(StorageOp::SwapChild, |storage| storage.swap_child(node, to));
+				self.log_mutation(Mutation::SetChild {
+					key: node,
+					value: to,
+				});
 				self.send(ShardMessage::SetSibling {
 					node: to,
 					to: prev_child,
@@ -139,19 +476,49 @@ impl<S: Storage> UnionFindShardData<S> {
 				});
 			}
 			ShardMessage::SetSibling { node, to, req_id } => {
-				self.storage.set_sibling(node, to);
+				self.storage(StorageOp::SetSibling, |storage| {
+					storage.set_sibling(node, to)
+				});
+				self.log_mutation(Mutation::SetSibling {
+					key: node,
+					value: to,
+				});
 				self.send_to_driver(DriverMessage::UnionDone { req_id })
 			}
-			ShardMessage::SetParent { node, to } => {
-				self.storage.set_parent(node, to);
+			ShardMessage::SetParent { node, to, .. } => {
+				self.set_parent(node, to);
+			}
+			ShardMessage::AddSize { node, size, req_id } => {
+				match self.storage(StorageOp::GetParent, |storage| storage.get_parent(node)) {
+					// Linked since, the root it was linked under gets the size
+					Some(parent) => self.send(ShardMessage::AddSize {
+						node: parent,
+						size,
+						req_id,
+					}),
+					None => {
+						let root_size =
+							self.storage(StorageOp::GetSize, |storage| storage.get_size(node));
+						// Unknown sizes stay unknown
+						let size = if size == 0 || root_size == 0 {
+							0
+						} else {
+							size + root_size
+						};
+						self.storage(StorageOp::SetSize, |storage| storage.set_size(node, size));
+						self.log_mutation(Mutation::SetSize { key: node, size });
+					}
+				}
 			}
 			ShardMessage::Find {
 				node,
 				child,
 				req_id,
+				hops,
 			} => {
-				match self.storage.get_parent(node) {
+				match self.storage(StorageOp::GetParent, |storage| storage.get_parent(node)) {
 					None => {
+						self.counters.find_hops.observe(hops as u64 + 1);
 						self.send_to_driver(DriverMessage::FindDone {
 							req_id,
 							response: node,
@@ -162,22 +529,102 @@ impl<S: Storage> UnionFindShardData<S> {
 							node: parent,
 							child: node,
 							req_id,
+							hops: hops + 1,
 						});
-						// Path compression
-						if child != node {
-							// node is a special value for child that specifies that it's the
-							// starting point of our search so nothing to compress in that case
-							self.send(ShardMessage::SetParent {
-								node: child,
-								to: parent,
-							});
-						}
+						self.compress_path(child, node, parent, req_id);
+					}
+				};
+			}
+			ShardMessage::FindDetailed {
+				node,
+				child,
+				req_id,
+				hops,
+				mut shards,
+			} => {
+				if !shards.contains(&(self.shard_id as u16)) {
+					shards.push(self.shard_id as u16);
+				}
+				match self.storage(StorageOp::GetParent, |storage| storage.get_parent(node)) {
+					None => {
+						self.counters.find_hops.observe(hops as u64 + 1);
+						let component_size =
+							self.storage(StorageOp::GetSize, |storage| storage.get_size(node));
+						self.send_to_driver(DriverMessage::FindDetailedDone {
+							req_id,
+							response: node,
+							hops,
+							shards,
+							component_size,
+						});
+					}
+					Some(parent) => {
+						self.send(ShardMessage::FindDetailed {
+							node: parent,
+							child: node,
+							req_id,
+							hops: hops + 1,
+							shards,
+						});
+						self.compress_path(child, node, parent, req_id);
 					}
 				};
 			}
 			ShardMessage::GracefulShutdown { shard, req_id } => {
 				debug_assert!(self.shard_id == shard as usize);
-				return Some(req_id);
+				return Some(Stop::Shutdown(req_id));
+			}
+			ShardMessage::Barrier { shard, req_id } => {
+				debug_assert!(self.shard_id == shard as usize);
+				self.send_to_driver(DriverMessage::BarrierAck {
+					req_id,
+					shard,
+					sent: self.n_sent_shard_messages,
+					received: self.n_received_shard_messages,
+				});
+			}
+			ShardMessage::Migrate {
+				shard,
+				to_system,
+				req_id,
+			} => {
+				debug_assert!(self.shard_id == shard as usize);
+				let system = &self.other_shard_batching.system;
+				if to_system == system.system_id() {
+					self.send_to_driver(DriverMessage::MoveShardDone { req_id, shard });
+				} else if system.has_left(to_system) {
+					// Checked before we stop serving the shard, which would be lost
+					self.send_to_driver(DriverMessage::MoveShardFailed {
+						req_id,
+						shard,
+						reason: format!("System {to_system} isn't in the cluster"),
+					});
+				} else {
+					return Some(Stop::Migrate { to_system, req_id });
+				}
+			}
+			ShardMessage::Verify { shard, req_id } => {
+				debug_assert!(self.shard_id == shard as usize);
+				let n_nodes = self.storage.n_nodes();
+				let mut first = 0;
+				loop {
+					let last = (first + verify::CHUNK_LEN).min(n_nodes);
+					let nodes = (first..last)
+						.map(|id| NodeLinks::read(&self.storage, Key::new(self.shard_id, id)))
+						.collect();
+					self.send_to_driver(DriverMessage::VerifyNodes {
+						req_id,
+						shard,
+						n_nodes,
+						nodes,
+					});
+					// One chunk per batch
+					self.other_shard_batching.flush();
+					first = last;
+					if first == n_nodes {
+						break;
+					}
+				}
 			}
 		}
 		None
@@ -186,12 +633,20 @@ impl<S: Storage> UnionFindShardData<S> {
 
 pub(crate) trait ShardAccess: Sync + Send {
 	fn send_messages(&self, batch: Vec<ShardMessage>);
+	/// Batches waiting to be processed, when the shard is local
+	fn queue_len(&self) -> Option<usize> {
+		None
+	}
 }
 
 impl ShardAccess for crossbeam_channel::Sender<Vec<ShardMessage>> {
 	fn send_messages(&self, batch: Vec<ShardMessage>) {
 		self.send(batch).unwrap()
 	}
+
+	fn queue_len(&self) -> Option<usize> {
+		Some(self.len())
+	}
 }
 
 pub(crate) struct RemoteShardAccess {
@@ -201,12 +656,14 @@ pub(crate) struct RemoteShardAccess {
 
 impl ShardAccess for RemoteShardAccess {
 	fn send_messages(&self, batch: Vec<ShardMessage>) {
-		futures::executor::block_on(self.system_channel.clone().send(
+		// If the link is down the batch is lost, but the forwarding task reports
+		// the failure to every driver
+		let _ = futures::executor::block_on(self.system_channel.clone().send(
 			NetworkMessage::ShardMessages {
+				seq: 0,
 				shard_id: self.shard_id,
 				batch,
 			},
-		))
-		.unwrap();
+		));
 	}
 }
@@ -0,0 +1,636 @@
+use std::{
+	collections::HashMap,
+	fs::{File, OpenOptions},
+	path::{Path, PathBuf},
+	ptr::NonNull,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex, Weak,
+	},
+	time::Duration,
+};
+
+use anyhow::{ensure, Context, Result};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender};
+use memmap2::MmapMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+	network_link::{deliver_batch, new_session},
+	network_message::{Codec, NetworkMessage},
+	prelude::*,
+	trace,
+};
+
+/// Bytes of frames a ring holds before its producer waits for the consumer
+const RING_CAPACITY: u64 = 16 << 20;
+const MAGIC: u64 = u64::from_le_bytes(*b"BIGUFSHM");
+
+/// Layout of the ring files: the magic, nonce and capacity, then the head and
+/// tail counters on cache lines of their own, then the data
+const NONCE_OFFSET: usize = 8;
+const CAPACITY_OFFSET: usize = 16;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 128;
+const DATA_OFFSET: usize = 192;
+
+/// The longest a ring waits between two polls
+const MAX_POLL_PERIOD: Duration = Duration::from_millis(1);
+/// A peer on our host accepts a ring as soon as the offer reaches it, one on
+/// another host never does
+const OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A single producer, single consumer byte ring in a file mapped by two
+/// processes of the same host
+///
+/// `head` and `tail` count the bytes written and read since the creation of the
+/// ring. The producer only writes between `head` and `tail + capacity`, the
+/// consumer only reads between `tail` and `head`.
+struct ShmRing {
+	/// Only kept to unmap the file, which is accessed through `base`
+	_map: MmapMut,
+	/// Taken once from the mutable mapping, so that writing through it is
+	/// allowed
+	base: NonNull<u8>,
+	capacity: u64,
+}
+
+impl ShmRing {
+	fn create(path: &Path, nonce: u64, capacity: u64) -> Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(path)?;
+		file.set_len(DATA_OFFSET as u64 + capacity)?;
+		let ring = Self::new(Self::map(&file)?, capacity);
+		ring.header(NONCE_OFFSET).store(nonce, Ordering::Relaxed);
+		ring.header(CAPACITY_OFFSET)
+			.store(capacity, Ordering::Relaxed);
+		// Written last, so that a ring is only opened once initialized
+		ring.header(0).store(MAGIC, Ordering::Release);
+		Ok(ring)
+	}
+
+	/// Opens the ring created by the peer, which proves that we share its host
+	/// if the nonce it announced is there
+	fn open(path: &Path, nonce: u64) -> Result<Self> {
+		let file = OpenOptions::new().read(true).write(true).open(path)?;
+		let len = file.metadata()?.len();
+		ensure!(len > DATA_OFFSET as u64, "Not a shared memory ring");
+		let ring = Self::new(Self::map(&file)?, len - DATA_OFFSET as u64);
+		ensure!(
+			ring.header(0).load(Ordering::Acquire) == MAGIC
+				&& ring.header(CAPACITY_OFFSET).load(Ordering::Relaxed) == ring.capacity,
+			"Not a shared memory ring"
+		);
+		ensure!(
+			ring.header(NONCE_OFFSET).load(Ordering::Relaxed) == nonce,
+			"The ring has another nonce, the peer is on another host"
+		);
+		Ok(ring)
+	}
+
+	fn new(mut map: MmapMut, capacity: u64) -> Self {
+		let base = NonNull::new(map.as_mut_ptr()).expect("A mapping is never null");
+		Self {
+			_map: map,
+			base,
+			capacity,
+		}
+	}
+
+	fn map(file: &File) -> Result<MmapMut> {
+		// SAFETY: the file is only accessed through the atomics of the header and
+		// the parts of the data that the head and tail counters give us
+		Ok(unsafe { MmapMut::map_mut(file)? })
+	}
+
+	fn header(&self, offset: usize) -> &AtomicU64 {
+		// SAFETY: `base` comes from the mutable mapping, which lives as long as
+		// `self`, is page aligned and starts with the header, and the offsets are
+		// multiples of 8 inside it. The header is only accessed through atomics.
+		unsafe { &*(self.base.as_ptr().add(offset) as *const AtomicU64) }
+	}
+
+	fn data(&self) -> *mut u8 {
+		// SAFETY: `base` comes from the mutable mapping, whose data starts right
+		// after the header
+		unsafe { self.base.as_ptr().add(DATA_OFFSET) }
+	}
+
+	/// Writes all of `bytes`, waiting for the consumer to make room, unless
+	/// `closed` is set in the meantime
+	///
+	/// Must only be called by one thread at a time. Fails if the peer corrupted
+	/// the counters.
+	fn write(&self, mut bytes: &[u8], closed: &AtomicBool) -> Result<()> {
+		let (head, tail) = (self.header(HEAD_OFFSET), self.header(TAIL_OFFSET));
+		let mut backoff = Backoff::default();
+		while !bytes.is_empty() {
+			let written = head.load(Ordering::Relaxed);
+			let read = tail.load(Ordering::Acquire);
+			ensure!(
+				read <= written && written - read <= self.capacity,
+				"The ring was read up to {read} but written up to {written}"
+			);
+			let free = self.capacity - (written - read);
+			if free == 0 {
+				if closed.load(Ordering::Relaxed) {
+					return Ok(());
+				}
+				backoff.wait();
+				continue;
+			}
+			backoff = Backoff::default();
+			let n = free.min(bytes.len() as u64) as usize;
+			self.copy(written, n, |data, len| {
+				let (chunk, rest) = bytes.split_at(len);
+				// SAFETY: the consumer doesn't read past `head`, which we only
+				// advance once the bytes are copied
+				unsafe { std::ptr::copy_nonoverlapping(chunk.as_ptr(), data, len) };
+				bytes = rest;
+			});
+			head.store(written + n as u64, Ordering::Release);
+		}
+		Ok(())
+	}
+
+	/// Appends the bytes written since the last read to `out`, and returns how
+	/// many there were
+	///
+	/// Must only be called by one thread at a time. Fails if the peer corrupted
+	/// the counters.
+	fn read(&self, out: &mut BytesMut) -> Result<usize> {
+		let (head, tail) = (self.header(HEAD_OFFSET), self.header(TAIL_OFFSET));
+		let read = tail.load(Ordering::Relaxed);
+		let written = head.load(Ordering::Acquire);
+		ensure!(
+			read <= written && written - read <= self.capacity,
+			"The ring was read up to {read} but written up to {written}"
+		);
+		let n = (written - read) as usize;
+		self.copy(read, n, |data, len| {
+			// SAFETY: the producer doesn't write past `tail`, which we only
+			// advance once the bytes are copied
+			out.extend_from_slice(unsafe { std::slice::from_raw_parts(data, len) });
+		});
+		tail.store(read + n as u64, Ordering::Release);
+		Ok(n)
+	}
+
+	/// Calls `f` on the one or two parts of the data that hold the `n` bytes
+	/// from position `from`
+	fn copy(&self, from: u64, n: usize, mut f: impl FnMut(*mut u8, usize)) {
+		let start = (from % self.capacity) as usize;
+		let first = n.min(self.capacity as usize - start);
+		// SAFETY: `start + first` is at most the capacity
+		f(unsafe { self.data().add(start) }, first);
+		if first < n {
+			f(self.data(), n - first);
+		}
+	}
+}
+
+// SAFETY: `base` points into the mapping owned by the ring, the counters are
+// atomics, and the producer and the consumer never access the same part of the
+// data at the same time
+unsafe impl Send for ShmRing {}
+unsafe impl Sync for ShmRing {}
+
+/// Spins, then yields, then sleeps longer and longer up to `MAX_POLL_PERIOD`
+#[derive(Default)]
+struct Backoff {
+	step: u32,
+}
+
+impl Backoff {
+	fn wait(&mut self) {
+		match self.step {
+			0..=6 => (0..1 << self.step).for_each(|_| std::hint::spin_loop()),
+			7..=16 => std::thread::yield_now(),
+			_ => std::thread::sleep(
+				(Duration::from_micros(10) * (self.step - 16)).min(MAX_POLL_PERIOD),
+			),
+		}
+		self.step += 1;
+	}
+}
+
+/// Hands the batches for a peer to the thread that writes them into the ring
+/// it created, so that a full ring never blocks the shards, drivers and links
+pub(crate) struct ShmSender {
+	batches: Sender<NetworkMessage>,
+	/// Set when the peer is lost, so that no one waits for room in the ring
+	/// forever
+	closed: Arc<AtomicBool>,
+}
+
+impl ShmSender {
+	/// If the ring is closed the batch is lost, but the link reports the failure
+	/// to every driver
+	fn send(&self, message: NetworkMessage) {
+		let _ = self.batches.send(message);
+	}
+
+	fn close(&self) {
+		self.closed.store(true, Ordering::Relaxed);
+	}
+}
+
+/// Writes the batches into `ring` until every `ShmSender` is dropped or closed
+///
+/// A ring whose counters make no sense is given up, and the batches go through
+/// the link again.
+fn spawn_sender(
+	ring: ShmRing,
+	mut codec: Codec,
+	batches: Receiver<NetworkMessage>,
+	closed: Arc<AtomicBool>,
+	system: Weak<System>,
+	peer: u16,
+) {
+	std::thread::spawn(move || {
+		let mut buffer = BytesMut::new();
+		for message in batches {
+			buffer.clear();
+			if codec.encode(message, &mut buffer).is_err() {
+				continue;
+			}
+			if let Err(err) = ring.write(&buffer, &closed) {
+				if let Some(system) = system.upgrade() {
+					system.report_failure(
+						SystemError::PeerDisconnected {
+							peer,
+							reason: format!("Invalid shared memory ring: {err}"),
+						},
+						false,
+					);
+					system.close_shared_memory(peer);
+				}
+				return;
+			}
+			if closed.load(Ordering::Relaxed) {
+				return;
+			}
+		}
+	});
+}
+
+pub(crate) struct ShmShardAccess {
+	pub shard_id: u16,
+	pub sender: Arc<ShmSender>,
+}
+
+impl ShardAccess for ShmShardAccess {
+	fn send_messages(&self, batch: Vec<ShardMessage>) {
+		self.sender.send(NetworkMessage::ShardMessages {
+			seq: 0,
+			shard_id: self.shard_id,
+			batch,
+		});
+	}
+}
+
+pub(crate) struct ShmDriverAccess {
+	pub driver_idx: u16,
+	pub sender: Arc<ShmSender>,
+}
+
+impl DriverAccess for ShmDriverAccess {
+	fn send_messages(&self, batch: Vec<DriverMessage>) {
+		self.sender.send(NetworkMessage::DriverMessages {
+			seq: 0,
+			driver_idx: self.driver_idx,
+			batch,
+		});
+	}
+
+	fn send_failure(&self, error: SystemError) {
+		self.send_messages(vec![DriverMessage::SystemFailure { error }]);
+	}
+}
+
+/// A ring offered to a peer, only read by a thread of its own once the peer has
+/// switched to it
+struct Incoming {
+	nonce: u64,
+	/// Until the peer accepts the offer or it times out
+	offer: Mutex<Option<Offer>>,
+	stopped: AtomicBool,
+}
+
+struct Offer {
+	ring: ShmRing,
+	path: PathBuf,
+	codec: Codec,
+}
+
+impl Incoming {
+	/// Deletes the ring if the peer didn't accept it, returns whether it did
+	fn withdraw(&self) -> bool {
+		match self.offer.lock().unwrap().take() {
+			Some(offer) => {
+				let _ = std::fs::remove_file(&offer.path);
+				false
+			}
+			None => true,
+		}
+	}
+
+	fn stop(&self) {
+		self.withdraw();
+		self.stopped.store(true, Ordering::Relaxed);
+	}
+}
+
+#[derive(Default)]
+struct Rings {
+	incoming: Option<Arc<Incoming>>,
+	outgoing: Option<Arc<ShmSender>>,
+}
+
+/// The rings shared with the peers on the same host, by system id
+#[derive(Default)]
+pub(crate) struct SharedMemory {
+	peers: Mutex<HashMap<u16, Rings>>,
+}
+
+impl SharedMemory {
+	/// Creates a ring for `peer` to write into, and returns the message
+	/// announcing it
+	///
+	/// The ring created before for `peer`, if any, is closed. The ring is deleted
+	/// if `peer` doesn't accept it within `OFFER_TIMEOUT`. Must be called from
+	/// the runtime of the links.
+	pub fn offer(
+		&self,
+		system: &Arc<System>,
+		peer: u16,
+		dir: &Path,
+		codec: Codec,
+	) -> Result<NetworkMessage> {
+		let nonce = new_session();
+		let path = dir.join(format!(
+			"big_uf-{:x}-{}-from-{peer}-{nonce:x}",
+			system.session(),
+			system.system_id()
+		));
+		let ring = ShmRing::create(&path, nonce, RING_CAPACITY)
+			.with_context(|| format!("Can't create the ring {}", path.display()))?;
+		let incoming = Arc::new(Incoming {
+			nonce,
+			offer: Mutex::new(Some(Offer {
+				ring,
+				path: path.clone(),
+				codec,
+			})),
+			stopped: AtomicBool::new(false),
+		});
+		tokio::spawn({
+			let incoming = incoming.clone();
+			async move {
+				tokio::time::sleep(OFFER_TIMEOUT).await;
+				incoming.withdraw();
+			}
+		});
+		let previous = self
+			.peers
+			.lock()
+			.unwrap()
+			.entry(peer)
+			.or_default()
+			.incoming
+			.replace(incoming);
+		if let Some(previous) = previous {
+			previous.stop();
+		}
+		Ok(NetworkMessage::SharedMemory {
+			seq: 0,
+			path: path.to_string_lossy().into_owned(),
+			nonce,
+		})
+	}
+
+	/// Opens the ring announced by `peer`, and returns whether it can be written
+	/// into, which isn't the case when the peer is on another host
+	pub fn accept(
+		&self,
+		system: &Arc<System>,
+		peer: u16,
+		dir: &Path,
+		path: &str,
+		nonce: u64,
+		codec: Codec,
+	) -> bool {
+		let path = PathBuf::from(path);
+		// A peer can't make us map any other file
+		if path.parent() != Some(dir) {
+			return false;
+		}
+		let Ok(ring) = ShmRing::open(&path, nonce) else {
+			return false;
+		};
+		let (batches, receiver) = crossbeam_channel::unbounded();
+		let closed = Arc::new(AtomicBool::new(false));
+		spawn_sender(
+			ring,
+			codec,
+			receiver,
+			closed.clone(),
+			Arc::downgrade(system),
+			peer,
+		);
+		let sender = Arc::new(ShmSender { batches, closed });
+		let previous = self
+			.peers
+			.lock()
+			.unwrap()
+			.entry(peer)
+			.or_default()
+			.outgoing
+			.replace(sender);
+		if let Some(previous) = previous {
+			previous.close();
+		}
+		true
+	}
+
+	/// Starts the thread reading the ring of `peer`, now that all the batches
+	/// `peer` sent through the link before switching to it arrived
+	///
+	/// Fails if the offer timed out: nothing would read what `peer` writes.
+	pub fn start(&self, system: &Arc<System>, peer: u16, nonce: u64) -> Result<()> {
+		let peers = self.peers.lock().unwrap();
+		let Some(incoming) = peers.get(&peer).and_then(|rings| rings.incoming.as_ref()) else {
+			return Ok(());
+		};
+		if incoming.nonce != nonce {
+			return Ok(());
+		}
+		let offer = incoming.offer.lock().unwrap().take();
+		let offer = offer.with_context(|| {
+			format!("System {peer} accepted the ring offered to it after it timed out")
+		})?;
+		spawn_receiver(offer, system.clone(), peer, incoming.clone());
+		Ok(())
+	}
+
+	pub fn sender(&self, peer: u16) -> Option<Arc<ShmSender>> {
+		let peers = self.peers.lock().unwrap();
+		peers.get(&peer)?.outgoing.clone()
+	}
+
+	/// Stops using the rings shared with `peer`, returns whether there were some
+	pub fn close(&self, peer: u16) -> bool {
+		let Some(rings) = self.peers.lock().unwrap().remove(&peer) else {
+			return false;
+		};
+		if let Some(incoming) = rings.incoming {
+			incoming.stop();
+		}
+		if let Some(sender) = rings.outgoing {
+			sender.close();
+		}
+		true
+	}
+}
+
+/// Hands the batches written into the ring of `offer` to the shards and
+/// drivers, until it's stopped, then deletes the ring
+fn spawn_receiver(offer: Offer, system: Arc<System>, peer: u16, incoming: Arc<Incoming>) {
+	let Offer {
+		ring,
+		path,
+		mut codec,
+	} = offer;
+	std::thread::spawn(move || {
+		let mut buffer = BytesMut::new();
+		let mut backoff = Backoff::default();
+		let link_metrics = system.metrics().link(peer);
+		let error = 'receive: loop {
+			if incoming.stopped.load(Ordering::Relaxed) {
+				break None;
+			}
+			match ring.read(&mut buffer) {
+				Ok(0) => {
+					backoff.wait();
+					continue;
+				}
+				Ok(_) => (),
+				Err(err) => break Some(err),
+			}
+			backoff = Backoff::default();
+			loop {
+				match codec.decode(&mut buffer) {
+					Ok(Some(message)) => {
+						// Dropped, as on the link
+						if let Err(err) = deliver_batch(&system, peer, message) {
+							link_metrics.refused.fetch_add(1, Ordering::Relaxed);
+							trace::refused(peer, &err);
+						}
+					}
+					Ok(None) => break,
+					Err(err) => break 'receive Some(err),
+				}
+			}
+		};
+		let _ = std::fs::remove_file(&path);
+		if let Some(err) = error {
+			system.report_failure(
+				SystemError::PeerDisconnected {
+					peer,
+					reason: format!("Invalid shared memory batch: {err}"),
+				},
+				false,
+			);
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		network_config::NetworkConfig,
+		network_message::{features, negotiate_protocol},
+	};
+
+	fn rings(capacity: u64) -> (tempfile::TempDir, ShmRing, ShmRing) {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("ring");
+		let producer = ShmRing::create(&path, 7, capacity).unwrap();
+		let consumer = ShmRing::open(&path, 7).unwrap();
+		(dir, producer, consumer)
+	}
+
+	#[test]
+	fn bytes_wrap_around() {
+		let (_dir, producer, consumer) = rings(64);
+		let closed = AtomicBool::new(false);
+		let mut out = BytesMut::new();
+		for round in 0..5u8 {
+			let bytes: Vec<u8> = (0..40).map(|i| round * 40 + i).collect();
+			producer.write(&bytes, &closed).unwrap();
+			assert_eq!(consumer.read(&mut out).unwrap(), 40);
+			assert_eq!(out.split(), bytes);
+		}
+		assert_eq!(consumer.read(&mut out).unwrap(), 0);
+	}
+
+	/// The producer only writes what fits once the ring is closed
+	#[test]
+	fn closed_rings_stop_waiting() {
+		let (_dir, producer, consumer) = rings(64);
+		producer.write(&[1; 100], &AtomicBool::new(true)).unwrap();
+		let mut out = BytesMut::new();
+		assert_eq!(consumer.read(&mut out).unwrap(), 64);
+	}
+
+	#[test]
+	fn corrupted_counters_fail() {
+		let (_dir, producer, consumer) = rings(64);
+		let closed = AtomicBool::new(false);
+		producer.header(HEAD_OFFSET).store(65, Ordering::Relaxed);
+		assert!(consumer.read(&mut BytesMut::new()).is_err());
+		producer.header(HEAD_OFFSET).store(0, Ordering::Relaxed);
+		consumer.header(TAIL_OFFSET).store(1, Ordering::Relaxed);
+		assert!(producer.write(&[1], &closed).is_err());
+	}
+
+	#[test]
+	fn other_nonces_are_refused() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("ring");
+		let _producer = ShmRing::create(&path, 7, 64).unwrap();
+		assert!(ShmRing::open(&path, 8).is_err());
+	}
+
+	/// An offer that times out leaves no ring behind, and can't be accepted
+	#[tokio::test]
+	async fn withdrawn_offers_are_deleted() {
+		let (mut ours, mut theirs) = tokio::io::duplex(64);
+		let (protocol, _) = tokio::join!(
+			negotiate_protocol(&mut ours, features::SUPPORTED),
+			negotiate_protocol(&mut theirs, features::SUPPORTED)
+		);
+		let codec = NetworkConfig::default().shared_memory_codec(protocol.unwrap());
+		let dir = tempfile::tempdir().unwrap();
+		let (system, _shards, _answers) = System::unthreaded(1);
+		let shared_memory = SharedMemory::default();
+		let offer = shared_memory.offer(&system, 1, dir.path(), codec).unwrap();
+		let NetworkMessage::SharedMemory { path, nonce, .. } = offer else {
+			panic!("Expected an offer, got {offer:?}");
+		};
+		assert!(Path::new(&path).exists());
+
+		let incoming = shared_memory.peers.lock().unwrap()[&1]
+			.incoming
+			.clone()
+			.unwrap();
+		assert!(!incoming.withdraw());
+		assert!(!Path::new(&path).exists());
+		assert!(shared_memory.start(&system, 1, nonce).is_err());
+	}
+}
@@ -0,0 +1,284 @@
+//! Deterministic simulation of the shard protocol
+//!
+//! A `Simulation` runs the message handlers of every shard on the calling
+//! thread, and a scheduler seeded by the caller picks the message in flight
+//! that is delivered next. It can also reorder the messages between two shards,
+//! delay them and duplicate the requests. The same seed and requests replay the
+//! same run step by step, so that an interleaving found by exploring seeds can
+//! be reproduced and its `history` read.
+
+use anyhow::{bail, Result};
+
+use crate::{
+	prelude::*,
+	shard::UnionFindShardData,
+	storage::ram::RamStorage,
+	verify::{ForestCheck, Report},
+};
+
+/// Steps after which `run` gives up by default, as the requests of a broken
+/// protocol may never be answered
+const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+/// xorshift64, seeded so that failures can be replayed
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: u64) -> Self {
+		// Zero is a fixed point
+		Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	pub fn below(&mut self, n: usize) -> usize {
+		(self.next_u64() % n as u64) as usize
+	}
+
+	pub fn happens(&mut self, probability: f64) -> bool {
+		probability > 0. && (self.next_u64() as f64) < probability * u64::MAX as f64
+	}
+}
+
+struct InFlight {
+	/// The shard that sent it, none for the driver
+	from: Option<usize>,
+	message: ShardMessage,
+	/// Delayed until that step
+	not_before: u64,
+}
+
+pub struct Simulation {
+	shards: Vec<UnionFindShardData<RamStorage>>,
+	/// What the shards send each other through their `MessageBatching`
+	receivers: Vec<crossbeam_channel::Receiver<Vec<ShardMessage>>>,
+	answers: crossbeam_channel::Receiver<Vec<DriverMessage>>,
+	/// In the order they were sent
+	in_flight: Vec<InFlight>,
+	received: Vec<DriverMessage>,
+	rng: Rng,
+	step: u64,
+	next_req_id: u64,
+	history: Vec<String>,
+	reordering: bool,
+	delay_probability: f64,
+	max_delay: u64,
+	duplicate_probability: f64,
+	max_steps: u64,
+}
+
+impl Simulation {
+	/// Shards on `RamStorage`, without reordering, delays nor duplicates
+	pub fn new(n_shards: u16, seed: u64) -> Self {
+		let (system, receivers, answers) = System::unthreaded(n_shards);
+		let shards = receivers
+			.iter()
+			.map(|&(shard_id, _)| {
+				UnionFindShardData::new(
+					RamStorage::default(),
+					system.clone(),
+					shard_id,
+					Vec::new(),
+					(0, 0),
+				)
+			})
+			.collect();
+		Self {
+			shards,
+			receivers: receivers
+				.into_iter()
+				.map(|(_, receiver)| receiver)
+				.collect(),
+			answers,
+			in_flight: Vec::new(),
+			received: Vec::new(),
+			rng: Rng::new(seed),
+			step: 0,
+			next_req_id: 0,
+			history: Vec::new(),
+			reordering: false,
+			delay_probability: 0.,
+			max_delay: 0,
+			duplicate_probability: 0.,
+			max_steps: DEFAULT_MAX_STEPS,
+		}
+	}
+
+	/// Delivers the messages from a shard or the driver to another shard in any
+	/// order, where the system keeps the order they were sent in
+	pub fn with_reordering(mut self, reordering: bool) -> Self {
+		self.reordering = reordering;
+		self
+	}
+
+	/// Holds each message back for up to `max_steps` with that probability,
+	/// along with the messages sent after it on the same route unless reordering
+	pub fn with_delays(mut self, probability: f64, max_steps: u64) -> Self {
+		self.delay_probability = probability;
+		self.max_delay = max_steps;
+		self
+	}
+
+	/// Sends each request twice with that probability, as a driver retrying it
+	/// would: it's answered twice, and a node is added twice
+	///
+	/// Only requests are duplicated, as the links of the system never deliver
+	/// a message between shards twice.
+	pub fn with_duplicates(mut self, probability: f64) -> Self {
+		self.duplicate_probability = probability;
+		self
+	}
+
+	/// After which `run` fails with the messages still in flight
+	pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+		self.max_steps = max_steps;
+		self
+	}
+
+	fn send(&mut self, from: Option<usize>, message: ShardMessage) {
+		let copies = if from.is_none() && self.rng.happens(self.duplicate_probability) {
+			2
+		} else {
+			1
+		};
+		for _ in 0..copies {
+			let not_before = if self.rng.happens(self.delay_probability) {
+				self.step + 1 + self.rng.next_u64() % self.max_delay.max(1)
+			} else {
+				self.step
+			};
+			self.in_flight.push(InFlight {
+				from,
+				message: message.clone(),
+				not_before,
+			});
+		}
+	}
+
+	fn send_request(&mut self, message: impl FnOnce(ReqId) -> ShardMessage) -> u64 {
+		let id = self.next_req_id;
+		self.next_req_id += 1;
+		self.send(None, message(ReqId::new(0, id)));
+		id
+	}
+
+	/// Returns the id of the request, answered with `AddNodeDone`
+	pub fn add_node(&mut self, shard: u16) -> u64 {
+		self.send_request(|req_id| ShardMessage::AddNode { shard, req_id })
+	}
+
+	/// Returns the id of the request, answered with `UnionDone`
+	pub fn union(&mut self, node: Key, to: Key) -> u64 {
+		self.send_request(|req_id| ShardMessage::Union {
+			node,
+			to,
+			child: node,
+			req_id,
+			hops: 0,
+		})
+	}
+
+	/// Returns the id of the request, answered with `FindDone`
+	pub fn find(&mut self, node: Key) -> u64 {
+		self.send_request(|req_id| ShardMessage::Find {
+			node,
+			child: node,
+			req_id,
+			hops: 0,
+		})
+	}
+
+	/// The messages that can be delivered now: the first one of each route that
+	/// isn't delayed, or all those that aren't delayed when reordering
+	fn deliverable(&self) -> Vec<usize> {
+		let mut routes = Vec::new();
+		let mut deliverable = Vec::new();
+		for (i, in_flight) in self.in_flight.iter().enumerate() {
+			if !self.reordering {
+				let route = (in_flight.from, in_flight.message.target_shard());
+				if routes.contains(&route) {
+					continue;
+				}
+				routes.push(route);
+			}
+			if in_flight.not_before <= self.step {
+				deliverable.push(i);
+			}
+		}
+		deliverable
+	}
+
+	/// Delivers one message, returns false when none is in flight
+	pub fn step(&mut self) -> bool {
+		if self.in_flight.is_empty() {
+			return false;
+		}
+		let deliverable = loop {
+			let deliverable = self.deliverable();
+			if !deliverable.is_empty() {
+				break deliverable;
+			}
+			// Everything is delayed
+			self.step += 1;
+		};
+		let chosen = deliverable[self.rng.below(deliverable.len())];
+		let InFlight { message, .. } = self.in_flight.remove(chosen);
+		let shard_id = message.target_shard();
+		self.history
+			.push(format!("{}: shard {shard_id} <- {message:?}", self.step));
+		self.step += 1;
+
+		let shard = &mut self.shards[shard_id];
+		let _ = shard.process_message(message);
+		shard.flush();
+		let sent: Vec<ShardMessage> = shard
+			.take_pending_messages()
+			.into_iter()
+			.chain(
+				self.receivers
+					.iter()
+					.flat_map(|receiver| receiver.try_iter().flatten()),
+			)
+			.collect();
+		for message in sent {
+			self.send(Some(shard_id), message);
+		}
+		self.received.extend(self.answers.try_iter().flatten());
+		true
+	}
+
+	/// Delivers messages until none is in flight, and returns the answers
+	/// received since the last call
+	pub fn run(&mut self) -> Result<Vec<DriverMessage>> {
+		let start = self.step;
+		while self.step() {
+			if self.step - start > self.max_steps {
+				bail!(
+					"{} messages still in flight after {} steps",
+					self.in_flight.len(),
+					self.max_steps
+				);
+			}
+		}
+		Ok(std::mem::take(&mut self.received))
+	}
+
+	/// Checks the structure stored by the shards, see `ForestCheck`
+	pub fn verify(&self) -> Report {
+		let mut forest = ForestCheck::new(self.shards.len());
+		for (shard_id, shard) in self.shards.iter().enumerate() {
+			forest.add_storage(shard_id, shard.storage_ref());
+		}
+		forest.check()
+	}
+
+	/// Every message delivered, with its step and shard
+	pub fn history(&self) -> &[String] {
+		&self.history
+	}
+}
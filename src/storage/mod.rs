@@ -1,6 +1,10 @@
 pub mod ram;
 pub mod rocksdb;
 
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
 use crate::prelude::*;
 
 pub trait Storage {
@@ -12,5 +16,93 @@ pub trait Storage {
 	fn get_sibling(&self, key: Key) -> Option<Key>;
 	fn get_child(&self, key: Key) -> Option<Key>;
 
+	/// The number of nodes in the component of a root, 1 for a new node, kept
+	/// by roots only
+	///
+	/// 0 when unknown, for the nodes of a database written before sizes were
+	/// kept, which stays unknown for the roots they are linked under, and for
+	/// every node of a storage that doesn't keep sizes.
+	fn get_size(&self, key: Key) -> u64 {
+		let _ = key;
+		0
+	}
+	fn set_size(&mut self, key: Key, size: u64) {
+		let _ = (key, size);
+	}
+
 	fn add_node(&mut self, shard: usize) -> Key;
+	/// Nodes are numbered contiguously from 0, so this is also the id of the
+	/// next node
+	fn n_nodes(&self) -> u64;
+
+	/// Writes what the storage buffers in memory to disk
+	fn flush(&mut self) -> Result<()> {
+		Ok(())
+	}
+	/// Copies the storage into `dir`, which must not exist, as it is now
+	fn checkpoint(&mut self, dir: &Path) -> Result<()> {
+		let _ = dir;
+		bail!("This storage can't be checkpointed")
+	}
+	/// Removes every node, e.g. the ones left from when this system served a
+	/// shard that moves back to it
+	fn clear(&mut self) -> Result<()> {
+		bail!("This storage can't be cleared")
+	}
+}
+
+/// The storage of the shards moved to a worker, whose type is only known when
+/// the worker starts
+impl<S: Storage + ?Sized> Storage for Box<S> {
+	fn set_parent(&mut self, key: Key, value: Key) {
+		(**self).set_parent(key, value)
+	}
+
+	fn set_sibling(&mut self, key: Key, value: Key) {
+		(**self).set_sibling(key, value)
+	}
+
+	fn swap_child(&mut self, key: Key, value: Key) -> Key {
+		(**self).swap_child(key, value)
+	}
+
+	fn get_parent(&self, key: Key) -> Option<Key> {
+		(**self).get_parent(key)
+	}
+
+	fn get_sibling(&self, key: Key) -> Option<Key> {
+		(**self).get_sibling(key)
+	}
+
+	fn get_child(&self, key: Key) -> Option<Key> {
+		(**self).get_child(key)
+	}
+
+	fn get_size(&self, key: Key) -> u64 {
+		(**self).get_size(key)
+	}
+
+	fn set_size(&mut self, key: Key, size: u64) {
+		(**self).set_size(key, size)
+	}
+
+	fn add_node(&mut self, shard: usize) -> Key {
+		(**self).add_node(shard)
+	}
+
+	fn n_nodes(&self) -> u64 {
+		(**self).n_nodes()
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		(**self).flush()
+	}
+
+	fn checkpoint(&mut self, dir: &Path) -> Result<()> {
+		(**self).checkpoint(dir)
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		(**self).clear()
+	}
 }
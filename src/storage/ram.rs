@@ -1,9 +1,12 @@
+use anyhow::Result;
+
 use crate::prelude::*;
 
 struct NodeData {
 	parent: Key,
 	sibling: Key,
 	child: Key,
+	size: u64,
 }
 
 #[derive(Default)]
@@ -56,6 +59,14 @@ impl Storage for RamStorage {
 		self.get(key, |x| x.child)
 	}
 
+	fn get_size(&self, key: Key) -> u64 {
+		self.store[key.shard_specific_id() as usize].size
+	}
+
+	fn set_size(&mut self, key: Key, size: u64) {
+		self.store[key.shard_specific_id() as usize].size = size;
+	}
+
 	fn add_node(&mut self, shard: usize) -> Key {
 		let shard_specific_id = self.store.len();
 		let key = Key::new(shard, shard_specific_id as u64);
@@ -64,7 +75,17 @@ impl Storage for RamStorage {
 			parent: key,
 			sibling: key,
 			child: key,
+			size: 1,
 		});
 		key
 	}
+
+	fn n_nodes(&self) -> u64 {
+		self.store.len() as u64
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		self.store.clear();
+		Ok(())
+	}
 }
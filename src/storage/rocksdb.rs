@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use anyhow::Result;
 use rocksdb::WriteBatchWithTransaction;
 
 use {crate::prelude::*, rocksdb::Options};
@@ -11,23 +14,89 @@ pub struct RocksDbStorage {
 	child: &'this rocksdb::ColumnFamily,
 	#[borrows(store)]
 	sibling: &'this rocksdb::ColumnFamily,
+	/// Holds the sizes set since the node was added, the others are 1 or unknown
+	/// as `get_size` tells, none when a database written before sizes were kept
+	/// is opened read-only
+	#[borrows(store)]
+	#[covariant]
+	size: Option<&'this rocksdb::ColumnFamily>,
 	len: u64,
+	/// Nodes added before sizes were kept, whose sizes are unknown unless
+	/// stored since
+	legacy_len: u64,
 }
 
+const COLUMN_FAMILIES: [&str; 4] = ["parent", "child", "sibling", "size"];
+/// Where the size column family keeps `legacy_len`, as no key is all ones
+const LEGACY_LEN_KEY: [u8; 8] = [u8::MAX; 8];
+
 impl RocksDbStorage {
+	/// Reopens the database if it already exists, so that a restarted worker
+	/// keeps its nodes
+	///
+	/// The sizes of the nodes of a database written before sizes were kept are
+	/// unknown, see `Storage::get_size`.
 	pub fn from_path(path: impl AsRef<std::path::Path>) -> Self {
+		let existing = rocksdb::DB::list_cf(&Options::default(), &path).unwrap_or_default();
 		let options = &mut Options::default();
 		options.create_if_missing(true);
-		let mut db = rocksdb::DB::open(options, path).expect("Failed to open RocksDB database");
-		for name in ["parent", "child", "sibling"] {
-			db.create_cf(name, &Options::default()).unwrap();
+		options.create_missing_column_families(true);
+		let db = rocksdb::DB::open_cf(options, path, COLUMN_FAMILIES)
+			.expect("Failed to open RocksDB database");
+		let mut storage = Self::with_db(db);
+		if !existing.is_empty() && !existing.iter().any(|name| name == "size") {
+			let len = *storage.borrow_len();
+			storage
+				.borrow_store()
+				.put_cf(
+					storage.borrow_size().unwrap(),
+					LEGACY_LEN_KEY,
+					len.to_le_bytes(),
+				)
+				.expect("Failed to write to RocksDB database");
+			storage.with_legacy_len_mut(|legacy_len| *legacy_len = len);
 		}
+		storage
+	}
+
+	/// Opens an existing database without writing to it, which works while its
+	/// worker runs too
+	pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+		let existing = rocksdb::DB::list_cf(&Options::default(), &path)?;
+		let db = rocksdb::DB::open_cf_for_read_only(
+			&Options::default(),
+			&path,
+			COLUMN_FAMILIES
+				.into_iter()
+				.filter(|name| existing.iter().any(|existing| existing == name)),
+			false,
+		)?;
+		Ok(Self::with_db(db))
+	}
+
+	fn with_db(db: rocksdb::DB) -> Self {
+		// Nodes are numbered contiguously from 0
+		let len = db
+			.iterator_cf(
+				db.cf_handle("parent").unwrap(),
+				rocksdb::IteratorMode::Start,
+			)
+			.count() as u64;
+		let legacy_len = match db.cf_handle("size") {
+			Some(cf) => db
+				.get_pinned_cf(cf, LEGACY_LEN_KEY)
+				.unwrap()
+				.map_or(0, |bytes| u64::from_le_bytes((&*bytes).try_into().unwrap())),
+			None => len,
+		};
 		Self::new(
 			db,
 			|db| db.cf_handle("parent").unwrap(),
 			|db| db.cf_handle("child").unwrap(),
 			|db| db.cf_handle("sibling").unwrap(),
-			0,
+			|db| db.cf_handle("size"),
+			len,
+			legacy_len,
 		)
 	}
 }
@@ -66,7 +135,8 @@ impl Storage for RocksDbStorage {
 	}
 
 	fn swap_child(&mut self, key: Key, value: Key) -> Key {
-		let old = self.get_child(key).unwrap();
+		// A node without child points to itself, as `RamStorage` returns it
+		let old = self.get_child(key).unwrap_or(key);
 		self.set(key, self.borrow_child(), value);
 		old
 	}
@@ -83,9 +153,31 @@ impl Storage for RocksDbStorage {
 		self.get(key, self.borrow_child())
 	}
 
+	fn get_size(&self, key: Key) -> u64 {
+		let size = self.borrow_size().and_then(|cf| {
+			self.borrow_store()
+				.get_pinned_cf(cf, key.inner.to_le_bytes())
+				.unwrap()
+		});
+		match size {
+			Some(bytes) => u64::from_le_bytes((&*bytes).try_into().unwrap()),
+			None if key.shard_specific_id() < *self.borrow_legacy_len() => 0,
+			None => 1,
+		}
+	}
+
+	fn set_size(&mut self, key: Key, size: u64) {
+		let cf = self
+			.borrow_size()
+			.expect("The database was opened read-only");
+		self.borrow_store()
+			.put_cf(cf, key.inner.to_le_bytes(), size.to_le_bytes())
+			.unwrap();
+	}
+
 	fn add_node(&mut self, shard: usize) -> Key {
 		let shard_specific_id = *self.borrow_len();
-		let key = Key::new(shard, shard_specific_id as u64);
+		let key = Key::new(shard, shard_specific_id);
 		self.with_len_mut(|l| *l += 1);
 
 		let key_repr = key.inner.to_le_bytes();
@@ -98,4 +190,50 @@ impl Storage for RocksDbStorage {
 		self.borrow_store().write(batch).unwrap();
 		key
 	}
+
+	fn n_nodes(&self) -> u64 {
+		*self.borrow_len()
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		for cf in [
+			self.borrow_parent(),
+			self.borrow_child(),
+			self.borrow_sibling(),
+		]
+		.into_iter()
+		.chain(self.borrow_size().as_ref())
+		{
+			self.borrow_store().flush_cf(cf)?;
+		}
+		Ok(())
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		for cf in [
+			self.borrow_parent(),
+			self.borrow_child(),
+			self.borrow_sibling(),
+		]
+		.into_iter()
+		.chain(self.borrow_size().as_ref())
+		{
+			// Keys are little endian, and none is all ones
+			self.borrow_store()
+				.delete_range_cf(cf, [0u8; 8], [u8::MAX; 8])?;
+		}
+		if let Some(cf) = *self.borrow_size() {
+			self.borrow_store().delete_cf(cf, LEGACY_LEN_KEY)?;
+		}
+		self.with_len_mut(|len| *len = 0);
+		self.with_legacy_len_mut(|legacy_len| *legacy_len = 0);
+		Ok(())
+	}
+
+	/// The checkpoint is a database that `from_path` opens, made of hard links
+	/// to the files of this one when they are on the same filesystem
+	fn checkpoint(&mut self, dir: &Path) -> Result<()> {
+		rocksdb::checkpoint::Checkpoint::new(self.borrow_store())?.create_checkpoint(dir)?;
+		Ok(())
+	}
 }
@@ -1,22 +1,171 @@
-use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, RwLock,
+	},
+	time::Instant,
+};
 
 use crate::{
+	address::Address,
+	admin,
+	batch_pool::{BatchPool, MAX_POOLED_BYTES},
 	driver::RemoteDriverAccess,
-	network_message::{Codec, NetworkMessage},
+	http::{self, Response},
+	metrics::Metrics,
+	network_config::NetworkConfig,
+	network_link::{
+		accept_connections, dial, handle_network_forwarding, new_session, Reconnect, Socket,
+	},
+	network_message::{features, NetworkMessage, Protocol},
 	prelude::*,
-	shard::RemoteShardAccess,
+	replication::{Mutation, Replication},
+	shard::{Command, RemoteShardAccess},
+	shared_memory::{SharedMemory, ShmDriverAccess, ShmShardAccess},
+	storage::ram::RamStorage,
+	transport::{Listener, SocketTransport, Transport},
 };
-use anyhow::{anyhow, bail, Result};
-use futures::{stream::FuturesUnordered, Future, FutureExt, SinkExt, StreamExt, TryStreamExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::Framed;
+use anyhow::{anyhow, bail, ensure, Result};
+use arc_swap::{ArcSwap, Guard};
+use futures::{stream::FuturesUnordered, Future, SinkExt, Stream, StreamExt};
+
+type PeerSender = futures::channel::mpsc::UnboundedSender<NetworkMessage>;
+type PeerReceiver = futures::channel::mpsc::UnboundedReceiver<NetworkMessage>;
+type Link = tokio::task::JoinHandle<Result<()>>;
+type LocalShardReceivers = Vec<(usize, crossbeam_channel::Receiver<Vec<ShardMessage>>)>;
+/// A connection to a peer, before its link is added
+type NewLink = (u16, (Socket, u64), Reconnect);
+/// Makes the storage of a shard from its id, on the thread of the shard
+type StorageFactory =
+	Box<dyn Fn(usize) -> Box<dyn FnOnce() -> Box<dyn Storage> + Send> + Send + Sync>;
 
 pub struct System {
-	drivers: Vec<Box<dyn DriverAccess>>,
-	shards: Vec<Box<dyn ShardAccess>>,
+	/// Updated when the driver can be reached through shared memory
+	drivers: Vec<ArcSwap<Box<dyn DriverAccess>>>,
+	/// Updated when a shard moves to another system, read without locking by
+	/// every batch sent
+	shards: Vec<ArcSwap<Box<dyn ShardAccess>>>,
+	/// Emptied shard batches, reused by the senders
+	shard_batches: BatchPool<ShardMessage>,
+	/// System serving each shard
+	owners: RwLock<Vec<u16>>,
+	self_id: u16,
+	session: u64,
+	health: Mutex<ClusterHealth>,
+	/// Links to the other systems by system id, `None` for ourselves and for the
+	/// systems that left
+	peers: RwLock<Vec<Option<PeerSender>>>,
+	/// Where the workers listen, by system id. Only the master knows them, to
+	/// introduce new workers to the others.
+	addresses: Mutex<Vec<Option<Address>>>,
+	config: NetworkConfig,
+	/// The forwarding tasks of the links, awaited by `run_links`, closed when
+	/// we leave the system
+	links: futures::channel::mpsc::UnboundedSender<Link>,
+	replication: Option<Replication>,
+	/// Copies of the shards of other systems, by shard id
+	replicas: Mutex<HashMap<u16, RamStorage>>,
+	shared_memory: SharedMemory,
+	metrics: Metrics,
+	started: Instant,
+	/// The admin endpoint reaches the local shards through these, by shard id
+	shard_commands: Mutex<HashMap<usize, crossbeam_channel::Sender<Command>>>,
+	/// For the shards moved to this system
+	new_storage: StorageFactory,
+	/// Nodes of each shard served here, set by the shard before it answers the
+	/// key of a new node. `u64::MAX` for the others, only their system knows.
+	n_nodes: Vec<AtomicU64>,
 }
 
 impl System {
+	/// Routes each shard to the system that serves it: ours get a channel, whose
+	/// receivers are returned with their shard id
+	fn new(
+		self_id: u16,
+		session: u64,
+		drivers: Vec<Box<dyn DriverAccess>>,
+		owners: Vec<u16>,
+		peers: Vec<Option<PeerSender>>,
+		replication: Option<Replication>,
+		links: futures::channel::mpsc::UnboundedSender<Link>,
+	) -> Result<(Self, LocalShardReceivers)> {
+		let mut local_receivers = Vec::new();
+		let mut shards = Vec::new();
+		for (&owner, shard_id) in owners.iter().zip(0..) {
+			let access = if owner == self_id {
+				// Shards never wait on each other, which could deadlock: the credits
+				// of the drivers bound what these channels hold
+				let (s, r) = crossbeam_channel::unbounded::<Vec<ShardMessage>>();
+				local_receivers.push((shard_id as usize, r));
+				Box::new(s) as Box<dyn ShardAccess>
+			} else {
+				let system_channel =
+					peers
+						.get(owner as usize)
+						.cloned()
+						.flatten()
+						.ok_or_else(|| {
+							anyhow!("Shard {shard_id} is served by unknown system {owner}")
+						})?;
+				Box::new(RemoteShardAccess {
+					shard_id,
+					system_channel,
+				})
+			};
+			shards.push(ArcSwap::from_pointee(access));
+		}
+		let system = Self {
+			n_nodes: shards.iter().map(|_| AtomicU64::new(u64::MAX)).collect(),
+			drivers: drivers.into_iter().map(ArcSwap::from_pointee).collect(),
+			// Enough for every shard to have a few batches in flight
+			shard_batches: BatchPool::new(4 * shards.len(), MAX_POOLED_BYTES),
+			shards,
+			owners: RwLock::new(owners),
+			self_id,
+			session,
+			health: Mutex::new(ClusterHealth::Healthy),
+			peers: RwLock::new(peers),
+			addresses: Mutex::new(Vec::new()),
+			config: NetworkConfig::default(),
+			links,
+			replication,
+			replicas: Mutex::new(HashMap::new()),
+			shared_memory: SharedMemory::default(),
+			metrics: Metrics::default(),
+			started: Instant::now(),
+			shard_commands: Mutex::new(HashMap::new()),
+			new_storage: Box::new(|_shard_id| {
+				Box::new(|| Box::new(RamStorage::default()) as Box<dyn Storage>)
+			}),
+		};
+		Ok((system, local_receivers))
+	}
+
+	/// The system of a `Simulation`, which processes the messages of its shards
+	/// itself instead of spawning their threads, and reads the answers of its
+	/// only driver
+	pub(crate) fn unthreaded(
+		n_shards: u16,
+	) -> (
+		Arc<Self>,
+		LocalShardReceivers,
+		crossbeam_channel::Receiver<Vec<DriverMessage>>,
+	) {
+		let (driver, answers) = crossbeam_channel::unbounded::<Vec<DriverMessage>>();
+		let (system, shards_receivers) = Self::new(
+			0,
+			new_session(),
+			vec![Box::new(driver) as Box<dyn DriverAccess>],
+			vec![0; n_shards as usize],
+			vec![None],
+			None,
+			futures::channel::mpsc::unbounded().0,
+		)
+		.expect("All the shards are local");
+		(Arc::new(system), shards_receivers, answers)
+	}
+
 	pub fn local_shards<S: Storage, F, F2>(
 		storage: F,
 		n_drivers: usize,
@@ -33,17 +182,17 @@ impl System {
 			})
 			.unzip();
 
-		let (shards_accesses, shards_receivers): (Vec<_>, Vec<_>) = (0..n_shards)
-			.map(|_| {
-				let (s, r) = crossbeam_channel::unbounded::<Vec<ShardMessage>>();
-				(Box::new(s) as Box<dyn ShardAccess>, r)
-			})
-			.unzip();
-
-		let system = Arc::new(Self {
-			drivers: drivers_accesses,
-			shards: shards_accesses,
-		});
+		let (system, shards_receivers) = Self::new(
+			0,
+			new_session(),
+			drivers_accesses,
+			vec![0; n_shards as usize],
+			vec![None],
+			None,
+			futures::channel::mpsc::unbounded().0,
+		)
+		.expect("All the shards are local");
+		let system = Arc::new(system);
 
 		let drivers = drivers_receivers
 			.into_iter()
@@ -55,103 +204,87 @@ impl System {
 
 		let local_shards_join_handles = shards_receivers
 			.into_iter()
-			.enumerate()
-			.map(|(id, receiver)| crate::shard::spawn(storage(id), system.clone(), receiver, id))
+			.map(|(id, receiver)| {
+				crate::shard::spawn(
+					storage(id),
+					system.clone(),
+					receiver,
+					id,
+					Vec::new(),
+					(0, 0),
+				)
+			})
 			.collect();
 		(drivers, local_shards_join_handles)
 	}
 
+	/// The workers must be started with the same `config`. They are reached at
+	/// `(IpAddr, u16)` or `Address` values.
+	///
+	/// With `NetworkConfig::with_replication_factor`, the shards of each worker
+	/// are copied on other systems, which take over when it dies. Replication
+	/// is asynchronous: a shard answers before its mutations reach its
+	/// replicas, so the unions acknowledged just before a failure may be lost.
 	pub async fn connect(
 		num_shard_per_system: u16,
-		connect_to: Vec<(IpAddr, u16)>,
+		connect_to: Vec<impl Into<Address>>,
+		config: NetworkConfig,
 	) -> Result<(
 		Driver,
 		Arc<Self>,
 		Vec<std::thread::JoinHandle<()>>,
 		impl Future<Output = Result<()>>,
 	)> {
-		let mut sockets = HashMap::new();
+		let connect_to: Vec<Address> = connect_to.into_iter().map(Into::into).collect();
+		let session = new_session();
+		let n_systems = connect_to.len() as u16 + 1;
+		// The shards of each system are contiguous at first
+		let shard_owners: Vec<u16> = (0..n_systems)
+			.flat_map(|id| std::iter::repeat_n(id, num_shard_per_system as usize))
+			.collect();
+		let replication_factor = config.replication_factor();
+		let replication = (replication_factor > 0).then_some(Replication {
+			n_systems,
+			factor: replication_factor,
+		});
 
-		for ((ip, port), id) in connect_to.iter().zip(1..) {
-			let stream = TcpStream::connect(format!("{ip}:{port}")).await?;
-			let mut socket_framed = Framed::new(stream, Codec::default());
-			let connect = if id < connect_to.len() {
-				connect_to[(id).into()..].to_vec()
-			} else {
-				Vec::new()
-			};
-			socket_framed
-				.send(NetworkMessage::Hello {
-					id: id as u16,
-					num_shard_per_system,
-					connect_to: connect,
-				})
-				.await?;
-			sockets.insert(id, socket_framed);
-		}
+		let workers: Vec<_> = (1..).zip(connect_to.iter().cloned()).collect();
+		let connections =
+			dial_workers(&workers, session, &shard_owners, replication, &config).await?;
 
 		let (s, r) = crossbeam_channel::unbounded();
 		let (driver_access, receiver_driver) = (Box::new(s) as Box<dyn DriverAccess>, r);
 
-		let (system_senders, receivers_system): (Vec<_>, Vec<_>) = (0..connect_to.len())
-			.map(|_idx| futures::channel::mpsc::unbounded())
-			.unzip();
-
-		let (local_shard_access, local_receivers_shard): (Vec<_>, Vec<_>) = (0
-			..num_shard_per_system)
-			.map(|_| {
-				let (s, r) = crossbeam_channel::unbounded::<Vec<ShardMessage>>();
-				(Box::new(s) as Box<dyn ShardAccess>, r)
-			})
-			.unzip();
-
-		let remote_shard_access = system_senders.iter().zip(1..).flat_map(|(s, shard_id)| {
-			(0..num_shard_per_system).map(move |i| {
-				Box::new(RemoteShardAccess {
-					shard_id: shard_id * num_shard_per_system + i,
-					system_channel: s.clone(),
-				}) as Box<dyn ShardAccess>
-			})
-		});
-
-		let system = Arc::new(Self {
-			drivers: vec![driver_access],
-			shards: local_shard_access
-				.into_iter()
-				.chain(remote_shard_access)
-				.collect(),
-		});
-
-		let local_threads_join_handles = local_receivers_shard
-			.into_iter()
-			.enumerate()
-			.map(|(id, receiver)| {
-				crate::shard::spawn(
-					|| crate::storage::ram::RamStorage::default(),
-					system.clone(),
-					receiver.clone(),
-					id,
-				)
-			})
+		let (peers, mut receivers_system) = peer_channels(n_systems, |id| id != 0);
+		let (links, new_links) = futures::channel::mpsc::unbounded();
+		let (mut system, local_receivers_shard) = Self::new(
+			0,
+			session,
+			vec![driver_access],
+			shard_owners,
+			peers,
+			replication,
+			links,
+		)?;
+		*system.addresses.lock().unwrap() = std::iter::once(None)
+			.chain(connect_to.into_iter().map(Some))
 			.collect();
+		system.config = config;
+		let system = Arc::new(system);
+		let endpoints = system.serve_endpoints().await?;
 
-		let cloned_system = system.clone();
-		let forwarding = tokio::spawn(async {
-			receivers_system
-				.into_iter()
-				.zip(1..)
-				.map(move |(channel, system_id)| {
-					let socket = sockets.remove(&system_id).unwrap();
-
-					let system = cloned_system.clone();
-					tokio::spawn(async { handle_network_forwarding(channel, socket, system).await })
-				})
-				.collect::<FuturesUnordered<_>>()
-				.map(|x| Ok(x??))
-				.try_for_each(|_| async { anyhow::Ok(()) })
-				.await
-		})
-		.map(|x| Ok(x??));
+		let local_threads_join_handles =
+			system.spawn_shards(local_receivers_shard, |_| RamStorage::default);
+		system.add_links(connections, &mut receivers_system);
+		let links = tokio::spawn(run_links(new_links));
+		// The endpoints stop with the links, as on the workers
+		let forwarding = async move {
+			let res = links.await;
+			for endpoint in endpoints {
+				endpoint.abort();
+			}
+			res?
+		};
 
 		Ok((
 			Driver::new(MessageBatching::new(system.clone()), 0, receiver_driver),
@@ -161,198 +294,1019 @@ impl System {
 		))
 	}
 
-	pub async fn server(port: u16) -> Result<()> {
-		let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await?;
-		let (stream, _) = listener.accept().await?;
-		let mut socket_master = Framed::new(stream, Codec::default());
+	/// Listens at `listen`: a port on localhost, an `(IpAddr, u16)` or an
+	/// `Address`
+	pub async fn server(listen: impl Into<Address>, config: NetworkConfig) -> Result<()> {
+		Self::server_with_storage(listen, |_shard_id| RamStorage::default, config).await
+	}
 
-		let message = socket_master
-			.next()
-			.await
-			.ok_or_else(|| anyhow!("The stream was empty"))??;
+	/// A worker that restarts with persistent storages (e.g. `RocksDbStorage`)
+	/// rejoins the system when its peers reconnect to it, if it's back within
+	/// their `NetworkConfig::with_reconnect_timeout`
+	///
+	/// Shards moved to this worker with `Driver::move_shard` get a storage from
+	/// `storage` too, emptied first if it still holds the nodes of a previous
+	/// stay.
+	pub async fn server_with_storage<S: Storage + 'static, F, F2>(
+		listen: impl Into<Address>,
+		storage: F,
+		config: NetworkConfig,
+	) -> Result<()>
+	where
+		F: Fn(usize) -> F2 + Send + Sync + 'static,
+		F2: FnOnce() -> S + Send + 'static,
+	{
+		let listener = config.plain_transport().listen(&listen.into()).await?;
+		Self::server_with_listener(listener, storage, config).await
+	}
+
+	/// Serves the connections of `listener`, which the caller bound itself, e.g.
+	/// to learn the port it was given
+	///
+	/// The TLS of `config` is applied over it, so the listener must not run
+	/// the handshake already.
+	pub async fn server_with_listener<S: Storage + 'static, F, F2>(
+		listener: Box<dyn Listener>,
+		storage: F,
+		config: NetworkConfig,
+	) -> Result<()>
+	where
+		F: Fn(usize) -> F2 + Send + Sync + 'static,
+		F2: FnOnce() -> S + Send + 'static,
+	{
+		let storage = Arc::new(storage);
+		let session = new_session();
+		let (incoming_sender, mut incoming) = futures::channel::mpsc::unbounded();
+		let accepting = tokio::spawn(accept_connections(
+			config.secure(listener),
+			config.clone(),
+			incoming_sender,
+		));
+
+		let (
+			MasterHello {
+				socket: mut socket_master,
+				session: master_session,
+				self_id,
+				shard_owners,
+				replication,
+				connect_to,
+			},
+			early_connections,
+		) = wait_for_master(&mut incoming).await?;
 
-		let (self_id, num_shard_per_system, connect_to) = if let NetworkMessage::Hello {
+		let identity = NetworkMessage::Id {
+			id: self_id,
+			session,
+			n_shards: shard_owners.len() as u32,
+			cluster_token: config.cluster_token(),
+		};
+
+		// We dial the workers listed by the master, and the ones that come after us
+		// dial us
+		let n_systems = shard_owners.iter().copied().chain([self_id]).max().unwrap() + 1;
+		let (peers, mut receivers_system) = peer_channels(n_systems, |id| {
+			id == 0 || id > self_id || connect_to.iter().any(|&(peer, _)| peer == id)
+		});
+		let connections = dial_peers(connect_to, &identity, &config).await?;
+
+		let (links, new_links) = futures::channel::mpsc::unbounded();
+		let (mut system, local_receivers_shard) = Self::new(
+			self_id,
+			session,
+			vec![Box::new(RemoteDriverAccess {
+				driver_idx: 0,
+				system_channel: peers[0].clone().unwrap(),
+			}) as Box<dyn DriverAccess>],
+			shard_owners,
+			peers,
+			replication,
+			links,
+		)?;
+		system.config = config;
+		system.new_storage = {
+			let storage = storage.clone();
+			Box::new(move |shard_id| {
+				let storage = storage(shard_id);
+				Box::new(move || Box::new(storage()) as Box<dyn Storage>)
+			})
+		};
+		let system = Arc::new(system);
+		let endpoints = system.serve_endpoints().await?;
+
+		let _local_threads_join_handles = system.spawn_shards(local_receivers_shard, &*storage);
+		system.add_links(connections, &mut receivers_system);
+		let receiver_master = receivers_system[0].take().unwrap();
+		let (master_reconnects, connections_master) = futures::channel::mpsc::unbounded();
+		let routing = tokio::spawn(route_connections(
+			system.clone(),
+			futures::stream::iter(early_connections).chain(incoming),
+			receivers_system,
+			master_reconnects,
+		));
+
+		// Answering the master lets it go on with the next worker, which dials us
+		socket_master.send(identity.clone()).await?;
+		system.add_link(
+			0,
+			receiver_master,
+			(socket_master, master_session),
+			Reconnect::Accept {
+				connections: connections_master,
+				identity,
+			},
+		);
+
+		let res = run_links(new_links).await;
+		accepting.abort();
+		routing.abort();
+		for endpoint in endpoints {
+			endpoint.abort();
+		}
+		res
+	}
+
+	/// Connects a new worker, listening at `addr`, to the running system and
+	/// returns its system id
+	///
+	/// The worker starts without shards, give it some with `Driver::move_shard`.
+	/// Only the master can add workers.
+	pub async fn add_worker(self: &Arc<Self>, addr: impl Into<Address>) -> Result<u16> {
+		let addr = addr.into();
+		ensure!(self.self_id == 0, "Only the master can add workers");
+		if let ClusterHealth::Failed(error) = self.health() {
+			bail!("Can't add a worker to a failed system: {error}");
+		}
+		let id = {
+			let mut addresses = self.addresses.lock().unwrap();
+			addresses.push(None);
+			addresses.len() as u16 - 1
+		};
+		let identity = self.hello(id);
+		let connection = dial(&addr, identity.clone(), id, &self.config).await?;
+		if !connection.0.codec().protocol().supports(features::ELASTIC) {
+			bail!("The worker at {addr} can't join a running system");
+		}
+		let receiver = self.register_peer(id);
+		let reconnect = Reconnect::Dial {
+			addr: addr.clone(),
+			identity,
+		};
+		self.add_link(id, receiver, connection, reconnect);
+		self.addresses.lock().unwrap()[id as usize] = Some(addr);
+		Ok(id)
+	}
+
+	/// What the master tells worker `id` when it dials it: the current owner of
+	/// each shard, and the workers before it, which it dials
+	pub(crate) fn hello(&self, id: u16) -> NetworkMessage {
+		let connect_to = self
+			.addresses
+			.lock()
+			.unwrap()
+			.iter()
+			.zip(0..id)
+			.filter_map(|(addr, id)| Some((id, addr.clone()?)))
+			.collect();
+		NetworkMessage::Hello {
 			id,
-			num_shard_per_system,
+			session: self.session,
+			shard_owners: self.owners.read().unwrap().clone(),
+			replication: self.replication,
 			connect_to,
-		} = message
+			cluster_token: self.config.cluster_token(),
+		}
+	}
+
+	/// Disconnects a worker from the system, after which its server returns
+	///
+	/// The worker must not serve any shard anymore, move them away first with
+	/// `Driver::move_shard`. Only the master can remove workers.
+	pub fn remove_worker(&self, system_id: u16) -> Result<()> {
+		ensure!(self.self_id == 0, "Only the master can remove workers");
+		ensure!(
+			system_id != 0 && !self.has_left(system_id),
+			"System {system_id} is not a worker of this system"
+		);
+		ensure!(
+			!self.owners.read().unwrap().contains(&system_id),
+			"System {system_id} still serves shards, move them away first"
+		);
+		self.broadcast(NetworkMessage::SystemLeft { seq: 0, system_id });
+		self.remove_peer(system_id);
+		Ok(())
+	}
+
+	pub(crate) fn shard(&self, shard_id: usize) -> Guard<Arc<Box<dyn ShardAccess>>> {
+		self.shards[shard_id].load()
+	}
+
+	pub(crate) fn shard_batches(&self) -> &BatchPool<ShardMessage> {
+		&self.shard_batches
+	}
+
+	pub(crate) fn driver(&self, driver_id: usize) -> Guard<Arc<Box<dyn DriverAccess>>> {
+		self.drivers[driver_id].load()
+	}
+
+	pub fn n_shards(&self) -> usize {
+		self.shards.len()
+	}
+
+	/// Set by a shard served here, or to `u64::MAX` when it moves away
+	pub(crate) fn set_n_nodes(&self, shard_id: usize, n_nodes: u64) {
+		self.n_nodes[shard_id].store(n_nodes, Ordering::Release);
+	}
+
+	/// Whether `key` may be a node, which is certain for the shards served here
+	pub(crate) fn node_exists(&self, key: Key) -> bool {
+		key.shard() < self.n_shards()
+			&& Key::new(key.shard(), key.shard_specific_id()) == key
+			&& key.shard_specific_id() < self.n_nodes[key.shard()].load(Ordering::Acquire)
+	}
+
+	pub fn n_drivers(&self) -> usize {
+		self.drivers.len()
+	}
+
+	/// The master is system 0, workers are numbered from 1
+	pub fn system_id(&self) -> u16 {
+		self.self_id
+	}
+
+	pub(crate) fn session(&self) -> u64 {
+		self.session
+	}
+
+	pub(crate) fn metrics(&self) -> &Metrics {
+		&self.metrics
+	}
+
+	/// The metrics of this system in the Prometheus text format, as served by
+	/// the endpoint of `NetworkConfig::with_metrics_endpoint`
+	pub fn render_metrics(&self) -> String {
+		self.metrics.render(self)
+	}
+
+	pub fn uptime(&self) -> std::time::Duration {
+		self.started.elapsed()
+	}
+
+	pub(crate) fn register_shard_commands(
+		&self,
+		shard_id: usize,
+		commands: crossbeam_channel::Sender<Command>,
+	) {
+		self.shard_commands
+			.lock()
+			.unwrap()
+			.insert(shard_id, commands);
+	}
+
+	/// Unless the shard came back in the meantime, with other commands
+	pub(crate) fn unregister_shard_commands(
+		&self,
+		shard_id: usize,
+		commands: &crossbeam_channel::Sender<Command>,
+	) {
+		let mut shard_commands = self.shard_commands.lock().unwrap();
+		if shard_commands
+			.get(&shard_id)
+			.is_some_and(|registered| registered.same_channel(commands))
 		{
-			(id, num_shard_per_system, connect_to)
-		} else {
-			bail!("The first message from the master should be Hello")
-		};
+			shard_commands.remove(&shard_id);
+		}
+	}
+
+	pub(crate) fn shard_commands(&self) -> Vec<(usize, crossbeam_channel::Sender<Command>)> {
+		let shard_commands = self.shard_commands.lock().unwrap();
+		let mut shard_commands: Vec<_> = shard_commands
+			.iter()
+			.map(|(&shard_id, commands)| (shard_id, commands.clone()))
+			.collect();
+		shard_commands.sort_by_key(|&(shard_id, _)| shard_id);
+		shard_commands
+	}
+
+	/// Serves the metrics and admin endpoints of the config, until the system is
+	/// dropped or the tasks aborted
+	async fn serve_endpoints(self: &Arc<Self>) -> Result<Vec<tokio::task::JoinHandle<()>>> {
+		let mut endpoints = Vec::new();
+		if let Some(listen) = self.config.metrics_endpoint() {
+			let listener = SocketTransport.listen(listen).await?;
+			let system = Arc::downgrade(self);
+			endpoints.push(tokio::spawn(http::serve(listener, move |request| {
+				let response = match (&*request.method, &*request.path, system.upgrade()) {
+					(_, _, None) => Response::error(503, "The system stopped"),
+					("GET", "/metrics", Some(system)) => Response::text(system.render_metrics()),
+					(_, "/metrics", _) => Response::error(405, "Only GET is allowed"),
+					_ => Response::error(404, "Not found"),
+				};
+				futures::future::ready(response)
+			})));
+		}
+		if let Some(listen) = self.config.admin_endpoint() {
+			ensure!(
+				self.config.cluster_token().is_some(),
+				"The admin endpoint needs a cluster token"
+			);
+			if let Address::Tcp(ip, _) = listen {
+				ensure!(
+					ip.is_loopback(),
+					"The admin endpoint serves plain HTTP, it only listens on loopback \
+					 addresses and Unix sockets, not {listen}"
+				);
+			}
+			let listener = SocketTransport.listen(listen).await?;
+			let system = Arc::downgrade(self);
+			endpoints.push(tokio::spawn(http::serve(listener, move |request| {
+				admin::handle(system.clone(), request)
+			})));
+		}
+		Ok(endpoints)
+	}
 
-		let mut sockets = HashMap::new();
+	/// The system currently serving the shard
+	pub fn shard_owner(&self, shard_id: usize) -> u16 {
+		self.owners.read().unwrap()[shard_id]
+	}
 
-		sockets.insert(0, socket_master);
+	pub(crate) fn config(&self) -> &NetworkConfig {
+		&self.config
+	}
 
-		for _ in 1..self_id {
-			let (stream, _) = listener.accept().await?;
-			let mut stream_framed = Framed::new(stream, Codec::default());
-			let message = stream_framed
-				.next()
-				.await
-				.ok_or_else(|| anyhow!("The stream was empty"))??;
+	pub fn health(&self) -> ClusterHealth {
+		self.health.lock().unwrap().clone()
+	}
 
-			if let NetworkMessage::Id { id } = message {
-				sockets.insert(id, stream_framed);
+	/// Sends the failure to the drivers and records it, unless the system was
+	/// already in a worse state
+	///
+	/// Every failure is sent, as the requests lost with it must be released even
+	/// when an earlier one was recoverable.
+	pub(crate) fn report_failure(&self, error: SystemError, recovered: bool) {
+		let mut health = self.health.lock().unwrap();
+		if !matches!(*health, ClusterHealth::Failed(_)) {
+			*health = if recovered {
+				ClusterHealth::Degraded(error.clone())
 			} else {
-				bail!("The first message from a peer should be Id")
-			}
+				ClusterHealth::Failed(error.clone())
+			};
+		}
+		for driver in &self.drivers {
+			driver.load().send_failure(error.clone());
 		}
+	}
 
-		for ((ip, port), id) in connect_to.iter().zip(self_id + 1..) {
-			let stream = TcpStream::connect(format!("{ip}:{port}")).await?;
-			let mut socket_framed = Framed::new(stream, Codec::default());
-			socket_framed
-				.send(NetworkMessage::Id { id: self_id })
-				.await?;
-			sockets.insert(id, socket_framed);
+	/// Systems the local shards stream their mutations to
+	pub(crate) fn replica_targets(&self) -> Vec<u16> {
+		match self.replication {
+			Some(replication) => replication.replicas_of(self.self_id).collect(),
+			None => Vec::new(),
 		}
+	}
 
-		let (local_shard_access, local_receivers_shard): (Vec<_>, Vec<_>) = (0
-			..num_shard_per_system)
-			.map(|_| {
-				let (s, r) = crossbeam_channel::unbounded::<Vec<ShardMessage>>();
-				(Box::new(s) as Box<dyn ShardAccess>, r)
-			})
-			.unzip();
+	/// Answers `RequestRefused` to the requests waiting for `messages`, which
+	/// are dropped
+	pub(crate) fn refuse(&self, messages: &[ShardMessage], reason: &str) {
+		for req_id in messages.iter().filter_map(ShardMessage::awaited_by) {
+			if req_id != ReqId::unknown() && req_id.driver() < self.n_drivers() {
+				self.driver(req_id.driver())
+					.send_messages(vec![DriverMessage::RequestRefused {
+						req_id,
+						reason: reason.to_owned(),
+					}]);
+			}
+		}
+	}
 
-		let (ref system_senders, receivers_system): (Vec<_>, Vec<_>) = (0..sockets.len())
-			.map(|_idx| futures::channel::mpsc::unbounded())
-			.unzip();
+	/// Best effort: if the link is down the failure is reported by its
+	/// forwarding task
+	pub(crate) fn send_to_peer(&self, system_id: u16, message: NetworkMessage) {
+		if let Some(Some(peer)) = self.peers.read().unwrap().get(system_id as usize) {
+			let _ = peer.unbounded_send(message);
+		}
+	}
 
-		let shards = (0..self_id)
-			.flat_map(|system_id| {
-				(0..num_shard_per_system).map(move |i| {
-					Box::new(RemoteShardAccess {
-						shard_id: system_id * num_shard_per_system + i,
-						system_channel: system_senders[system_id as usize].clone(),
-					}) as Box<dyn ShardAccess>
-				})
-			})
-			.chain(local_shard_access)
-			.chain(
-				(self_id + 1..sockets.len() as u16 + 1).flat_map(|system_id| {
-					(0..num_shard_per_system).map(move |i| {
-						Box::new(RemoteShardAccess {
-							shard_id: system_id * num_shard_per_system + i,
-							system_channel: (&system_senders)[system_id as usize - 1].clone(),
-						}) as Box<dyn ShardAccess>
-					})
-				}),
-			)
-			.collect();
+	fn broadcast(&self, message: NetworkMessage) {
+		for peer in self.peers.read().unwrap().iter().flatten() {
+			let _ = peer.unbounded_send(message.clone());
+		}
+	}
 
-		let system = Arc::new(Self {
-			drivers: vec![Box::new(RemoteDriverAccess {
-				driver_idx: 0,
-				system_channel: system_senders[0].clone(),
-			}) as Box<dyn DriverAccess>],
-			shards,
-		});
+	/// Creates the channel to a system whose link is about to be added
+	fn register_peer(&self, system_id: u16) -> PeerReceiver {
+		let (s, r) = futures::channel::mpsc::unbounded();
+		let mut peers = self.peers.write().unwrap();
+		if peers.len() <= system_id as usize {
+			peers.resize(system_id as usize + 1, None);
+		}
+		peers[system_id as usize] = Some(s);
+		r
+	}
 
-		let local_threads_join_handles = local_receivers_shard
+	/// Spawns the threads of the shards we serve from the start
+	fn spawn_shards<S: Storage, F2: FnOnce() -> S + Send + 'static>(
+		self: &Arc<Self>,
+		receivers: LocalShardReceivers,
+		storage: impl Fn(usize) -> F2,
+	) -> Vec<std::thread::JoinHandle<()>> {
+		receivers
 			.into_iter()
-			.enumerate()
 			.map(|(id, receiver)| {
 				crate::shard::spawn(
-					|| crate::storage::ram::RamStorage::default(),
-					system.clone(),
-					receiver.clone(),
-					id + (self_id * num_shard_per_system) as usize,
+					storage(id),
+					self.clone(),
+					receiver,
+					id,
+					self.replica_targets(),
+					(0, 0),
 				)
 			})
-			.collect::<Vec<_>>();
-
-		let forwarding = async {
-			receivers_system
-				.into_iter()
-				.zip(0..)
-				.map(|(channel, system_id)| {
-					let system_id = if system_id == self_id {
-						system_id + 1
-					} else {
-						system_id
-					};
-					let socket = sockets.remove(&system_id).unwrap();
-
-					let union_find_system = system.clone();
-					tokio::spawn(async {
-						handle_network_forwarding(channel, socket, union_find_system).await
-					})
-				})
-				.collect::<FuturesUnordered<_>>()
-				.map(|x| anyhow::Ok(x??))
-				.try_for_each(|_| async { Ok(()) })
-				.await
+			.collect()
+	}
+
+	/// `receivers` holds the channels of the peers, taken by their links
+	fn add_links(
+		self: &Arc<Self>,
+		connections: Vec<NewLink>,
+		receivers: &mut [Option<PeerReceiver>],
+	) {
+		for (id, connection, reconnect) in connections {
+			let receiver = receivers[id as usize].take().unwrap();
+			self.add_link(id, receiver, connection, reconnect);
+		}
+	}
+
+	fn add_link(
+		self: &Arc<Self>,
+		peer: u16,
+		receiver: PeerReceiver,
+		connection: (Socket, u64),
+		reconnect: Reconnect,
+	) {
+		let link = tokio::spawn(handle_network_forwarding(
+			receiver,
+			connection,
+			reconnect,
+			self.clone(),
+			peer,
+		));
+		let _ = self.links.unbounded_send(link);
+	}
+
+	/// Closes the link to the system once the messages already sent to it are
+	/// forwarded
+	pub(crate) fn remove_peer(&self, system_id: u16) {
+		self.shared_memory.close(system_id);
+		if let Some(peer) = self
+			.peers
+			.write()
+			.unwrap()
+			.get_mut(system_id as usize)
+			.and_then(Option::take)
+		{
+			peer.close_channel();
+		}
+		if let Some(addr) = self.addresses.lock().unwrap().get_mut(system_id as usize) {
+			*addr = None;
+		}
+	}
+
+	pub(crate) fn has_left(&self, system_id: u16) -> bool {
+		!matches!(
+			self.peers.read().unwrap().get(system_id as usize),
+			Some(Some(_))
+		)
+	}
+
+	/// Told by `from`. A worker that leaves on its own tells the master, which
+	/// removes it. When we are the one leaving, all our links are closed.
+	pub(crate) fn system_left(&self, from: u16, system_id: u16) {
+		if self.self_id == 0 && from == system_id {
+			// Refused if a shard moved to it in the meantime, it stays then
+			let _ = self.remove_worker(system_id);
+		} else if system_id == self.self_id {
+			let n_peers = self.peers.read().unwrap().len() as u16;
+			for peer in 0..n_peers {
+				self.remove_peer(peer);
+			}
+			self.links.close_channel();
+		} else {
+			self.remove_peer(system_id);
+		}
+	}
+
+	pub(crate) fn apply_replica_mutations(
+		&self,
+		shard_id: u16,
+		batch: Vec<Mutation>,
+	) -> Result<()> {
+		let mut replicas = self.replicas.lock().unwrap();
+		let storage = replicas.entry(shard_id).or_default();
+		// Before applying any, as a panic would poison the replicas for good
+		Mutation::check_all(&batch, shard_id, self.n_shards(), storage.n_nodes())?;
+		for mutation in batch {
+			mutation.apply(storage);
+		}
+		Ok(())
+	}
+
+	pub(crate) fn replace_replica(&self, shard_id: u16, nodes: Vec<Mutation>) -> Result<()> {
+		Mutation::check_all(&nodes, shard_id, self.n_shards(), 0)?;
+		let mut storage = RamStorage::default();
+		for mutation in nodes {
+			mutation.apply(&mut storage);
+		}
+		self.replicas.lock().unwrap().insert(shard_id, storage);
+		Ok(())
+	}
+
+	pub(crate) fn move_shard(&self, shard_id: u16, system_id: u16) -> Result<()> {
+		let access = self
+			.remote_shard(shard_id, system_id)
+			.ok_or_else(|| anyhow!("Shard {shard_id} moved to unknown system {system_id}"))?;
+		self.shards[shard_id as usize].store(Arc::new(access));
+		self.owners.write().unwrap()[shard_id as usize] = system_id;
+		Ok(())
+	}
+
+	/// The route to a shard served by another system, through shared memory when
+	/// that system is on our host
+	fn remote_shard(&self, shard_id: u16, system_id: u16) -> Option<Box<dyn ShardAccess>> {
+		if let Some(sender) = self.shared_memory.sender(system_id) {
+			return Some(Box::new(ShmShardAccess { shard_id, sender }));
+		}
+		let system_channel = self
+			.peers
+			.read()
+			.unwrap()
+			.get(system_id as usize)?
+			.clone()?;
+		Some(Box::new(RemoteShardAccess {
+			shard_id,
+			system_channel,
+		}))
+	}
+
+	/// Updates the routes to the shards and drivers of `system_id` after its
+	/// rings were opened or closed
+	fn reroute(&self, system_id: u16) {
+		let shard_ids: Vec<u16> = (0..self.n_shards() as u16)
+			.filter(|&shard_id| self.shard_owner(shard_id as usize) == system_id)
+			.collect();
+		for shard_id in shard_ids {
+			// A system that left doesn't serve shards anymore
+			if let Some(access) = self.remote_shard(shard_id, system_id) {
+				self.shards[shard_id as usize].store(Arc::new(access));
+			}
+		}
+		// The drivers are on the master
+		if system_id != 0 || self.self_id == 0 {
+			return;
+		}
+		let sender = self.shared_memory.sender(0);
+		let system_channel = self.peers.read().unwrap().first().cloned().flatten();
+		for (driver, driver_idx) in self.drivers.iter().zip(0..) {
+			let access: Box<dyn DriverAccess> = match (&sender, &system_channel) {
+				(Some(sender), _) => Box::new(ShmDriverAccess {
+					driver_idx,
+					sender: sender.clone(),
+				}),
+				(None, Some(system_channel)) => Box::new(RemoteDriverAccess {
+					driver_idx,
+					system_channel: system_channel.clone(),
+				}),
+				(None, None) => continue,
+			};
+			driver.store(Arc::new(access));
+		}
+	}
+
+	/// Offers `peer` to send us its batches through shared memory, if both of us
+	/// are configured for it
+	///
+	/// Without a ring the batches keep going through the link.
+	pub(crate) fn offer_shared_memory(self: &Arc<Self>, peer: u16, protocol: Protocol) {
+		let Some(dir) = self.config.shared_memory_dir() else {
+			return;
 		};
+		if !protocol.supports(features::SHARED_MEMORY) {
+			return;
+		}
+		let codec = self.config.shared_memory_codec(protocol);
+		if let Ok(offer) = self.shared_memory.offer(self, peer, dir, codec) {
+			self.send_to_peer(peer, offer);
+		}
+	}
+
+	/// Sends our batches for `peer` through the ring it offered, if we share its
+	/// host
+	///
+	/// The peer reads the ring once it received everything we sent through the
+	/// link before, which `SharedMemoryAccepted` follows, so that each route
+	/// stays in order.
+	pub(crate) fn accept_shared_memory(
+		self: &Arc<Self>,
+		peer: u16,
+		path: &str,
+		nonce: u64,
+		protocol: Protocol,
+	) {
+		let Some(dir) = self.config.shared_memory_dir() else {
+			return;
+		};
+		let codec = self.config.shared_memory_codec(protocol);
+		if self
+			.shared_memory
+			.accept(self, peer, dir, path, nonce, codec)
+		{
+			self.reroute(peer);
+			self.send_to_peer(peer, NetworkMessage::SharedMemoryAccepted { seq: 0, nonce });
+		}
+	}
 
-		// let handle = tokio::task::spawn_blocking(|| {
-		// 	for t in local_threads_join_handles {
-		// 		t.join().unwrap()
-		// 	}
-		// })
-		// .map_err(|err| err.into());
+	/// Told by `peer` that it sends its batches through the ring we offered
+	pub(crate) fn start_shared_memory(self: &Arc<Self>, peer: u16, nonce: u64) -> Result<()> {
+		self.shared_memory.start(self, peer, nonce)
+	}
 
-		futures::try_join!(/* handle, */ forwarding)?;
+	/// Sends the batches for `peer` through its link again
+	pub(crate) fn close_shared_memory(&self, peer: u16) {
+		if self.shared_memory.close(peer) {
+			self.reroute(peer);
+		}
+	}
 
+	/// Serves a shard that was migrated to us, and answers the driver that moved
+	/// it
+	///
+	/// Its previous system keeps it until it hears that we serve it, so an error
+	/// gives the shard back.
+	///
+	/// Blocks while the storage is loaded, see `serve_locally`.
+	pub(crate) fn install_shard(
+		self: &Arc<Self>,
+		shard_id: u16,
+		nodes: Vec<Mutation>,
+		message_counters: (u64, u64),
+		req_id: ReqId,
+	) -> Result<()> {
+		let storage = (self.new_storage)(shard_id as usize);
+		self.serve_locally(shard_id as usize, storage, nodes, message_counters)?;
+		self.driver(req_id.driver())
+			.send_messages(vec![DriverMessage::MoveShardDone {
+				req_id,
+				shard: shard_id,
+			}]);
 		Ok(())
 	}
 
-	pub(crate) fn shard(&self, shard_id: usize) -> &dyn ShardAccess {
-		&*self.shards[shard_id]
+	/// Tells a local shard migrating to another system that it won't serve it
+	pub(crate) fn migration_refused(&self, shard_id: u16, reason: String) {
+		if let Some(commands) = self
+			.shard_commands
+			.lock()
+			.unwrap()
+			.get(&(shard_id as usize))
+		{
+			let _ = commands.send(Command::MigrationRefused(reason));
+		}
 	}
 
-	pub(crate) fn driver(&self, driver_id: usize) -> &dyn DriverAccess {
-		&*self.drivers[driver_id]
+	/// Starts a thread for the shard, with the `nodes` loaded into the storage
+	/// made by `storage`, sends a copy of it to our replicas and tells the other
+	/// systems to send us its messages
+	///
+	/// Waits until the storage is loaded, and nothing is sent to the shard if
+	/// that fails. Opening a RocksDB storage and replaying a shard takes a while,
+	/// so this must not be called from an async task, which would hold up the
+	/// links of its runtime: they call it through `spawn_blocking`.
+	fn serve_locally<S: Storage>(
+		self: &Arc<Self>,
+		shard_id: usize,
+		storage: impl FnOnce() -> S + Send + 'static,
+		nodes: Vec<Mutation>,
+		message_counters: (u64, u64),
+	) -> Result<()> {
+		let replicas = self.replica_targets();
+		let (loaded_sender, loaded) = crossbeam_channel::bounded(1);
+		let (s, r) = crossbeam_channel::unbounded::<Vec<ShardMessage>>();
+		crate::shard::spawn(
+			move || {
+				let mut storage = storage();
+				let _ = loaded_sender.send(load(&mut storage, nodes));
+				storage
+			},
+			self.clone(),
+			r,
+			shard_id,
+			replicas.clone(),
+			message_counters,
+		);
+		// On failure, dropping `s` stops the thread
+		loaded
+			.recv()
+			.map_err(|_| anyhow!("The storage of shard {shard_id} failed to open"))??;
+
+		self.shards[shard_id].store(Arc::new(Box::new(s)));
+		self.owners.write().unwrap()[shard_id] = self.self_id;
+		self.broadcast(NetworkMessage::ShardMoved {
+			seq: 0,
+			shard_id: shard_id as u16,
+			system_id: self.self_id,
+		});
+		Ok(())
 	}
 
-	pub fn n_shards(&self) -> usize {
-		self.shards.len()
+	/// Called when the link to `system_id` is lost, returns whether the system
+	/// can keep working without it
+	///
+	/// If we are the first replica of the lost system, we start serving its
+	/// shards from our copies and tell the other systems to send us their
+	/// messages.
+	///
+	/// Blocks while the copies are loaded into their storages.
+	pub(crate) fn take_over_shards_of(self: &Arc<Self>, system_id: u16) -> bool {
+		let replication = match self.replication {
+			// The master holds the driver, so we can't do without it
+			Some(replication) if system_id != 0 => replication,
+			_ => return false,
+		};
+		if replication.replicas_of(system_id).next() != Some(self.self_id) {
+			return true;
+		}
+		let shard_ids: Vec<usize> = (0..self.n_shards())
+			.filter(|&shard_id| self.shard_owner(shard_id) == system_id)
+			.collect();
+		// A shard whose snapshot never reached us would be served empty, and the
+		// other copies are kept for a later takeover
+		let replicas: Vec<RamStorage> = {
+			let mut replicas = self.replicas.lock().unwrap();
+			if !shard_ids
+				.iter()
+				.all(|shard_id| replicas.contains_key(&(*shard_id as u16)))
+			{
+				return false;
+			}
+			shard_ids
+				.iter()
+				.map(|shard_id| replicas.remove(&(*shard_id as u16)).unwrap())
+				.collect()
+		};
+		for (shard_id, replica) in shard_ids.into_iter().zip(replicas) {
+			let nodes = Mutation::snapshot(&replica, shard_id);
+			// Kept in the storage of the worker, as the shards moved to it
+			let storage = (self.new_storage)(shard_id);
+			if self
+				.serve_locally(shard_id, storage, nodes, (0, 0))
+				.is_err()
+			{
+				return false;
+			}
+		}
+		true
 	}
+}
 
-	pub fn n_drivers(&self) -> usize {
-		self.drivers.len()
+/// Replaces what `storage` holds with the `nodes` of a shard
+fn load(storage: &mut impl Storage, nodes: Vec<Mutation>) -> Result<()> {
+	if storage.n_nodes() > 0 {
+		storage.clear()?;
 	}
+	for mutation in nodes {
+		mutation.apply(storage);
+	}
+	Ok(())
 }
 
-async fn handle_network_forwarding(
-	receiver: futures::channel::mpsc::UnboundedReceiver<NetworkMessage>,
-	socket: Framed<TcpStream, Codec>,
-	system: Arc<System>,
-) -> Result<()> {
-	let (sink, stream) = socket.split();
-	let forward_to_remote = async {
-		receiver.map(|x| Ok(x)).forward(sink).await?;
-		Err::<(), anyhow::Error>(anyhow!("The channel was closed"))
-	};
+/// The master dials the workers in order, and tells each one to dial the ones
+/// before it
+async fn dial_workers(
+	workers: &[(u16, Address)],
+	session: u64,
+	shard_owners: &[u16],
+	replication: Option<Replication>,
+	config: &NetworkConfig,
+) -> Result<Vec<NewLink>> {
+	let mut connections = Vec::new();
+	for (id, addr) in workers.iter().cloned() {
+		let identity = NetworkMessage::Hello {
+			id,
+			session,
+			shard_owners: shard_owners.to_vec(),
+			replication,
+			connect_to: workers[..id as usize - 1].to_vec(),
+			cluster_token: config.cluster_token(),
+		};
+		let connection = dial(&addr, identity.clone(), id, config).await?;
+		if replication.is_some()
+			&& !connection
+				.0
+				.codec()
+				.protocol()
+				.supports(features::REPLICATION)
+		{
+			bail!("System {id} at {addr} doesn't support replication");
+		}
+		let reconnect = Reconnect::Dial { addr, identity };
+		connections.push((id, connection, reconnect));
+	}
+	Ok(connections)
+}
+
+/// What a worker learns from the `Hello` of the master
+struct MasterHello {
+	socket: Socket,
+	session: u64,
+	self_id: u16,
+	shard_owners: Vec<u16>,
+	replication: Option<Replication>,
+	connect_to: Vec<(u16, Address)>,
+}
 
-	let forward_from_remote = async move {
-		stream
-			.try_for_each(|m| async {
-				match m {
-					NetworkMessage::Hello {
-						id: _,
-						num_shard_per_system: _,
-						connect_to: _,
-					} => bail!("There should be no Hello messages at this point"),
-					NetworkMessage::Id { id: _ } => {
-						bail!("There should be no Id messages at this point")
-					}
-					NetworkMessage::DriverMessages { driver_idx, batch } => {
-						system.driver(driver_idx as usize).send_messages(batch)
-					}
-					NetworkMessage::ShardMessages { shard_id, batch } => {
-						system.shard(shard_id as usize).send_messages(batch)
-					}
+/// Waits for the master to connect, and returns the connections of the peers
+/// that came before it
+///
+/// Peers may reconnect to a restarted worker before the master tells it who it
+/// is.
+async fn wait_for_master(
+	incoming: &mut (impl Stream<Item = (NetworkMessage, Socket)> + Unpin),
+) -> Result<(MasterHello, Vec<(NetworkMessage, Socket)>)> {
+	let mut early_connections = Vec::new();
+	loop {
+		match incoming
+			.next()
+			.await
+			.ok_or_else(|| anyhow!("The listener was closed"))?
+		{
+			(
+				NetworkMessage::Hello {
+					id,
+					session,
+					shard_owners,
+					replication,
+					connect_to,
+					..
+				},
+				socket,
+			) => {
+				let master = MasterHello {
+					socket,
+					session,
+					self_id: id,
+					shard_owners,
+					replication,
+					connect_to,
 				};
-				Ok(())
-			})
-			.await?;
+				return Ok((master, early_connections));
+			}
+			(message @ NetworkMessage::Id { .. }, socket) => {
+				early_connections.push((message, socket))
+			}
+			// Not a peer
+			_ => {}
+		}
+	}
+}
+
+/// A worker dials the workers listed by the master
+async fn dial_peers(
+	connect_to: Vec<(u16, Address)>,
+	identity: &NetworkMessage,
+	config: &NetworkConfig,
+) -> Result<Vec<NewLink>> {
+	let mut connections = Vec::new();
+	for (id, addr) in connect_to {
+		let connection = dial(&addr, identity.clone(), id, config).await?;
+		let reconnect = Reconnect::Dial {
+			addr,
+			identity: identity.clone(),
+		};
+		connections.push((id, connection, reconnect));
+	}
+	Ok(connections)
+}
 
-		Err::<(), anyhow::Error>(anyhow!("The socket was closed"))
+/// One channel per system for which `linked` is true
+fn peer_channels(
+	n_systems: u16,
+	linked: impl Fn(u16) -> bool,
+) -> (Vec<Option<PeerSender>>, Vec<Option<PeerReceiver>>) {
+	(0..n_systems)
+		.map(|system_id| {
+			if linked(system_id) {
+				let (s, r) = futures::channel::mpsc::unbounded();
+				(Some(s), Some(r))
+			} else {
+				(None, None)
+			}
+		})
+		.unzip()
+}
+
+/// Links the workers that dial us: a new one gets a link, and the connections
+/// of the known ones are handed to their link to reconnect it
+///
+/// `receivers` holds the channels created for the workers expected to dial us.
+async fn route_connections(
+	system: Arc<System>,
+	mut incoming: impl Stream<Item = (NetworkMessage, Socket)> + Unpin,
+	mut receivers: Vec<Option<PeerReceiver>>,
+	master: futures::channel::mpsc::UnboundedSender<(Socket, u64)>,
+) {
+	let self_id = system.self_id;
+	let identity = NetworkMessage::Id {
+		id: self_id,
+		session: system.session,
+		n_shards: system.n_shards() as u32,
+		cluster_token: system.config.cluster_token(),
 	};
+	let mut reconnects = HashMap::from([(0, master)]);
+	while let Some((message, mut socket)) = incoming.next().await {
+		if message.n_shards() != Some(system.n_shards()) {
+			continue;
+		}
+		let (peer, peer_session) = match message {
+			NetworkMessage::Hello { id, session, .. } if id == self_id => (0, session),
+			NetworkMessage::Id { id, session, .. } if id > self_id => (id, session),
+			_ => continue,
+		};
+		if let Some(sender) = reconnects.get(&peer) {
+			let _ = sender.unbounded_send((socket, peer_session));
+			continue;
+		}
+		// The channel exists before we answer, so that the new worker can be sent
+		// messages as soon as it's connected
+		let receiver = match receivers.get_mut(peer as usize).and_then(Option::take) {
+			Some(receiver) => receiver,
+			None => system.register_peer(peer),
+		};
+		if socket.send(identity.clone()).await.is_err() {
+			continue;
+		}
+		let (sender, connections) = futures::channel::mpsc::unbounded();
+		reconnects.insert(peer, sender);
+		system.add_link(
+			peer,
+			receiver,
+			(socket, peer_session),
+			Reconnect::Accept {
+				connections,
+				identity: identity.clone(),
+			},
+		);
+	}
+}
 
-	futures::try_join!(forward_from_remote, forward_to_remote).map(|_| ())
+/// Runs until we leave the system and all the links are closed, and fails as
+/// soon as one of them fails
+///
+/// There may be no link for a while, e.g. when the only worker is removed
+/// before another is added, so that alone doesn't stop it.
+async fn run_links(mut new_links: futures::channel::mpsc::UnboundedReceiver<Link>) -> Result<()> {
+	let mut links = FuturesUnordered::new();
+	let mut leaving = false;
+	loop {
+		tokio::select! {
+			biased;
+			link = new_links.next(), if !leaving => match link {
+				Some(link) => links.push(link),
+				None => leaving = true,
+			},
+			Some(res) = links.next() => res??,
+			else => return Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn links_run_until_we_leave() {
+		let (links, new_links) = futures::channel::mpsc::unbounded();
+		let running = tokio::spawn(run_links(new_links));
+		links
+			.unbounded_send(tokio::spawn(async { Ok(()) }))
+			.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!running.is_finished(), "Stopped without links");
+
+		let (close, closed) = futures::channel::oneshot::channel::<()>();
+		links
+			.unbounded_send(tokio::spawn(async {
+				let _ = closed.await;
+				Ok(())
+			}))
+			.unwrap();
+		links.close_channel();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!running.is_finished(), "Stopped before its links");
+		drop(close);
+		running.await.unwrap().unwrap();
+	}
 }
@@ -0,0 +1,72 @@
+//! Events that rebuild the journey of a request, with the `tracing` feature
+//!
+//! Every shard message is logged at the `TRACE` level when a driver sends it and
+//! on each shard that processes it, within a `request` span holding the driver
+//! and id of its `ReqId`, and whether it belongs to a `find_many` or
+//! `union_many`. Filtering the logs of every system on that span gives
+//! the shards the request went through and the messages it caused, up to its
+//! answer. The messages refused from peers are logged at the `WARN` level.
+//! Without the feature, these functions compile to nothing.
+
+use crate::prelude::*;
+
+/// Keeps the span of a request entered while a shard processes its message, so
+/// that the events of that processing, such as the answer, belong to it
+pub(crate) struct Hop {
+	#[cfg(feature = "tracing")]
+	_span: tracing::span::EnteredSpan,
+}
+
+#[cfg(feature = "tracing")]
+fn span(req_id: ReqId) -> tracing::Span {
+	if req_id == ReqId::unknown() {
+		return tracing::Span::none();
+	}
+	tracing::trace_span!(
+		"request",
+		driver = req_id.driver(),
+		id = req_id
+			.batched_id()
+			.unwrap_or_else(|| req_id.driver_specific_id()),
+		batched = req_id.batched_id().is_some()
+	)
+}
+
+/// A driver of `system` sends `message`
+pub(crate) fn sent(system: u16, message: &ShardMessage) {
+	#[cfg(feature = "tracing")]
+	span(message.req_id()).in_scope(|| tracing::trace!(system, msg = ?message, "sent"));
+	#[cfg(not(feature = "tracing"))]
+	let _ = (system, message);
+}
+
+/// `shard` of `system` processes `message`
+pub(crate) fn hop(system: u16, shard: usize, message: &ShardMessage) -> Hop {
+	#[cfg(feature = "tracing")]
+	{
+		let span = span(message.req_id()).entered();
+		tracing::trace!(system, shard, msg = ?message, "hop");
+		Hop { _span: span }
+	}
+	#[cfg(not(feature = "tracing"))]
+	{
+		let _ = (system, shard, message);
+		Hop {}
+	}
+}
+
+/// A shard answers a driver, within the `Hop` of the message it processes
+pub(crate) fn answered(message: &DriverMessage) {
+	#[cfg(feature = "tracing")]
+	tracing::trace!(msg = ?message, "answered");
+	#[cfg(not(feature = "tracing"))]
+	let _ = message;
+}
+
+/// A message of `peer` was refused and dropped
+pub(crate) fn refused(peer: u16, error: &anyhow::Error) {
+	#[cfg(feature = "tracing")]
+	tracing::warn!(peer, "Refused a message: {error:#}");
+	#[cfg(not(feature = "tracing"))]
+	let _ = (peer, error);
+}
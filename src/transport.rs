@@ -0,0 +1,247 @@
+use std::{
+	collections::HashMap,
+	os::unix::fs::FileTypeExt,
+	sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use futures::{channel::mpsc, future::BoxFuture, FutureExt, StreamExt};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, DuplexStream},
+	net::{TcpListener, UnixListener},
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsAcceptor, TlsConnector};
+
+use crate::address::Address;
+
+/// A byte stream between two systems, which the links frame into
+/// `NetworkMessage`s after the protocol handshake
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// How systems reach each other, set with `NetworkConfig::with_transport`
+///
+/// The links don't depend on what carries their bytes: `SocketTransport`, the
+/// default, uses TCP and Unix sockets, `MemoryTransport` connects the systems
+/// of a single process. TLS, when configured, wraps any of them.
+pub trait Transport: Send + Sync {
+	fn connect<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Connection>>>;
+	fn listen<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Listener>>>;
+}
+
+/// A connection that was accepted, and what remains to do before it can be
+/// used, such as a TLS handshake
+pub type Incoming = BoxFuture<'static, Result<Box<dyn Connection>>>;
+
+pub trait Listener: Send {
+	/// The caller finishes each `Incoming` on its own, so that a slow peer
+	/// doesn't hold back the next ones
+	fn accept(&mut self) -> BoxFuture<'_, Result<Incoming>>;
+}
+
+fn ready(connection: impl Connection + 'static) -> Incoming {
+	futures::future::ready(Ok(Box::new(connection) as Box<dyn Connection>)).boxed()
+}
+
+/// TCP for `Address::Tcp`, Unix sockets for `Address::Unix`
+#[derive(Clone, Copy, Default)]
+pub struct SocketTransport;
+
+impl Transport for SocketTransport {
+	fn connect<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Connection>>> {
+		async move {
+			Ok(match address {
+				Address::Tcp(ip, port) => {
+					Box::new(tokio::net::TcpStream::connect((*ip, *port)).await?)
+						as Box<dyn Connection>
+				}
+				Address::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+			})
+		}
+		.boxed()
+	}
+
+	/// A Unix socket left by a previous run at the same path is replaced, but not
+	/// one that something still listens at
+	fn listen<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+		async move {
+			Ok(match address {
+				Address::Tcp(ip, port) => {
+					Box::new(TcpListener::bind((*ip, *port)).await?) as Box<dyn Listener>
+				}
+				Address::Unix(path) => {
+					if std::fs::metadata(path)
+						.is_ok_and(|metadata| metadata.file_type().is_socket())
+					{
+						ensure!(
+							tokio::net::UnixStream::connect(path).await.is_err(),
+							"Something already listens at {address}"
+						);
+						std::fs::remove_file(path)?;
+					}
+					Box::new(UnixListener::bind(path)?)
+				}
+			})
+		}
+		.boxed()
+	}
+}
+
+impl Listener for TcpListener {
+	fn accept(&mut self) -> BoxFuture<'_, Result<Incoming>> {
+		async move { Ok(ready(TcpListener::accept(self).await?.0)) }.boxed()
+	}
+}
+
+impl Listener for UnixListener {
+	fn accept(&mut self) -> BoxFuture<'_, Result<Incoming>> {
+		async move { Ok(ready(UnixListener::accept(self).await?.0)) }.boxed()
+	}
+}
+
+/// Bytes a direction of a `MemoryTransport` connection holds before the writer
+/// waits for the reader
+const MEMORY_BUFFER_SIZE: usize = 1 << 16;
+
+/// Connects the systems of a single process, whatever their address, which
+/// makes tests fast and independent of the network
+///
+/// The systems must share the same instance, or clones of it.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+	listeners: Arc<Mutex<HashMap<Address, mpsc::UnboundedSender<DuplexStream>>>>,
+}
+
+impl Transport for MemoryTransport {
+	fn connect<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Connection>>> {
+		async move {
+			let (ours, theirs) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+			let listeners = self.listeners.lock().unwrap();
+			listeners
+				.get(address)
+				.and_then(|listener| listener.unbounded_send(theirs).ok())
+				.ok_or_else(|| anyhow!("Nothing listens at {address}"))?;
+			Ok(Box::new(ours) as Box<dyn Connection>)
+		}
+		.boxed()
+	}
+
+	/// Replaces the listener at the same address, as a restarted system would
+	fn listen<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+		async move {
+			let (s, r) = mpsc::unbounded();
+			self.listeners.lock().unwrap().insert(address.clone(), s);
+			Ok(Box::new(r) as Box<dyn Listener>)
+		}
+		.boxed()
+	}
+}
+
+impl Listener for mpsc::UnboundedReceiver<DuplexStream> {
+	fn accept(&mut self) -> BoxFuture<'_, Result<Incoming>> {
+		async move {
+			let stream = self
+				.next()
+				.await
+				.ok_or_else(|| anyhow!("The transport was dropped"))?;
+			Ok(ready(stream))
+		}
+		.boxed()
+	}
+}
+
+/// The TLS settings of `NetworkConfig::with_tls`, applied over its transport
+#[derive(Clone)]
+pub(crate) struct Tls {
+	pub connector: TlsConnector,
+	pub acceptor: TlsAcceptor,
+	pub server_name: ServerName<'static>,
+}
+
+impl Tls {
+	pub fn over(&self, inner: Arc<dyn Transport>) -> Arc<dyn Transport> {
+		Arc::new(TlsTransport {
+			tls: self.clone(),
+			inner,
+		})
+	}
+
+	/// Runs the TLS handshake on the connections of `inner`
+	pub fn listener(&self, inner: Box<dyn Listener>) -> Box<dyn Listener> {
+		Box::new(TlsListener {
+			acceptor: self.acceptor.clone(),
+			inner,
+		})
+	}
+}
+
+struct TlsTransport {
+	tls: Tls,
+	inner: Arc<dyn Transport>,
+}
+
+impl Transport for TlsTransport {
+	fn connect<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Connection>>> {
+		async move {
+			let stream = self.inner.connect(address).await?;
+			let stream = self
+				.tls
+				.connector
+				.connect(self.tls.server_name.clone(), stream)
+				.await?;
+			Ok(Box::new(stream) as Box<dyn Connection>)
+		}
+		.boxed()
+	}
+
+	fn listen<'a>(&'a self, address: &'a Address) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+		async move { Ok(self.tls.listener(self.inner.listen(address).await?)) }.boxed()
+	}
+}
+
+struct TlsListener {
+	acceptor: TlsAcceptor,
+	inner: Box<dyn Listener>,
+}
+
+impl Listener for TlsListener {
+	fn accept(&mut self) -> BoxFuture<'_, Result<Incoming>> {
+		async move {
+			let incoming = self.inner.accept().await?;
+			let acceptor = self.acceptor.clone();
+			Ok(async move {
+				let stream = acceptor.accept(incoming.await?).await?;
+				Ok(Box::new(stream) as Box<dyn Connection>)
+			}
+			.boxed())
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn unix_sockets() {
+		let dir = tempfile::tempdir().unwrap();
+		let address = Address::Unix(dir.path().join("socket"));
+		let mut listener = SocketTransport.listen(&address).await.unwrap();
+		let mut connection = SocketTransport.connect(&address).await.unwrap();
+		let mut accepted = listener.accept().await.unwrap().await.unwrap();
+		connection.write_all(b"ping").await.unwrap();
+		let mut bytes = [0; 4];
+		accepted.read_exact(&mut bytes).await.unwrap();
+		assert_eq!(&bytes, b"ping");
+
+		// A live socket isn't replaced
+		assert!(SocketTransport.listen(&address).await.is_err());
+
+		// The socket left behind by a listener that is gone is
+		drop(listener);
+		SocketTransport.listen(&address).await.unwrap();
+	}
+}
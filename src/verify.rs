@@ -0,0 +1,462 @@
+//! Checks the structure of the union find stored by the shards
+//!
+//! Each node stores its parent, its first child and its next sibling, a link to
+//! the node itself meaning there is none. Following the parents leads to the
+//! root of the component of a node. A union makes the root of a component the
+//! first child of the node it's attached to, so the children of a root, their
+//! own children and so on list the members of its component. The sibling chain
+//! of the children of a node ends with a link back to that node. Path
+//! compression only changes parents, so the lists keep every node where its
+//! union put it. Roots also store the number of nodes in their component.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Nodes a shard sends in each `DriverMessage::VerifyNodes`, so that the
+/// batches stay far below the maximum frame size
+pub(crate) const CHUNK_LEN: u64 = 1 << 14;
+
+/// Violations kept in a `Report`, the next ones are only counted
+const MAX_REPORTED_VIOLATIONS: usize = 1000;
+
+/// The links of a node, pointing to the node itself when unset
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeLinks {
+	pub parent: Key,
+	pub child: Key,
+	pub sibling: Key,
+	/// Of the component, if the node is a root, 0 when unknown
+	pub size: u64,
+}
+
+impl NodeLinks {
+	pub fn read(storage: &impl Storage, node: Key) -> Self {
+		Self {
+			parent: storage.get_parent(node).unwrap_or(node),
+			child: storage.get_child(node).unwrap_or(node),
+			sibling: storage.get_sibling(node).unwrap_or(node),
+			size: storage.get_size(node),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+	Parent,
+	Child,
+	Sibling,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+	/// A link of `node` points to a shard or a node that doesn't exist
+	Dangling { node: Key, link: Link, to: Key },
+	/// Following the parents from `node` comes back to it
+	ParentCycle { node: Key },
+	/// The sibling chain of the children of `node` doesn't come back to it
+	UnendedSiblings { node: Key },
+	/// `node` is listed as a child more than once, or is a root listed as a
+	/// child
+	ListedTwice { node: Key },
+	/// `node` is listed in the component of `root`, but has another root
+	ForeignMember { root: Key, node: Key },
+	/// The root of `node` is `root`, but it isn't listed in its component
+	MissingMember { root: Key, node: Key },
+	/// `root` stores `size`, but is the root of `n_nodes` nodes
+	WrongSize { root: Key, size: u64, n_nodes: u64 },
+}
+
+/// Shorter than the `Debug` of `Key`
+struct Node(Key);
+
+impl fmt::Display for Node {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.0.shard(), self.0.shard_specific_id())
+	}
+}
+
+impl fmt::Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match *self {
+			Violation::Dangling { node, link, to } => write!(
+				f,
+				"The {link:?} link of node {} points to {}, which doesn't exist",
+				Node(node),
+				Node(to)
+			),
+			Violation::ParentCycle { node } => {
+				write!(f, "The parents of node {} loop", Node(node))
+			}
+			Violation::UnendedSiblings { node } => write!(
+				f,
+				"The sibling chain of the children of node {} doesn't end",
+				Node(node)
+			),
+			Violation::ListedTwice { node } => {
+				write!(f, "Node {} is listed more than once", Node(node))
+			}
+			Violation::ForeignMember { root, node } => write!(
+				f,
+				"Node {} is listed in the component of {}, but has another root",
+				Node(node),
+				Node(root)
+			),
+			Violation::MissingMember { root, node } => write!(
+				f,
+				"Node {} has root {} but isn't listed in its component",
+				Node(node),
+				Node(root)
+			),
+			Violation::WrongSize {
+				root,
+				size,
+				n_nodes,
+			} => write!(
+				f,
+				"Root {} stores a size of {size} but has {n_nodes} nodes",
+				Node(root)
+			),
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+	pub n_nodes: u64,
+	/// Roots, including the nodes that were never united
+	pub n_components: u64,
+	/// The first `MAX_REPORTED_VIOLATIONS` found
+	pub violations: Vec<Violation>,
+	pub n_violations: u64,
+}
+
+impl Report {
+	pub fn is_valid(&self) -> bool {
+		self.n_violations == 0
+	}
+
+	fn violation(&mut self, violation: Violation) {
+		self.n_violations += 1;
+		if self.violations.len() < MAX_REPORTED_VIOLATIONS {
+			self.violations.push(violation);
+		}
+	}
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"{} nodes in {} components, {} violations",
+			self.n_nodes, self.n_components, self.n_violations
+		)?;
+		for violation in &self.violations {
+			writeln!(f, "{violation}")?;
+		}
+		let not_shown = self.n_violations - self.violations.len() as u64;
+		if not_shown > 0 {
+			writeln!(f, "and {not_shown} more")?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+	New,
+	OnPath,
+	Done,
+}
+
+/// Gathers the nodes of every shard, then checks that:
+/// - the parents don't loop
+/// - the members listed from each root are exactly the nodes whose root it is
+/// - no link points to a shard or a node that doesn't exist
+/// - every sibling chain ends
+/// - each root stores the size of its component, unless it's unknown, see
+///   `Storage::get_size`
+pub struct ForestCheck {
+	shards: Vec<Vec<NodeLinks>>,
+}
+
+impl ForestCheck {
+	pub fn new(n_shards: usize) -> Self {
+		Self {
+			shards: vec![Vec::new(); n_shards],
+		}
+	}
+
+	/// Reads every node of `shard` from its storage
+	pub fn add_storage(&mut self, shard: usize, storage: &impl Storage) {
+		let nodes = (0..storage.n_nodes()).map(|id| NodeLinks::read(storage, Key::new(shard, id)));
+		self.shards[shard].extend(nodes);
+	}
+
+	/// The next nodes of `shard`, in the order of their ids
+	pub(crate) fn add_nodes(&mut self, shard: usize, nodes: Vec<NodeLinks>) {
+		self.shards[shard].extend(nodes);
+	}
+
+	pub fn check(&self) -> Report {
+		// Nodes are numbered by shard then id, and `offsets` is where each shard
+		// starts
+		let mut offsets = Vec::with_capacity(self.shards.len());
+		let mut nodes = Vec::new();
+		for (shard, links) in self.shards.iter().enumerate() {
+			offsets.push(nodes.len());
+			nodes.extend(
+				links
+					.iter()
+					.zip(0..)
+					.map(|(&links, id)| (Key::new(shard, id), links)),
+			);
+		}
+		let index = |key: Key| {
+			let links = self.shards.get(key.shard())?;
+			let id = key.shard_specific_id() as usize;
+			(id < links.len()).then(|| offsets[key.shard()] + id)
+		};
+		let mut report = Report {
+			n_nodes: nodes.len() as u64,
+			..Report::default()
+		};
+
+		// The next checks stop at these links
+		for &(node, links) in &nodes {
+			for (link, to) in [
+				(Link::Parent, links.parent),
+				(Link::Child, links.child),
+				(Link::Sibling, links.sibling),
+			] {
+				if index(to).is_none() {
+					report.violation(Violation::Dangling { node, link, to });
+				}
+			}
+		}
+
+		// The root of every node, none if its parents loop or dangle
+		let mut roots = vec![None; nodes.len()];
+		let mut visits = vec![Visit::New; nodes.len()];
+		let mut path = Vec::new();
+		for start in 0..nodes.len() {
+			let mut current = start;
+			let root = loop {
+				match visits[current] {
+					Visit::Done => break roots[current],
+					Visit::OnPath => {
+						report.violation(Violation::ParentCycle {
+							node: nodes[current].0,
+						});
+						break None;
+					}
+					Visit::New => {}
+				}
+				visits[current] = Visit::OnPath;
+				path.push(current);
+				match index(nodes[current].1.parent) {
+					Some(parent) if parent == current => break Some(current),
+					Some(parent) => current = parent,
+					None => break None,
+				}
+			};
+			for node in path.drain(..) {
+				visits[node] = Visit::Done;
+				roots[node] = root;
+			}
+		}
+
+		// The node whose children list each node, roots list themselves
+		let mut owners = vec![None; nodes.len()];
+		for (node, &root) in roots.iter().enumerate() {
+			if root == Some(node) {
+				owners[node] = Some(node);
+				report.n_components += 1;
+			}
+		}
+		let mut stack = Vec::new();
+		for root in 0..nodes.len() {
+			if roots[root] != Some(root) {
+				continue;
+			}
+			stack.push(root);
+			while let Some(owner) = stack.pop() {
+				let mut current = nodes[owner].1.child;
+				while let Some(child) = index(current) {
+					if child == owner {
+						break;
+					}
+					if let Some(previous_owner) = owners[child] {
+						report.violation(if previous_owner == owner {
+							Violation::UnendedSiblings {
+								node: nodes[owner].0,
+							}
+						} else {
+							Violation::ListedTwice {
+								node: nodes[child].0,
+							}
+						});
+						break;
+					}
+					owners[child] = Some(owner);
+					if roots[child] != Some(root) {
+						report.violation(Violation::ForeignMember {
+							root: nodes[root].0,
+							node: nodes[child].0,
+						});
+					}
+					stack.push(child);
+					current = nodes[child].1.sibling;
+				}
+			}
+		}
+		for (node, (&owner, &root)) in owners.iter().zip(&roots).enumerate() {
+			if let (None, Some(root)) = (owner, root) {
+				report.violation(Violation::MissingMember {
+					root: nodes[root].0,
+					node: nodes[node].0,
+				});
+			}
+		}
+		let mut sizes = vec![0; nodes.len()];
+		for &root in roots.iter().flatten() {
+			sizes[root] += 1;
+		}
+		for (root, &n_nodes) in sizes.iter().enumerate() {
+			let (root, links) = nodes[root];
+			if n_nodes > 0 && links.size != 0 && links.size != n_nodes {
+				report.violation(Violation::WrongSize {
+					root,
+					size: links.size,
+					n_nodes,
+				});
+			}
+		}
+		report
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::ram::RamStorage;
+
+	/// Two shards of 3 nodes, `unite` makes the root `node` a child of `to` as
+	/// the shards do
+	struct Forest {
+		shards: [RamStorage; 2],
+	}
+
+	impl Forest {
+		fn new() -> Self {
+			let mut shards = [RamStorage::default(), RamStorage::default()];
+			for (shard, storage) in shards.iter_mut().enumerate() {
+				for _ in 0..3 {
+					storage.add_node(shard);
+				}
+			}
+			Self { shards }
+		}
+
+		fn storage(&mut self, key: Key) -> &mut RamStorage {
+			&mut self.shards[key.shard()]
+		}
+
+		fn unite(&mut self, node: Key, to: Key) {
+			let mut root = to;
+			while let Some(parent) = self.storage(root).get_parent(root) {
+				root = parent;
+			}
+			let size = self.storage(root).get_size(root) + self.storage(node).get_size(node);
+			self.storage(root).set_size(root, size);
+			self.storage(node).set_parent(node, to);
+			let previous_child = self.storage(to).swap_child(to, node);
+			self.storage(node).set_sibling(node, previous_child);
+		}
+
+		fn check(&self) -> Report {
+			let mut forest = ForestCheck::new(self.shards.len());
+			for (shard, storage) in self.shards.iter().enumerate() {
+				forest.add_storage(shard, storage);
+			}
+			forest.check()
+		}
+	}
+
+	fn key(shard: usize, id: u64) -> Key {
+		Key::new(shard, id)
+	}
+
+	#[test]
+	fn valid_forest() {
+		let mut forest = Forest::new();
+		forest.unite(key(0, 1), key(1, 0));
+		forest.unite(key(0, 2), key(1, 0));
+		forest.unite(key(1, 0), key(0, 0));
+		forest.unite(key(1, 2), key(0, 1));
+		// Path compression
+		forest.storage(key(0, 1)).set_parent(key(0, 1), key(0, 0));
+		let report = forest.check();
+		assert!(report.is_valid(), "{report}");
+		assert_eq!((report.n_nodes, report.n_components), (6, 2));
+		// Unknown sizes aren't checked
+		forest.storage(key(0, 0)).set_size(key(0, 0), 0);
+		assert!(forest.check().is_valid());
+	}
+
+	#[test]
+	fn violations() {
+		let mut forest = Forest::new();
+		forest.unite(key(0, 1), key(1, 0));
+		forest.unite(key(0, 2), key(0, 1));
+		// A union within its own component
+		forest.unite(key(1, 0), key(0, 2));
+		forest.storage(key(1, 1)).set_sibling(key(1, 1), key(3, 0));
+		assert_eq!(
+			forest.check().violations,
+			[
+				Violation::Dangling {
+					node: key(1, 1),
+					link: Link::Sibling,
+					to: key(3, 0),
+				},
+				Violation::ParentCycle { node: key(0, 1) },
+			]
+		);
+
+		let mut forest = Forest::new();
+		forest.unite(key(0, 1), key(0, 0));
+		forest.unite(key(0, 2), key(0, 0));
+		// Loses the first child of the list, then loops on the second one, which
+		// moved to another component
+		forest.storage(key(0, 0)).swap_child(key(0, 0), key(0, 1));
+		forest.storage(key(0, 1)).set_sibling(key(0, 1), key(0, 1));
+		forest.storage(key(0, 1)).set_parent(key(0, 1), key(1, 2));
+		assert_eq!(
+			forest.check().violations,
+			[
+				Violation::ForeignMember {
+					root: key(0, 0),
+					node: key(0, 1),
+				},
+				Violation::UnendedSiblings { node: key(0, 0) },
+				Violation::MissingMember {
+					root: key(0, 0),
+					node: key(0, 2),
+				},
+				Violation::WrongSize {
+					root: key(0, 0),
+					size: 3,
+					n_nodes: 2,
+				},
+				Violation::WrongSize {
+					root: key(1, 2),
+					size: 1,
+					n_nodes: 2,
+				},
+			]
+		);
+	}
+}
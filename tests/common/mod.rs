@@ -0,0 +1,263 @@
+//! Random workloads run through drivers and checked against a sequential union
+//! find
+
+// Each test crate uses a part of it
+#![allow(dead_code)]
+
+use std::{collections::BTreeMap, time::Duration};
+
+pub use big_uf::simulation::Rng;
+use big_uf::*;
+
+/// Long enough for a loaded CI machine, short enough that a lost answer fails
+/// the test instead of hanging it
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The sequential union find the system is compared with
+pub struct Model {
+	parents: Vec<usize>,
+}
+
+impl Model {
+	pub fn new(n_nodes: usize) -> Self {
+		Self {
+			parents: (0..n_nodes).collect(),
+		}
+	}
+
+	pub fn find(&mut self, mut node: usize) -> usize {
+		while self.parents[node] != node {
+			self.parents[node] = self.parents[self.parents[node]];
+			node = self.parents[node];
+		}
+		node
+	}
+
+	pub fn union(&mut self, a: usize, b: usize) {
+		let (a, b) = (self.find(a), self.find(b));
+		self.parents[a] = b;
+	}
+
+	pub fn same(&mut self, a: usize, b: usize) -> bool {
+		self.find(a) == self.find(b)
+	}
+
+	pub fn n_components(&mut self) -> u64 {
+		(0..self.parents.len())
+			.filter(|&node| self.find(node) == node)
+			.count() as u64
+	}
+}
+
+/// Nodes are numbered by the order they are added in
+pub struct Workload {
+	/// Shard of each node
+	pub shards: Vec<u16>,
+	/// Sent concurrently by all the drivers, a wave starting once the unions of
+	/// the previous one are answered
+	pub waves: Vec<Wave>,
+	/// The partition after all the waves
+	pub model: Model,
+}
+
+pub struct Wave {
+	pub unions: Vec<(usize, usize)>,
+	/// Answered with a root of the component of the node at some point of the
+	/// wave
+	pub finds: Vec<usize>,
+}
+
+impl Workload {
+	/// The unions of a wave race each other, and some of them unite nodes that
+	/// are already in the same component
+	pub fn random(seed: u64, n_nodes: usize, n_shards: u16, n_waves: usize) -> Self {
+		let mut rng = Rng::new(seed);
+		let shards = (0..n_nodes)
+			.map(|_| rng.below(n_shards as usize) as u16)
+			.collect();
+		let mut model = Model::new(n_nodes);
+		let waves = (0..n_waves)
+			.map(|_| {
+				let wave = Wave {
+					unions: (0..n_nodes / 4)
+						.map(|_| (rng.below(n_nodes), rng.below(n_nodes)))
+						.collect(),
+					finds: (0..n_nodes / 8).map(|_| rng.below(n_nodes)).collect(),
+				};
+				for &(a, b) in &wave.unions {
+					model.union(a, b);
+				}
+				wave
+			})
+			.collect();
+		Self {
+			shards,
+			waves,
+			model,
+		}
+	}
+}
+
+fn receive(driver: &Driver, context: &str) -> Vec<DriverMessage> {
+	match driver.receiver().recv_timeout(ANSWER_TIMEOUT) {
+		Ok(batch) => batch,
+		Err(error) => panic!("{context}: no answer for {ANSWER_TIMEOUT:?}: {error}"),
+	}
+}
+
+/// Sends each request, numbered by its index, through the drivers in turn and
+/// returns the answers by index, all drivers running concurrently
+fn requests<T: Send>(
+	drivers: &mut [Driver],
+	n_requests: usize,
+	send: impl Fn(&mut Driver, usize) + Sync,
+	answer: impl Fn(DriverMessage) -> Option<(usize, T)> + Sync,
+	context: &str,
+) -> Vec<T> {
+	let n_drivers = drivers.len();
+	let mut answers: Vec<(usize, T)> = std::thread::scope(|scope| {
+		let threads: Vec<_> = drivers
+			.iter_mut()
+			.enumerate()
+			.map(|(driver_idx, driver)| {
+				let (send, answer) = (&send, &answer);
+				scope.spawn(move || {
+					let mine: Vec<usize> = (driver_idx..n_requests).step_by(n_drivers).collect();
+					for &request in &mine {
+						send(driver, request);
+					}
+					driver.flush();
+					let mut answers = Vec::new();
+					while answers.len() < mine.len() {
+						for message in receive(driver, context) {
+							if let DriverMessage::SystemFailure { error } = message {
+								panic!("{context}: the system failed: {error}");
+							}
+							answers.extend(answer(message));
+						}
+					}
+					answers
+				})
+			})
+			.collect();
+		threads
+			.into_iter()
+			.flat_map(|thread| thread.join().unwrap())
+			.collect()
+	});
+	answers.sort_by_key(|&(request, _)| request);
+	answers.into_iter().map(|(_, answer)| answer).collect()
+}
+
+/// Runs the workload through the drivers, checks every answer against the
+/// model, then the structure stored by the shards, and returns what it found
+/// along with the key of each node
+pub fn run(
+	drivers: &mut [Driver],
+	workload: &mut Workload,
+	context: &str,
+) -> (verify::Report, Vec<Key>) {
+	let shards = &workload.shards;
+	let keys: Vec<Key> = requests(
+		drivers,
+		shards.len(),
+		|driver, node| driver.add_node(node as u64, shards[node]),
+		|message| match message {
+			DriverMessage::AddNodeDone { req_id, response } => {
+				Some((req_id.driver_specific_id() as usize, response))
+			}
+			_ => None,
+		},
+		context,
+	);
+	let nodes: BTreeMap<Key, usize> = keys
+		.iter()
+		.zip(0..)
+		.map(|(&key, node)| (key, node))
+		.collect();
+	assert_eq!(nodes.len(), keys.len(), "{context}: a key was given twice");
+	for (node, key) in keys.iter().enumerate() {
+		assert_eq!(key.shard(), shards[node] as usize, "{context}: node {node}");
+	}
+
+	let mut found = Vec::new();
+	for wave in &workload.waves {
+		let n_unions = wave.unions.len();
+		let roots = requests(
+			drivers,
+			n_unions + wave.finds.len(),
+			|driver, request| match wave.unions.get(request) {
+				Some(&(a, b)) => driver.union(request as u64, keys[a], keys[b]),
+				None => driver.find(request as u64, keys[wave.finds[request - n_unions]]),
+			},
+			|message| match message {
+				DriverMessage::UnionDone { req_id } => {
+					Some((req_id.driver_specific_id() as usize, None))
+				}
+				DriverMessage::FindDone { req_id, response } => {
+					Some((req_id.driver_specific_id() as usize, Some(response)))
+				}
+				_ => None,
+			},
+			context,
+		);
+		found.extend(wave.finds.iter().zip(roots.into_iter().flatten()));
+	}
+
+	let model = &mut workload.model;
+	for (&node, root) in found {
+		let root = nodes[&root];
+		assert!(
+			model.same(node, root),
+			"{context}: a find of node {node} answered node {root}, from another component"
+		);
+	}
+	check_partition(drivers, &keys, model, context);
+
+	let (report, _) = drivers[0]
+		.verify(0)
+		.unwrap_or_else(|error| panic!("{context}: the system failed: {error}"));
+	assert!(report.is_valid(), "{context}: {report}");
+	(report, keys)
+}
+
+/// Finds every node, and checks that the system holds the partition of the
+/// model: one root for each component, the smallest node of the component
+pub fn check_partition(drivers: &mut [Driver], keys: &[Key], model: &mut Model, context: &str) {
+	let nodes: BTreeMap<Key, usize> = keys
+		.iter()
+		.zip(0..)
+		.map(|(&key, node)| (key, node))
+		.collect();
+	let roots = requests(
+		drivers,
+		keys.len(),
+		|driver, node| driver.find(node as u64, keys[node]),
+		|message| match message {
+			DriverMessage::FindDone { req_id, response } => {
+				Some((req_id.driver_specific_id() as usize, response))
+			}
+			_ => None,
+		},
+		context,
+	);
+	let mut model_roots: BTreeMap<Key, usize> = BTreeMap::new();
+	for (node, &root) in roots.iter().enumerate() {
+		assert!(
+			root <= keys[node],
+			"{context}: node {node} has a larger root, node {}",
+			nodes[&root]
+		);
+		let model_root = model.find(node);
+		let previous = *model_roots.entry(root).or_insert(model_root);
+		assert_eq!(
+			previous, model_root,
+			"{context}: node {node} has the root of the component of node {previous}"
+		);
+	}
+	assert_eq!(
+		model_roots.len() as u64,
+		model.n_components(),
+		"{context}: a component has several roots"
+	);
+}
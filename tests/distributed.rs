@@ -0,0 +1,103 @@
+mod common;
+
+use std::time::Duration;
+
+use big_uf::{
+	storage::ram::RamStorage, Address, MemoryTransport, NetworkConfig, System, Transport,
+};
+use common::Workload;
+use tokio::task::JoinHandle;
+
+/// A master and two workers on localhost ports, each seed on its own cluster
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn tcp_localhost() {
+	for seed in 0..2u16 {
+		let mut servers = Vec::new();
+		let mut workers = Vec::new();
+		for _ in 0..2 {
+			let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+			workers.push(listener.local_addr().unwrap().port());
+			servers.push(tokio::spawn(System::server_with_listener(
+				Box::new(listener),
+				|_| RamStorage::default,
+				NetworkConfig::default(),
+			)));
+		}
+		let connected = System::connect(3, workers, NetworkConfig::default()).await;
+		check_servers(&mut servers).await;
+		let (driver, system, _shards, links) = connected.unwrap();
+		let links = tokio::spawn(links);
+
+		let context = format!("seed {seed}");
+		let mut workload = Workload::random(seed as u64, 400, system.n_shards() as u16, 8);
+		let mut drivers = [driver];
+		let (report, _) =
+			tokio::task::block_in_place(|| common::run(&mut drivers, &mut workload, &context));
+		assert_eq!(
+			report.n_components,
+			workload.model.n_components(),
+			"{context}"
+		);
+		assert!(!links.is_finished(), "{context}: a link failed");
+		check_servers(&mut servers).await;
+	}
+}
+
+/// Fails with the error of the servers that stopped
+async fn check_servers(servers: &mut [JoinHandle<anyhow::Result<()>>]) {
+	for server in servers {
+		if server.is_finished() {
+			server.await.unwrap().unwrap();
+			panic!("A server stopped");
+		}
+	}
+}
+
+/// A peer that accepts the connection but never answers fails the handshake
+/// instead of hanging the master
+#[tokio::test]
+async fn silent_peer() {
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let port = listener.local_addr().unwrap().port();
+	tokio::spawn(async move {
+		let mut connections = Vec::new();
+		while let Ok(connection) = listener.accept().await {
+			connections.push(connection);
+		}
+	});
+	let connecting = System::connect(1, vec![port], NetworkConfig::default());
+	let result = tokio::time::timeout(Duration::from_secs(30), connecting)
+		.await
+		.expect("The handshake never timed out");
+	assert!(result.is_err());
+}
+
+/// The same cluster over `MemoryTransport`, whose listeners exist before the
+/// workers are spawned
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn memory_transport() {
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default().with_transport(transport.clone());
+	let workers = [1, 2].map(Address::from);
+	let mut servers = Vec::new();
+	for address in &workers {
+		let listener = transport.listen(address).await.unwrap();
+		servers.push(tokio::spawn(System::server_with_listener(
+			listener,
+			|_| RamStorage::default,
+			config.clone(),
+		)));
+	}
+	let connected = System::connect(3, workers.to_vec(), config.with_replication_factor(1)).await;
+	check_servers(&mut servers).await;
+	let (driver, system, _shards, links) = connected.unwrap();
+	let links = tokio::spawn(links);
+
+	let mut workload = Workload::random(7, 400, system.n_shards() as u16, 8);
+	let mut drivers = [driver];
+	let (report, _) =
+		tokio::task::block_in_place(|| common::run(&mut drivers, &mut workload, "memory"));
+	assert_eq!(report.n_components, workload.model.n_components());
+	assert!(!links.is_finished(), "A link failed");
+	check_servers(&mut servers).await;
+}
@@ -0,0 +1,388 @@
+mod common;
+
+use std::{
+	io,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use anyhow::anyhow;
+use big_uf::*;
+use common::Workload;
+use futures::{future::BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The transport of one system, over a `MemoryTransport` shared by all, whose
+/// connections can be broken as if the network failed, or frozen as if the
+/// system hung
+#[derive(Clone, Default)]
+struct Cuttable {
+	inner: MemoryTransport,
+	state: Arc<CutState>,
+}
+
+#[derive(Default)]
+struct CutState {
+	/// The connections made before the last cut fail
+	generation: AtomicU64,
+	/// Nothing is read nor written anymore, and no connection is made
+	frozen: AtomicBool,
+	/// Kept open, so that the peers only notice the silence
+	closed: Mutex<Vec<Box<dyn Connection>>>,
+}
+
+impl Cuttable {
+	/// Another system, over the same memory transport
+	fn sibling(&self) -> Self {
+		Self {
+			inner: self.inner.clone(),
+			state: Arc::default(),
+		}
+	}
+
+	/// Breaks the current connections, the next ones work
+	fn cut(&self) {
+		self.state.generation.fetch_add(1, Ordering::SeqCst);
+	}
+
+	fn freeze(&self) {
+		self.state.frozen.store(true, Ordering::SeqCst);
+	}
+
+	fn wrap(&self, inner: Box<dyn Connection>) -> Box<dyn Connection> {
+		Box::new(CutConnection {
+			inner,
+			generation: self.state.generation.load(Ordering::SeqCst),
+			state: self.state.clone(),
+		})
+	}
+}
+
+impl Transport for Cuttable {
+	fn connect<'a>(
+		&'a self,
+		address: &'a Address,
+	) -> BoxFuture<'a, anyhow::Result<Box<dyn Connection>>> {
+		async move {
+			if self.state.frozen.load(Ordering::SeqCst) {
+				return Err(anyhow!("The system is frozen"));
+			}
+			Ok(self.wrap(self.inner.connect(address).await?))
+		}
+		.boxed()
+	}
+
+	fn listen<'a>(
+		&'a self,
+		address: &'a Address,
+	) -> BoxFuture<'a, anyhow::Result<Box<dyn Listener>>> {
+		async move {
+			Ok(Box::new(CutListener {
+				transport: self.clone(),
+				inner: self.inner.listen(address).await?,
+			}) as Box<dyn Listener>)
+		}
+		.boxed()
+	}
+}
+
+struct CutListener {
+	transport: Cuttable,
+	inner: Box<dyn Listener>,
+}
+
+impl Listener for CutListener {
+	/// A frozen system drops what it accepts, which its peers see as closed
+	fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Incoming>> {
+		async move {
+			loop {
+				let incoming = self.inner.accept().await?;
+				if !self.transport.state.frozen.load(Ordering::SeqCst) {
+					let transport = self.transport.clone();
+					return Ok(async move { Ok(transport.wrap(incoming.await?)) }.boxed());
+				}
+			}
+		}
+		.boxed()
+	}
+}
+
+struct CutConnection {
+	inner: Box<dyn Connection>,
+	generation: u64,
+	state: Arc<CutState>,
+}
+
+impl CutConnection {
+	/// Checked on every poll: a connection waiting to read is woken by what the
+	/// peer sends, at least its heartbeats
+	fn check<T>(&self) -> Option<Poll<io::Result<T>>> {
+		if self.state.frozen.load(Ordering::SeqCst) {
+			Some(Poll::Pending)
+		} else if self.state.generation.load(Ordering::SeqCst) != self.generation {
+			Some(Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())))
+		} else {
+			None
+		}
+	}
+}
+
+impl Drop for CutConnection {
+	fn drop(&mut self) {
+		if self.state.frozen.load(Ordering::SeqCst) {
+			let inner = std::mem::replace(&mut self.inner, Box::new(tokio::io::duplex(1).0));
+			self.state.closed.lock().unwrap().push(inner);
+		}
+	}
+}
+
+impl AsyncRead for CutConnection {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		if let Some(poll) = self.check() {
+			return poll;
+		}
+		Pin::new(&mut self.inner).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for CutConnection {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		if let Some(poll) = self.check() {
+			return poll;
+		}
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		if let Some(poll) = self.check() {
+			return poll;
+		}
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// A master and two workers, with the transport of each
+struct Cluster {
+	driver: Driver,
+	system: Arc<System>,
+	transports: Vec<Cuttable>,
+}
+
+async fn cluster(replication_factor: u16) -> Cluster {
+	let master = Cuttable::default();
+	let mut transports = vec![master.clone()];
+	let workers = [1, 2].map(Address::from);
+	for address in &workers {
+		let transport = master.sibling();
+		let listener = transport.listen(address).await.unwrap();
+		tokio::spawn(System::server_with_listener(
+			listener,
+			|_| storage::ram::RamStorage::default,
+			config(&transport),
+		));
+		transports.push(transport);
+	}
+	let config = config(&master).with_replication_factor(replication_factor);
+	let (driver, system, _shards, links) =
+		System::connect(3, workers.to_vec(), config).await.unwrap();
+	tokio::spawn(links);
+	Cluster {
+		driver,
+		system,
+		transports,
+	}
+}
+
+fn config(transport: &Cuttable) -> NetworkConfig {
+	NetworkConfig::default()
+		.with_transport(transport.clone())
+		.with_reconnect_timeout(Duration::from_secs(1))
+}
+
+/// Waits for the failure the system reports to the driver, skipping the
+/// answers
+fn failure(driver: &Driver) -> SystemError {
+	loop {
+		let batch = driver
+			.receiver()
+			.recv_timeout(Duration::from_secs(30))
+			.expect("The failure was never reported");
+		for message in batch {
+			if let DriverMessage::SystemFailure { error } = message {
+				return error;
+			}
+		}
+	}
+}
+
+/// A worker that hangs is detected by the heartbeats, and the requests it held
+/// are released with the failure
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn frozen_worker() {
+	let Cluster {
+		mut driver,
+		system,
+		transports,
+	} = cluster(0).await;
+	let mut workload = Workload::random(1, 200, system.n_shards() as u16, 2);
+	let (_, keys) = tokio::task::block_in_place(|| {
+		common::run(std::slice::from_mut(&mut driver), &mut workload, "before")
+	});
+
+	transports[2].freeze();
+	for (i, &key) in keys.iter().enumerate() {
+		driver.find(i as u64, key);
+	}
+	driver.flush();
+	let error = tokio::task::block_in_place(|| failure(&driver));
+	assert_eq!(error, SystemError::HeartbeatTimeout { peer: 2 });
+	// The first report may come from the other worker
+	let failed = async {
+		while system.health() == ClusterHealth::Healthy {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(30), failed)
+		.await
+		.expect("The master never noticed");
+	assert_eq!(system.health(), ClusterHealth::Failed(error));
+}
+
+/// Requests sent while the links are down are answered once they reconnect
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn reconnect() {
+	let Cluster {
+		mut driver,
+		system,
+		transports,
+	} = cluster(0).await;
+	transports[2].cut();
+	let mut workload = Workload::random(2, 200, system.n_shards() as u16, 4);
+	let (report, _) = tokio::task::block_in_place(|| {
+		common::run(
+			std::slice::from_mut(&mut driver),
+			&mut workload,
+			"reconnect",
+		)
+	});
+	assert_eq!(report.n_components, workload.model.n_components());
+	assert_eq!(system.health(), ClusterHealth::Healthy);
+}
+
+/// With two copies of each shard, the master serves the shards of a worker that
+/// hangs, with the nodes they held, and the cluster keeps working
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn failover() {
+	let Cluster {
+		mut driver,
+		system,
+		transports,
+	} = cluster(2).await;
+	let drivers = std::slice::from_mut(&mut driver);
+	let mut before = Workload::random(3, 200, system.n_shards() as u16, 4);
+	let (_, keys) = tokio::task::block_in_place(|| common::run(drivers, &mut before, "before"));
+	// The mutations reach the replicas after their answers
+	tokio::time::sleep(Duration::from_millis(500)).await;
+
+	transports[2].freeze();
+	// From the master and from the other worker
+	for _ in 0..2 {
+		let error = tokio::task::block_in_place(|| failure(&drivers[0]));
+		assert_eq!(error, SystemError::HeartbeatTimeout { peer: 2 });
+	}
+	assert!(
+		matches!(system.health(), ClusterHealth::Degraded(_)),
+		"{:?}",
+		system.health()
+	);
+	assert!((0..system.n_shards()).all(|shard| system.shard_owner(shard) != 2));
+
+	tokio::task::block_in_place(|| {
+		common::check_partition(drivers, &keys, &mut before.model, "failover");
+		let mut after = Workload::random(4, 200, system.n_shards() as u16, 4);
+		let (report, _) = common::run(drivers, &mut after, "after");
+		assert_eq!(
+			report.n_components,
+			before.model.n_components() + after.model.n_components()
+		);
+	});
+}
+
+/// A worker that restarts after a shard moved away from it learns the current
+/// owners from the master, and doesn't serve that shard again
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn restart_after_move() {
+	let Cluster {
+		mut driver,
+		system,
+		transports,
+	} = cluster(0).await;
+	// The second shard of worker 1
+	let moved = 4;
+	driver.move_shard(0, moved, 2);
+	driver.flush();
+	let answer = tokio::task::block_in_place(|| {
+		driver
+			.receiver()
+			.recv_timeout(Duration::from_secs(30))
+			.expect("The shard never moved")
+	});
+	assert!(
+		matches!(answer[..], [DriverMessage::MoveShardDone { shard, .. }] if shard == moved),
+		"{answer:?}"
+	);
+
+	// The same worker, started again at the same address
+	transports[1].freeze();
+	let transport = transports[0].sibling();
+	let listener = transport.listen(&Address::from(1)).await.unwrap();
+	let opened = Arc::new(Mutex::new(Vec::new()));
+	tokio::spawn(System::server_with_listener(
+		listener,
+		{
+			let opened = opened.clone();
+			move |shard_id| {
+				let opened = opened.clone();
+				move || {
+					opened.lock().unwrap().push(shard_id);
+					storage::ram::RamStorage::default()
+				}
+			}
+		},
+		config(&transport),
+	));
+	let error = tokio::task::block_in_place(|| failure(&driver));
+	assert_eq!(error, SystemError::PeerRestarted { peer: 1 });
+	let reopened = async {
+		while opened.lock().unwrap().len() < 2 {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(30), reopened)
+		.await
+		.expect("The worker never served its shards");
+	// A shard it shouldn't serve would be opened along with them
+	tokio::time::sleep(Duration::from_millis(200)).await;
+
+	let mut opened = opened.lock().unwrap().clone();
+	opened.sort();
+	assert_eq!(opened, [3, 5]);
+	assert_eq!(system.shard_owner(moved as usize), 2);
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use std::{
+	net::{IpAddr, Ipv4Addr},
+	path::Path,
+	time::Duration,
+};
+
+use big_uf::{
+	storage::ram::RamStorage, Address, Driver, MemoryTransport, NetworkConfig, System, Transport,
+};
+use common::Workload;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	task::JoinHandle,
+};
+
+/// The certificates in `tests/tls` are valid for this name
+const SERVER_NAME: &str = "big_uf";
+
+/// `node` is signed by `ca.pem`, `untrusted` by another CA
+fn tls(config: NetworkConfig, cert: &str) -> NetworkConfig {
+	let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+	config
+		.with_tls(
+			dir.join(format!("{cert}.pem")),
+			dir.join(format!("{cert}.key")),
+			dir.join("ca.pem"),
+			SERVER_NAME,
+		)
+		.unwrap()
+}
+
+/// A worker listening on `Address::from(1)` of `transport`
+async fn worker(
+	transport: &MemoryTransport,
+	config: NetworkConfig,
+) -> JoinHandle<anyhow::Result<()>> {
+	let listener = transport.listen(&Address::from(1)).await.unwrap();
+	tokio::spawn(System::server_with_listener(
+		listener,
+		|_| RamStorage::default,
+		config,
+	))
+}
+
+/// A master of the worker at `Address::from(1)`, or the reason it was refused
+async fn master(
+	config: NetworkConfig,
+) -> anyhow::Result<(
+	Driver,
+	std::sync::Arc<System>,
+	JoinHandle<anyhow::Result<()>>,
+)> {
+	let connecting = System::connect(2, vec![Address::from(1)], config);
+	let (driver, system, _shards, links) =
+		tokio::time::timeout(Duration::from_secs(30), connecting)
+			.await
+			.expect("The handshake never ended")?;
+	Ok((driver, system, tokio::spawn(links)))
+}
+
+/// Runs a workload through a master that joined
+fn check_workload(driver: Driver, system: &System, context: &str) {
+	let mut workload = Workload::random(3, 200, system.n_shards() as u16, 4);
+	let mut drivers = [driver];
+	let (report, _) =
+		tokio::task::block_in_place(|| common::run(&mut drivers, &mut workload, context));
+	assert_eq!(
+		report.n_components,
+		workload.model.n_components(),
+		"{context}"
+	);
+}
+
+/// Masters with a missing or wrong token are refused, then one with the right
+/// token joins the same worker
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cluster_token() {
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default().with_transport(transport.clone());
+	let server = worker(&transport, config.clone().with_cluster_token("secret")).await;
+
+	for (config, context) in [
+		(config.clone(), "missing token"),
+		(config.clone().with_cluster_token("wrong"), "wrong token"),
+	] {
+		assert!(master(config).await.is_err(), "{context} was accepted");
+		assert!(!server.is_finished(), "{context} stopped the worker");
+	}
+
+	let (driver, system, links) = master(config.with_cluster_token("secret")).await.unwrap();
+	check_workload(driver, &system, "right token");
+	assert!(!links.is_finished(), "A link failed");
+	assert!(!server.is_finished(), "The worker stopped");
+}
+
+/// A master refuses a worker that doesn't present its token
+#[tokio::test]
+async fn worker_without_token() {
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default().with_transport(transport.clone());
+	let _server = worker(&transport, config.clone()).await;
+	let error = master(config.with_cluster_token("secret"))
+		.await
+		.err()
+		.unwrap();
+	assert!(
+		error.to_string().contains("wrong cluster token"),
+		"{error:#}"
+	);
+}
+
+/// A master presenting a certificate of an untrusted CA fails the TLS
+/// handshake, even with the right token, then a trusted one joins
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn mutual_tls() {
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default()
+		.with_transport(transport.clone())
+		.with_cluster_token("secret");
+	let server = worker(&transport, tls(config.clone(), "node")).await;
+
+	assert!(
+		master(tls(config.clone(), "untrusted")).await.is_err(),
+		"The untrusted certificate was accepted"
+	);
+	assert!(
+		master(config.clone()).await.is_err(),
+		"A master without TLS was accepted"
+	);
+	assert!(!server.is_finished(), "The worker stopped");
+
+	let (driver, system, links) = master(tls(config, "node")).await.unwrap();
+	check_workload(driver, &system, "trusted certificate");
+	assert!(!links.is_finished(), "A link failed");
+	assert!(!server.is_finished(), "The worker stopped");
+}
+
+/// The admin API answers 401 to requests without the right bearer token
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn admin_token() {
+	let dir = tempfile::tempdir().unwrap();
+	let socket = dir.path().join("admin.sock");
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default()
+		.with_transport(transport.clone())
+		.with_cluster_token("secret");
+	let _server = worker(&transport, config.clone()).await;
+	let (_driver, _system, _links) =
+		master(config.with_admin_endpoint(Address::Unix(socket.clone())))
+			.await
+			.unwrap();
+
+	for (authorization, status) in [
+		("", "401"),
+		("Authorization: Bearer wrong\r\n", "401"),
+		("Authorization: secret\r\n", "401"),
+		("Authorization: Bearer secret\r\n", "200"),
+	] {
+		let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+		let request = format!("GET /status HTTP/1.1\r\nHost: localhost\r\n{authorization}\r\n");
+		stream.write_all(request.as_bytes()).await.unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+		assert!(
+			response.starts_with(&format!("HTTP/1.1 {status} ")),
+			"{authorization:?}: {response}"
+		);
+	}
+}
+
+/// The admin API doesn't start without a cluster token, nor on other addresses
+/// than loopback ones, as the token travels in clear
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn admin_needs_a_token() {
+	let dir = tempfile::tempdir().unwrap();
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default().with_transport(transport.clone());
+	let _server = worker(&transport, config.clone()).await;
+	let error = master(config.with_admin_endpoint(Address::Unix(dir.path().join("admin.sock"))))
+		.await
+		.err()
+		.unwrap();
+	assert!(
+		error.to_string().contains("needs a cluster token"),
+		"{error:#}"
+	);
+
+	let transport = MemoryTransport::default();
+	let config = NetworkConfig::default()
+		.with_transport(transport.clone())
+		.with_cluster_token("secret");
+	let _server = worker(&transport, config.clone()).await;
+	let everywhere: (IpAddr, u16) = (Ipv4Addr::UNSPECIFIED.into(), 0);
+	let error = master(config.with_admin_endpoint(everywhere))
+		.await
+		.err()
+		.unwrap();
+	assert!(error.to_string().contains("loopback"), "{error:#}");
+}
@@ -0,0 +1,147 @@
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use big_uf::{simulation::Simulation, verify::Report, DriverMessage, Key};
+use common::Workload;
+
+/// Runs the workload wave after wave, checks every answer against the model,
+/// and returns what `verify` found
+///
+/// Requests may be answered more than once, when they are duplicated.
+fn simulate(simulation: &mut Simulation, workload: &mut Workload, context: &str) -> Report {
+	let requests: BTreeMap<u64, usize> = workload
+		.shards
+		.iter()
+		.enumerate()
+		.map(|(node, &shard)| (simulation.add_node(shard), node))
+		.collect();
+	let mut keys = vec![None; workload.shards.len()];
+	for answer in simulation
+		.run()
+		.unwrap_or_else(|error| panic!("{context}: {error}"))
+	{
+		if let DriverMessage::AddNodeDone { req_id, response } = answer {
+			keys[requests[&req_id.driver_specific_id()]] = Some(response);
+		}
+	}
+	let keys: Vec<Key> = keys
+		.into_iter()
+		.map(|key| key.unwrap_or_else(|| panic!("{context}: a node wasn't added")))
+		.collect();
+	let nodes: BTreeMap<Key, usize> = keys
+		.iter()
+		.zip(0..)
+		.map(|(&key, node)| (key, node))
+		.collect();
+
+	let mut found = Vec::new();
+	let mut n_finds = 0;
+	for wave in &workload.waves {
+		for &(a, b) in &wave.unions {
+			simulation.union(keys[a], keys[b]);
+		}
+		let finds: BTreeMap<u64, usize> = wave
+			.finds
+			.iter()
+			.map(|&node| (simulation.find(keys[node]), node))
+			.collect();
+		let mut unions = BTreeSet::new();
+		let mut answered_finds = BTreeSet::new();
+		for answer in simulation
+			.run()
+			.unwrap_or_else(|error| panic!("{context}: {error}"))
+		{
+			match answer {
+				DriverMessage::UnionDone { req_id } => {
+					unions.insert(req_id);
+				}
+				DriverMessage::FindDone { req_id, response } => {
+					answered_finds.insert(req_id);
+					found.push((finds[&req_id.driver_specific_id()], nodes[&response]))
+				}
+				_ => {}
+			}
+		}
+		assert_eq!(
+			unions.len(),
+			wave.unions.len(),
+			"{context}: unions were not answered"
+		);
+		n_finds += answered_finds.len();
+	}
+	assert_eq!(
+		n_finds,
+		workload
+			.waves
+			.iter()
+			.map(|wave| wave.finds.len())
+			.sum::<usize>(),
+		"{context}: finds were not answered"
+	);
+	for (node, root) in found {
+		assert!(
+			workload.model.same(node, root),
+			"{context}: a find of node {node} answered node {root}, from another component"
+		);
+	}
+	simulation.verify()
+}
+
+#[test]
+fn replay() {
+	let histories: Vec<Vec<String>> = [7, 7, 8]
+		.into_iter()
+		.map(|seed| {
+			let mut simulation = Simulation::new(3, seed)
+				.with_reordering(true)
+				.with_delays(0.2, 20);
+			simulate(
+				&mut simulation,
+				&mut Workload::random(0, 60, 3, 4),
+				"replay",
+			);
+			simulation.history().to_vec()
+		})
+		.collect();
+	assert_eq!(histories[0], histories[1]);
+	assert_ne!(histories[0], histories[2]);
+}
+
+#[test]
+fn interleavings() {
+	for seed in 0..32 {
+		for n_shards in [1, 3, 8] {
+			for reordering in [false, true] {
+				let context = format!("seed {seed}, {n_shards} shards, reordering {reordering}");
+				let mut workload = Workload::random(seed, 120, n_shards, 6);
+				let mut simulation = Simulation::new(n_shards, seed)
+					.with_reordering(reordering)
+					.with_delays(0.1, 50);
+				let report = simulate(&mut simulation, &mut workload, &context);
+				assert!(report.is_valid(), "{context}: {report}");
+				assert_eq!(
+					report.n_components,
+					workload.model.n_components(),
+					"{context}"
+				);
+			}
+		}
+	}
+}
+
+/// A request sent twice, as a driver retrying it would, is answered twice but
+/// unites and links its nodes once
+#[test]
+fn duplicated_requests() {
+	for seed in 0..32 {
+		let context = format!("seed {seed}");
+		let mut workload = Workload::random(seed, 120, 3, 6);
+		let mut simulation = Simulation::new(3, seed)
+			.with_reordering(true)
+			.with_delays(0.1, 50)
+			.with_duplicates(0.2);
+		let report = simulate(&mut simulation, &mut workload, &context);
+		assert!(report.is_valid(), "{context}: {report}");
+	}
+}
@@ -0,0 +1,431 @@
+mod common;
+
+use std::{
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use big_uf::{
+	storage::{ram::RamStorage, rocksdb::RocksDbStorage, Storage},
+	verify::ForestCheck,
+	DriverMessage, Key, System,
+};
+use common::{Model, Rng, Workload};
+
+/// Shards and drivers of each run
+const LAYOUTS: [(u16, usize); 4] = [(1, 1), (4, 1), (3, 3), (8, 4)];
+
+#[test]
+fn ram_storage() {
+	for seed in 0..8 {
+		for (n_shards, n_drivers) in LAYOUTS {
+			let context = format!("seed {seed}, {n_shards} shards, {n_drivers} drivers");
+			let mut workload = Workload::random(seed, 400, n_shards, 8);
+			let (mut drivers, shards) =
+				System::local_shards(|_| RamStorage::default, n_drivers, n_shards);
+			let (report, _) = common::run(&mut drivers, &mut workload, &context);
+			assert_eq!(
+				report.n_components,
+				workload.model.n_components(),
+				"{context}"
+			);
+			drivers
+				.into_iter()
+				.next()
+				.unwrap()
+				.shutdown_all_and_wait_for_completion();
+			for shard in shards {
+				shard.join().unwrap();
+			}
+		}
+	}
+}
+
+/// The databases are checked again once the shards closed them
+#[test]
+fn rocksdb_storage() {
+	for seed in 0..2 {
+		for (n_shards, n_drivers) in LAYOUTS {
+			let context = format!("seed {seed}, {n_shards} shards, {n_drivers} drivers");
+			let dir = tempfile::tempdir().unwrap();
+			let path = |shard_id| dir.path().join(format!("shard_{shard_id}"));
+			let mut workload = Workload::random(seed, 200, n_shards, 6);
+			let (mut drivers, shards) = System::local_shards(
+				|shard_id| {
+					let path = path(shard_id);
+					move || RocksDbStorage::from_path(path)
+				},
+				n_drivers,
+				n_shards,
+			);
+			let (report, _) = common::run(&mut drivers, &mut workload, &context);
+			drivers
+				.into_iter()
+				.next()
+				.unwrap()
+				.shutdown_all_and_wait_for_completion();
+			for shard in shards {
+				shard.join().unwrap();
+			}
+
+			let mut forest = ForestCheck::new(n_shards as usize);
+			for shard_id in 0..n_shards as usize {
+				let storage = RocksDbStorage::open_read_only(path(shard_id)).unwrap();
+				forest.add_storage(shard_id, &storage);
+			}
+			let reopened = forest.check();
+			assert!(reopened.is_valid(), "{context}: {reopened}");
+			assert_eq!(
+				(reopened.n_nodes, reopened.n_components),
+				(report.n_nodes, workload.model.n_components()),
+				"{context}"
+			);
+		}
+	}
+}
+
+/// Counts the parents set, by the unions and by the path compressions that
+/// follow the finds without being answered
+struct CountingStorage {
+	storage: RamStorage,
+	n_set_parents: Arc<AtomicU64>,
+	/// Sets parents slowly, so that the compressions sent to it are still
+	/// waiting when the finds are answered
+	slow: bool,
+}
+
+impl Storage for CountingStorage {
+	fn set_parent(&mut self, key: Key, value: Key) {
+		if self.slow {
+			std::thread::sleep(Duration::from_millis(2));
+		}
+		self.storage.set_parent(key, value);
+		self.n_set_parents.fetch_add(1, Ordering::SeqCst);
+	}
+
+	fn set_sibling(&mut self, key: Key, value: Key) {
+		self.storage.set_sibling(key, value)
+	}
+
+	fn swap_child(&mut self, key: Key, value: Key) -> Key {
+		self.storage.swap_child(key, value)
+	}
+
+	fn get_parent(&self, key: Key) -> Option<Key> {
+		self.storage.get_parent(key)
+	}
+
+	fn get_sibling(&self, key: Key) -> Option<Key> {
+		self.storage.get_sibling(key)
+	}
+
+	fn get_child(&self, key: Key) -> Option<Key> {
+		self.storage.get_child(key)
+	}
+
+	fn get_size(&self, key: Key) -> u64 {
+		self.storage.get_size(key)
+	}
+
+	fn set_size(&mut self, key: Key, size: u64) {
+		self.storage.set_size(key, size)
+	}
+
+	fn add_node(&mut self, shard: usize) -> Key {
+		self.storage.add_node(shard)
+	}
+
+	fn n_nodes(&self) -> u64 {
+		self.storage.n_nodes()
+	}
+}
+
+/// The finds down a chain spread over the shards are answered before the path
+/// compressions they send to the last shard are done, which the barrier must
+/// wait for
+#[test]
+fn barrier_waits_for_path_compression() {
+	const N_NODES: usize = 200;
+	const N_SHARDS: u16 = 8;
+	let n_set_parents = Arc::new(AtomicU64::new(0));
+	let (mut drivers, shards) = System::local_shards(
+		|shard_id| {
+			let n_set_parents = n_set_parents.clone();
+			move || CountingStorage {
+				storage: RamStorage::default(),
+				n_set_parents,
+				slow: shard_id == N_SHARDS as usize - 1,
+			}
+		},
+		1,
+		N_SHARDS,
+	);
+	let mut driver = drivers.pop().unwrap();
+	for node in 0..N_NODES {
+		driver.add_node(node as u64, node as u16 % N_SHARDS);
+	}
+	driver.flush();
+	let mut keys = vec![Key::new(0, 0); N_NODES];
+	let mut n_added = 0;
+	while n_added < N_NODES {
+		for message in driver.receiver().recv().unwrap() {
+			if let DriverMessage::AddNodeDone { req_id, response } = message {
+				keys[req_id.driver_specific_id() as usize] = response;
+				n_added += 1;
+			}
+		}
+	}
+	keys.sort();
+
+	// A union links the root of its node right under `to`, so linking each
+	// node under the one before, one at a time, makes a chain
+	for node in 1..N_NODES {
+		driver.union(node as u64, keys[node], keys[node - 1]);
+		driver.flush();
+		let answers = driver.receiver().recv().unwrap();
+		assert!(
+			matches!(answers[..], [DriverMessage::UnionDone { .. }]),
+			"{answers:?}"
+		);
+	}
+	for (node, &key) in keys.iter().enumerate() {
+		driver.find(node as u64, key);
+	}
+	driver.flush();
+	let mut n_found = 0;
+	while n_found < N_NODES {
+		for message in driver.receiver().recv().unwrap() {
+			let DriverMessage::FindDone { response, .. } = message else {
+				panic!("{message:?}");
+			};
+			assert_eq!(response, keys[0]);
+			n_found += 1;
+		}
+	}
+
+	let others = driver.barrier().unwrap();
+	assert!(others.is_empty(), "{others:?}");
+	let at_barrier = n_set_parents.load(Ordering::SeqCst);
+	// Time for the slow shard to set the parents the barrier didn't wait for
+	std::thread::sleep(Duration::from_millis(200));
+	assert_eq!(at_barrier, n_set_parents.load(Ordering::SeqCst));
+	assert!(at_barrier > N_NODES as u64, "{at_barrier}");
+	driver.shutdown_all_and_wait_for_completion();
+	for shard in shards {
+		shard.join().unwrap();
+	}
+}
+
+/// Each find is sent right after a union, without waiting for its answer, and
+/// must see it along with the unions sent before
+#[test]
+fn read_your_writes() {
+	const N_NODES: usize = 300;
+	for seed in 0..4 {
+		let context = format!("seed {seed}");
+		let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 1, 8);
+		let mut driver = drivers.pop().unwrap();
+		driver.set_read_your_writes(true);
+		let mut rng = Rng::new(seed);
+		for node in 0..N_NODES {
+			driver.add_node(node as u64, rng.below(8) as u16);
+		}
+		driver.flush();
+		let mut keys = vec![Key::new(0, 0); N_NODES];
+		let mut n_added = 0;
+		while n_added < N_NODES {
+			for message in driver.receiver().recv().unwrap() {
+				if let DriverMessage::AddNodeDone { req_id, response } = message {
+					keys[req_id.driver_specific_id() as usize] = response;
+					n_added += 1;
+				}
+			}
+		}
+
+		// The largest root each find may answer: the smallest node of the
+		// component the model has after the union
+		let mut model = Model::new(N_NODES);
+		let mut largest_roots = Vec::new();
+		for request in 0..N_NODES as u64 {
+			let (a, b) = (rng.below(N_NODES), rng.below(N_NODES));
+			model.union(a, b);
+			let component = model.find(a);
+			largest_roots.push(
+				(0..N_NODES)
+					.filter(|&node| model.find(node) == component)
+					.map(|node| keys[node])
+					.min()
+					.unwrap(),
+			);
+			driver.union(2 * request, keys[a], keys[b]);
+			driver.find(2 * request + 1, keys[a]);
+		}
+		driver.flush();
+		let mut n_found = 0;
+		while n_found < N_NODES {
+			for message in driver.receiver().recv().unwrap() {
+				if let DriverMessage::FindDone { req_id, response } = message {
+					let request = req_id.driver_specific_id() / 2;
+					assert!(
+						response <= largest_roots[request as usize],
+						"{context}: find {request} missed a union sent before it"
+					);
+					n_found += 1;
+				}
+			}
+		}
+		let (report, _) = driver.verify(2 * N_NODES as u64).unwrap();
+		assert!(report.is_valid(), "{context}: {report}");
+		driver.shutdown_all_and_wait_for_completion();
+		for shard in shards {
+			shard.join().unwrap();
+		}
+	}
+}
+
+#[test]
+fn find_detailed() {
+	let context = "find_detailed";
+	let mut workload = Workload::random(0, 300, 4, 4);
+	let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 2, 4);
+	let (_, keys) = common::run(&mut drivers, &mut workload, context);
+	let mut driver = drivers.into_iter().next().unwrap();
+	for (node, &key) in keys.iter().enumerate() {
+		driver.find_detailed(node as u64, key);
+	}
+	driver.flush();
+	let model = &mut workload.model;
+	let mut n_found = 0;
+	while n_found < keys.len() {
+		for message in driver.receiver().recv().unwrap() {
+			let DriverMessage::FindDetailedDone {
+				req_id,
+				response,
+				hops,
+				shards,
+				component_size,
+			} = message
+			else {
+				continue;
+			};
+			let node = req_id.driver_specific_id() as usize;
+			let component: Vec<Key> = (0..keys.len())
+				.filter(|&other| model.same(node, other))
+				.map(|other| keys[other])
+				.collect();
+			assert_eq!(Some(&response), component.iter().min(), "node {node}");
+			assert_eq!(component_size, component.len() as u64, "node {node}");
+			assert_eq!(hops == 0, response == keys[node], "node {node}");
+			assert_eq!(
+				shards.first(),
+				Some(&(keys[node].shard() as u16)),
+				"node {node}"
+			);
+			assert!(shards.contains(&(response.shard() as u16)), "node {node}");
+			assert!(shards.len() <= hops as usize + 1, "node {node}");
+			n_found += 1;
+		}
+	}
+	driver.shutdown_all_and_wait_for_completion();
+	for shard in shards {
+		shard.join().unwrap();
+	}
+}
+
+#[test]
+fn find_many_and_union_many() {
+	let context = "find_many_and_union_many";
+	let mut workload = Workload::random(1, 300, 4, 2);
+	let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 2, 4);
+	let (_, keys) = common::run(&mut drivers, &mut workload, context);
+	let mut driver = drivers.into_iter().next().unwrap();
+	let model = &mut workload.model;
+
+	let mut rng = Rng::new(1);
+	let unions: Vec<(usize, usize)> = (0..100)
+		.map(|_| (rng.below(keys.len()), rng.below(keys.len())))
+		.collect();
+	for &(a, b) in &unions {
+		model.union(a, b);
+	}
+	let union_keys: Vec<(Key, Key)> = unions.iter().map(|&(a, b)| (keys[a], keys[b])).collect();
+	driver.union_many(7, &union_keys);
+	driver.flush();
+	let answers: Vec<_> = driver
+		.receiver()
+		.iter()
+		.flatten()
+		.take_while(|message| !matches!(message, DriverMessage::UnionManyDone { .. }))
+		.collect();
+	assert!(answers.is_empty(), "{context}: {answers:?}");
+	// Answered without any union
+	driver.union_many(8, &[]);
+	assert!(
+		matches!(
+			driver.receiver().recv().unwrap()[..],
+			[DriverMessage::UnionManyDone { req_id }] if req_id.driver_specific_id() == 8
+		),
+		"{context}"
+	);
+
+	// In batches of different sizes, empty too, answered in any order
+	let nodes: Vec<usize> = (0..keys.len()).collect();
+	let chunks: Vec<&[usize]> = nodes.chunks(64).chain([&nodes[..1], &[]]).collect();
+	for (chunk, nodes) in chunks.iter().enumerate() {
+		let nodes: Vec<Key> = nodes.iter().map(|&node| keys[node]).collect();
+		driver.find_many(chunk as u64, &nodes);
+	}
+	driver.flush();
+	let mut n_found = 0;
+	while n_found < chunks.len() {
+		for message in driver.receiver().recv().unwrap() {
+			let DriverMessage::FindManyDone { req_id, roots } = message else {
+				panic!("{context}: unexpected {message:?}");
+			};
+			let nodes = chunks[req_id.driver_specific_id() as usize];
+			assert_eq!(roots.len(), nodes.len(), "{context}");
+			for (&node, root) in nodes.iter().zip(roots) {
+				let smallest = (0..keys.len())
+					.filter(|&other| model.same(node, other))
+					.map(|other| keys[other])
+					.min();
+				assert_eq!(Some(root), smallest, "{context}: node {node}");
+			}
+			n_found += 1;
+		}
+	}
+	driver.shutdown_all_and_wait_for_completion();
+	for shard in shards {
+		shard.join().unwrap();
+	}
+}
+
+/// Without peers, there is no system to move a shard to
+#[test]
+fn move_shard_to_unknown_system() {
+	let (mut drivers, shards) = System::local_shards(|_| RamStorage::default, 1, 2);
+	let mut driver = drivers.pop().unwrap();
+	driver.add_node(0, 1);
+	driver.move_shard(1, 1, 3);
+	driver.flush();
+	let answers: Vec<_> = driver.receiver().iter().flatten().take(2).collect();
+	let [DriverMessage::AddNodeDone { response: key, .. }, DriverMessage::MoveShardFailed { shard: 1, .. }] =
+		answers[..]
+	else {
+		panic!("The move wasn't refused: {answers:?}");
+	};
+	// Still served
+	driver.find(2, key);
+	driver.flush();
+	assert!(matches!(
+		driver.receiver().recv().unwrap()[..],
+		[DriverMessage::FindDone { response, .. }] if response == key
+	));
+	driver.shutdown_all_and_wait_for_completion();
+	for shard in shards {
+		shard.join().unwrap();
+	}
+}